@@ -0,0 +1,280 @@
+from __future__ import annotations
+
+from pathlib import Path
+from typing import Any, Dict, Optional, Sequence, Union
+
+import polars as pl
+from polars.plugins import register_plugin_function
+
+from polar_llama.polar_llama import (
+    CacheMetrics,
+    Config,
+    ModelCapabilities,
+    Progress,
+    Provider,
+    RunStats,
+    SetupDiagnosis,
+    __version__,
+    cache_metrics,
+    clear_api_key,
+    clear_usage_report,
+    configure_audit_log,
+    configure_http,
+    configure_logging,
+    configure_mock,
+    get_config,
+    get_usage_report,
+    last_run_stats,
+    list_models,
+    model_capabilities,
+    reset_config,
+    semantic_join,
+    set_api_key,
+    set_api_keys,
+    set_config,
+    set_model_price,
+    set_progress_callback,
+    set_stream_callback,
+    validate_setup,
+)
+
+IntoExpr = Union[pl.Expr, str]
+
+LIB = Path(__file__).parent
+
+
+def _plugin(
+    function_name: str,
+    args: Union[IntoExpr, Sequence[IntoExpr]],
+    is_elementwise: bool = True,
+    **kwargs: Any,
+) -> pl.Expr:
+    # Providers are passed by name, as the Rust side expects
+    kwargs = {k: str(v) if isinstance(v, Provider) else v for k, v in kwargs.items()}
+    # Row-wise expressions run on each batch of the streaming engine on its own
+    return register_plugin_function(
+        plugin_path=LIB,
+        function_name=function_name,
+        args=args,
+        kwargs=kwargs or None,
+        is_elementwise=is_elementwise,
+        changes_length=False,
+    )
+
+
+def _call(
+    function_name: str, args: Union[IntoExpr, Sequence[IntoExpr]], **kwargs: Any
+) -> pl.Expr:
+    # A call dedupes, orders, budgets and reports on all of its rows at once,
+    # so the column is never split in batches
+    return _plugin(function_name, args, is_elementwise=False, **kwargs)
+
+
+def inference(expr: IntoExpr, **kwargs: Any) -> pl.Expr:
+    """Sends every prompt with one blocking request at a time."""
+    return _plugin("inference", expr, **kwargs)
+
+
+def inference_async(expr: IntoExpr, **kwargs: Any) -> pl.Expr:
+    """Sends every prompt or message concurrently."""
+    return _call("inference_async", expr, **kwargs)
+
+
+def inference_predicted(expr: IntoExpr, prediction: IntoExpr, **kwargs: Any) -> pl.Expr:
+    """`inference_async` with the text of `prediction` as predicted output."""
+    return _call("inference_predicted", [expr, prediction], **kwargs)
+
+
+def inference_prioritized(expr: IntoExpr, priority: IntoExpr, **kwargs: Any) -> pl.Expr:
+    """`inference_async` sending rows with the highest `priority` first."""
+    return _call("inference_prioritized", [expr, priority], **kwargs)
+
+
+def inference_messages(expr: IntoExpr, **kwargs: Any) -> pl.Expr:
+    """Sends every conversation of a list of message structs."""
+    return _call("inference_messages", expr, **kwargs)
+
+
+def inference_sessions(
+    expr: IntoExpr, conversation_id: IntoExpr, **kwargs: Any
+) -> pl.Expr:
+    """Sends the rows of each conversation id in order, as one conversation."""
+    # Conversations span rows, so the column is never split in batches
+    return _plugin(
+        "inference_sessions", [expr, conversation_id], is_elementwise=False, **kwargs
+    )
+
+
+def inference_turn(expr: IntoExpr, **kwargs: Any) -> pl.Expr:
+    """Sends every conversation, returning the reply and the extended history."""
+    return _call("inference_turn", expr, **kwargs)
+
+
+def inference_json(
+    expr: IntoExpr, schemas: Optional[IntoExpr] = None, **kwargs: Any
+) -> pl.Expr:
+    """Asks for JSON replies matching `schema`, or each row's schema in `schemas`."""
+    args = [expr] if schemas is None else [expr, schemas]
+    return _call("inference_json", args, **kwargs)
+
+
+def compare_models(expr: IntoExpr, models: Sequence[str], **kwargs: Any) -> pl.Expr:
+    """Sends every row to each of `models`, as a struct with a field per model."""
+    return _call("compare_models", expr, models=list(models), **kwargs)
+
+
+def classify(expr: IntoExpr, labels: Sequence[str], **kwargs: Any) -> pl.Expr:
+    """The label of every text, one of `labels`."""
+    return _call("classify", expr, labels=list(labels), **kwargs)
+
+
+def tag_taxonomy(
+    expr: IntoExpr, taxonomy: Dict[str, Sequence[str]], **kwargs: Any
+) -> pl.Expr:
+    """Tags every text along each dimension of `taxonomy`."""
+    taxonomy = {name: list(values) for name, values in taxonomy.items()}
+    return _call("tag_taxonomy", expr, taxonomy=taxonomy, **kwargs)
+
+
+def extract_entities(
+    expr: IntoExpr, entity_types: Sequence[str], **kwargs: Any
+) -> pl.Expr:
+    """The entities of `entity_types` in every text."""
+    return _call("extract_entities", expr, entity_types=list(entity_types), **kwargs)
+
+
+def answer_with_citations(
+    question: IntoExpr, passages: IntoExpr, **kwargs: Any
+) -> pl.Expr:
+    """Answers every question from its passages, citing the ones used."""
+    return _call("answer_with_citations", [question, passages], **kwargs)
+
+
+def detect_injection(expr: IntoExpr, **kwargs: Any) -> pl.Expr:
+    """Scores every text for prompt injection attempts."""
+    if kwargs.get("use_model"):
+        return _call("detect_injection", expr, **kwargs)
+    return _plugin("detect_injection", expr, **kwargs)
+
+
+def redact_pii(expr: IntoExpr, **kwargs: Any) -> pl.Expr:
+    """Masks emails, phone numbers, card numbers and other PII."""
+    return _plugin("redact_pii", expr, **kwargs)
+
+
+def embedding(expr: IntoExpr, **kwargs: Any) -> pl.Expr:
+    """The embedding vector of every text."""
+    return _plugin("embedding", expr, **kwargs)
+
+
+def rerank(query: IntoExpr, documents: IntoExpr, **kwargs: Any) -> pl.Expr:
+    """Orders the documents of every query by relevance."""
+    return _plugin("rerank", [query, documents], **kwargs)
+
+
+def request_fingerprint(expr: IntoExpr, **kwargs: Any) -> pl.Expr:
+    """The key every request is checkpointed and cached under."""
+    return _plugin("request_fingerprint", expr, **kwargs)
+
+
+def response_cost(expr: IntoExpr, **kwargs: Any) -> pl.Expr:
+    """The estimated cost in USD of every response."""
+    return _plugin("response_cost", expr, **kwargs)
+
+
+def response_usage(expr: IntoExpr) -> pl.Expr:
+    """The token usage of every response."""
+    return _plugin("response_usage", expr)
+
+
+def extract_json(expr: IntoExpr, path: str) -> pl.Expr:
+    """The value at a JSONPath or dot path of every JSON text."""
+    return _plugin("extract_json", expr, path=path)
+
+
+def parse_xml_tags(expr: IntoExpr, tags: Sequence[str]) -> pl.Expr:
+    """A struct with the text of each of `tags` in every reply."""
+    return _plugin("parse_xml_tags", expr, tags=list(tags))
+
+
+def count_tokens(expr: IntoExpr, **kwargs: Any) -> pl.Expr:
+    """The number of tokens of every text."""
+    return _plugin("count_tokens", expr, **kwargs)
+
+
+def chunk_text(expr: IntoExpr, **kwargs: Any) -> pl.Expr:
+    """Every text split in chunks of at most `max_tokens` tokens."""
+    return _plugin("chunk_text", expr, **kwargs)
+
+
+def truncate_tokens(expr: IntoExpr, max_tokens: int, **kwargs: Any) -> pl.Expr:
+    """Every text shortened to at most `max_tokens` tokens."""
+    return _plugin("truncate_tokens", expr, max_tokens=max_tokens, **kwargs)
+
+
+def prompt_template(
+    template: str, *, escape: str = "none", missing: str = "null", **columns: IntoExpr
+) -> pl.Expr:
+    """Renders `template` per row, each keyword binding a variable to a column."""
+    args = [_bound(name, column) for name, column in columns.items()]
+    return _plugin(
+        "prompt_template", args, template=template, escape=escape, missing=missing
+    )
+
+
+def pairwise_prompt(
+    template: str,
+    *,
+    message_type: str = "user",
+    missing: str = "null",
+    **columns: IntoExpr,
+) -> pl.Expr:
+    """A message per row rendered from `template` over two or more columns."""
+    args = [_bound(name, column) for name, column in columns.items()]
+    return _plugin(
+        "pairwise_prompt",
+        args,
+        template=template,
+        message_type=message_type,
+        missing=missing,
+    )
+
+
+def _bound(name: str, column: IntoExpr) -> pl.Expr:
+    # A template variable, named after the keyword it was given as
+    expr = pl.col(column) if isinstance(column, str) else column
+    return expr.alias(name)
+
+
+def few_shot(
+    expr: IntoExpr, examples: Sequence[Dict[str, str]], **kwargs: Any
+) -> pl.Expr:
+    """Every conversation with `examples` prepended as user/assistant pairs."""
+    return _plugin("few_shot", expr, examples=list(examples), **kwargs)
+
+
+def string_to_message(expr: IntoExpr, message_type: str, **kwargs: Any) -> pl.Expr:
+    """A message from `message_type` with the text of every row."""
+    return _plugin("string_to_message", expr, message_type=message_type, **kwargs)
+
+
+def image_message(text: IntoExpr, image: IntoExpr, **kwargs: Any) -> pl.Expr:
+    """A message with the text of every row followed by its image."""
+    return _plugin("image_message", [text, image], **kwargs)
+
+
+def document_message(text: IntoExpr, document: IntoExpr, **kwargs: Any) -> pl.Expr:
+    """A message with the text of every row followed by its PDF document."""
+    return _plugin("document_message", [text, document], **kwargs)
+
+
+def combine_messages(*exprs: IntoExpr) -> pl.Expr:
+    """The messages and conversations of every row joined into one conversation."""
+    return _plugin("combine_messages", list(exprs))
+
+
+def append_message(
+    history: IntoExpr, content: IntoExpr, role: str, **kwargs: Any
+) -> pl.Expr:
+    """Every conversation with a message from `role` holding `content` appended."""
+    return _plugin("append_message", [history, content], role=role, **kwargs)
@@ -0,0 +1,242 @@
+use crate::ledger;
+use crate::provider::Provider;
+use crate::secrets::{scrub, REDACTED};
+use once_cell::sync::Lazy;
+use polars::prelude::*;
+use pyo3::exceptions::{PyIOError, PyValueError};
+use pyo3::prelude::*;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Where every request and response is written, when an audit log is configured
+static SINK: Lazy<Mutex<Option<AuditSink>>> = Lazy::new(|| Mutex::new(None));
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum AuditFormat {
+    // One JSON object per line, appended as requests complete
+    Jsonl,
+    // A directory of Parquet files, one written per batch of requests
+    Parquet,
+}
+
+#[derive(Serialize)]
+struct AuditEntry {
+    timestamp: f64,
+    call: u64,
+    provider: &'static str,
+    model: String,
+    row: u64,
+    // Headers added by the caller, with the values of credential headers redacted
+    headers: String,
+    request: String,
+    response: Option<String>,
+    success: bool,
+    latency_ms: f64,
+}
+
+struct AuditSink {
+    format: AuditFormat,
+    path: PathBuf,
+    // Store SHA-256 hashes of the request and response instead of their content
+    hash_content: bool,
+    file: Option<File>,
+    // Parquet entries not yet written
+    pending: Vec<AuditEntry>,
+}
+
+impl AuditSink {
+    fn open(path: PathBuf, format: AuditFormat, hash_content: bool) -> io::Result<Self> {
+        let file = match format {
+            AuditFormat::Jsonl => Some(OpenOptions::new().create(true).append(true).open(&path)?),
+            AuditFormat::Parquet => {
+                fs::create_dir_all(&path)?;
+                None
+            }
+        };
+        Ok(AuditSink {
+            format,
+            path,
+            hash_content,
+            file,
+            pending: Vec::new(),
+        })
+    }
+
+    fn write(&mut self, entry: AuditEntry) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => {
+                let mut line = serde_json::to_string(&entry)?;
+                line.push('\n');
+                file.write_all(line.as_bytes())?;
+                file.flush()
+            }
+            None => {
+                self.pending.push(entry);
+                Ok(())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> PolarsResult<()> {
+        if self.format != AuditFormat::Parquet || self.pending.is_empty() {
+            return Ok(());
+        }
+        let entries = std::mem::take(&mut self.pending);
+        let first = &entries[0];
+        let name = format!(
+            "audit-{}-{}.parquet",
+            (first.timestamp * 1000.0) as u64,
+            first.call
+        );
+        let mut df = df!(
+            "timestamp" => entries.iter().map(|e| e.timestamp).collect::<Vec<_>>(),
+            "call" => entries.iter().map(|e| e.call).collect::<Vec<_>>(),
+            "provider" => entries.iter().map(|e| e.provider).collect::<Vec<_>>(),
+            "model" => entries.iter().map(|e| e.model.as_str()).collect::<Vec<_>>(),
+            "row" => entries.iter().map(|e| e.row).collect::<Vec<_>>(),
+            "headers" => entries.iter().map(|e| e.headers.as_str()).collect::<Vec<_>>(),
+            "request" => entries.iter().map(|e| e.request.as_str()).collect::<Vec<_>>(),
+            "response" => entries.iter().map(|e| e.response.as_deref()).collect::<Vec<_>>(),
+            "success" => entries.iter().map(|e| e.success).collect::<Vec<_>>(),
+            "latency_ms" => entries.iter().map(|e| e.latency_ms).collect::<Vec<_>>(),
+        )?;
+        let file = File::create(self.path.join(name))?;
+        ParquetWriter::new(file).finish(&mut df)?;
+        Ok(())
+    }
+}
+
+fn audit_format(path: &Path, format: Option<&str>) -> PyResult<AuditFormat> {
+    let format = match format {
+        Some(format) => format.to_lowercase(),
+        None => path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default(),
+    };
+    match format.as_str() {
+        "parquet" => Ok(AuditFormat::Parquet),
+        "jsonl" | "json" | "" => Ok(AuditFormat::Jsonl),
+        other => Err(PyValueError::new_err(format!(
+            "unknown audit log format {}, expected jsonl or parquet",
+            other
+        ))),
+    }
+}
+
+/// Writes every request and response to an audit log at `path`, or stops
+/// writing when `path` is None. JSONL logs are appended to as requests
+/// complete; a Parquet log is a directory that gets a file per batch. The
+/// format follows the extension unless `format` is given. API keys are
+/// scrubbed, and with `hash_content` only SHA-256 hashes of the requests and
+/// responses are kept.
+#[pyfunction]
+#[pyo3(signature = (path=None, format=None, hash_content=false))]
+pub fn configure_audit_log(
+    path: Option<PathBuf>,
+    format: Option<&str>,
+    hash_content: bool,
+) -> PyResult<()> {
+    let sink = match path {
+        Some(path) => {
+            let format = audit_format(&path, format)?;
+            let sink = AuditSink::open(path.clone(), format, hash_content).map_err(|e| {
+                PyIOError::new_err(format!(
+                    "failed to open audit log {}: {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+            Some(sink)
+        }
+        None => None,
+    };
+    let previous = std::mem::replace(&mut *SINK.lock().unwrap(), sink);
+    if let Some(mut previous) = previous {
+        previous
+            .flush()
+            .map_err(|e| PyIOError::new_err(format!("failed to write audit log: {}", e)))?;
+    }
+    Ok(())
+}
+
+fn hash(text: &str) -> String {
+    format!("sha256:{:x}", Sha256::digest(text.as_bytes()))
+}
+
+fn is_credential_header(name: &str) -> bool {
+    let name = name.to_lowercase();
+    ["auth", "key", "token", "secret", "cookie"]
+        .iter()
+        .any(|word| name.contains(word))
+}
+
+/// Writes a request sent for `row` and its response, null when the request
+/// failed, to the audit log if one is configured.
+pub fn record(
+    provider: Provider,
+    model: &str,
+    row: usize,
+    headers: &HashMap<String, String>,
+    request: &str,
+    response: Option<&str>,
+    latency: Duration,
+) {
+    let mut sink = SINK.lock().unwrap();
+    let Some(sink) = sink.as_mut() else {
+        return;
+    };
+    let headers: serde_json::Map<String, serde_json::Value> = headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if is_credential_header(name) {
+                REDACTED.to_string()
+            } else {
+                scrub(value)
+            };
+            (name.clone(), value.into())
+        })
+        .collect();
+    let hash_content = sink.hash_content;
+    let content = |text: &str| {
+        if hash_content {
+            hash(text)
+        } else {
+            scrub(text)
+        }
+    };
+    let entry = AuditEntry {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default(),
+        call: ledger::current_call(),
+        provider: provider.as_str(),
+        model: model.to_string(),
+        row: row as u64,
+        headers: serde_json::Value::Object(headers).to_string(),
+        request: content(request),
+        response: response.map(content),
+        success: response.is_some(),
+        latency_ms: latency.as_secs_f64() * 1000.0,
+    };
+    // An audit log that cannot be written must not fail the batch
+    if let Err(e) = sink.write(entry) {
+        tracing::error!(error = %e, "failed to write the audit log");
+    }
+}
+
+/// Writes the entries buffered for a Parquet audit log to a new file.
+pub fn flush() {
+    if let Some(sink) = SINK.lock().unwrap().as_mut() {
+        if let Err(e) = sink.flush() {
+            tracing::error!(error = %e, "failed to write the audit log");
+        }
+    }
+}
@@ -0,0 +1,501 @@
+use crate::config::{config, Config};
+use crate::credentials::{api_key, key_variable};
+use crate::pricing::matches_model;
+use crate::provider::Provider;
+use crate::secrets::scrub;
+use crate::structured::StructuredMode;
+use crate::utils::RequestOptions;
+use once_cell::sync::Lazy;
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+/// What a chat model can do, from the provider's documentation.
+#[pyclass(frozen)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ModelCapabilities {
+    // Prompt and completion tokens together
+    #[pyo3(get)]
+    pub context_window: u32,
+    #[pyo3(get)]
+    pub max_output_tokens: u32,
+    // Responses API tools such as web search
+    #[pyo3(get)]
+    pub tools: bool,
+    // Image inputs
+    #[pyo3(get)]
+    pub vision: bool,
+    // `json_schema` response formats
+    #[pyo3(get)]
+    pub structured_output: bool,
+    #[pyo3(get)]
+    pub tokenizer: &'static str,
+}
+
+#[pymethods]
+impl ModelCapabilities {
+    fn __repr__(&self) -> String {
+        format!(
+            "ModelCapabilities(context_window={}, max_output_tokens={}, tools={}, vision={}, structured_output={}, tokenizer='{}')",
+            self.context_window,
+            self.max_output_tokens,
+            self.tools,
+            self.vision,
+            self.structured_output,
+            self.tokenizer
+        )
+    }
+}
+
+const fn caps(
+    context_window: u32,
+    max_output_tokens: u32,
+    tools: bool,
+    vision: bool,
+    structured_output: bool,
+    tokenizer: &'static str,
+) -> ModelCapabilities {
+    ModelCapabilities {
+        context_window,
+        max_output_tokens,
+        tools,
+        vision,
+        structured_output,
+        tokenizer,
+    }
+}
+
+// OpenAI chat models, dated snapshots match their model's prefix
+const CAPABILITIES: &[(&str, ModelCapabilities)] = &[
+    (
+        "gpt-5",
+        caps(400_000, 128_000, true, true, true, "o200k_base"),
+    ),
+    (
+        "gpt-5-mini",
+        caps(400_000, 128_000, true, true, true, "o200k_base"),
+    ),
+    (
+        "gpt-5-nano",
+        caps(400_000, 128_000, true, true, true, "o200k_base"),
+    ),
+    (
+        "gpt-4.1",
+        caps(1_047_576, 32_768, true, true, true, "o200k_base"),
+    ),
+    (
+        "gpt-4.1-mini",
+        caps(1_047_576, 32_768, true, true, true, "o200k_base"),
+    ),
+    (
+        "gpt-4.1-nano",
+        caps(1_047_576, 32_768, true, true, true, "o200k_base"),
+    ),
+    (
+        "gpt-4o",
+        caps(128_000, 16_384, true, true, true, "o200k_base"),
+    ),
+    (
+        "gpt-4o-mini",
+        caps(128_000, 16_384, true, true, true, "o200k_base"),
+    ),
+    (
+        "gpt-4-turbo",
+        caps(128_000, 4_096, true, true, false, "cl100k_base"),
+    ),
+    (
+        "gpt-4",
+        caps(8_192, 8_192, true, false, false, "cl100k_base"),
+    ),
+    (
+        "gpt-3.5-turbo",
+        caps(16_385, 4_096, true, false, false, "cl100k_base"),
+    ),
+    ("o1", caps(200_000, 100_000, true, true, true, "o200k_base")),
+    (
+        "o1-mini",
+        caps(128_000, 65_536, false, false, false, "o200k_base"),
+    ),
+    ("o3", caps(200_000, 100_000, true, true, true, "o200k_base")),
+    (
+        "o3-mini",
+        caps(200_000, 100_000, true, false, true, "o200k_base"),
+    ),
+    (
+        "o4-mini",
+        caps(200_000, 100_000, true, true, true, "o200k_base"),
+    ),
+];
+
+/// Capabilities of `model`, for the chat models in the table. Dated
+/// snapshots get those of the longest model name they start with.
+pub fn model_capabilities(model: &str) -> Option<ModelCapabilities> {
+    let model = model.rsplit('/').next().unwrap_or(model);
+    CAPABILITIES
+        .iter()
+        .filter(|(name, _)| matches_model(model, name))
+        .max_by_key(|(name, _)| name.len())
+        .map(|(_, caps)| *caps)
+}
+
+/// Returns what `model` can do, or None for models not in the table.
+#[pyfunction]
+#[pyo3(name = "model_capabilities")]
+pub fn py_model_capabilities(model: &str) -> Option<ModelCapabilities> {
+    model_capabilities(model)
+}
+
+/// Something an expression needs from the model it sends requests to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capability {
+    Tools,
+    Vision,
+    StructuredOutput,
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Capability::Tools => "tools",
+            Capability::Vision => "image inputs",
+            Capability::StructuredOutput => "structured outputs",
+        })
+    }
+}
+
+// Model lists already fetched, by provider and base URL
+static MODEL_LISTS: Lazy<Mutex<HashMap<String, Vec<String>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Why the model list of a provider could not be fetched.
+enum ListError {
+    NoEndpoint,
+    NoKey(String),
+    Unreachable(String),
+    Http(u16, String),
+    Unreadable,
+}
+
+impl fmt::Display for ListError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListError::NoEndpoint => f.write_str("the provider has no models endpoint"),
+            ListError::NoKey(reason) => f.write_str(reason),
+            ListError::Unreachable(reason) => {
+                write!(f, "the models endpoint is unreachable: {}", reason)
+            }
+            ListError::Http(status, _) => {
+                write!(f, "listing the models failed with HTTP {}", status)
+            }
+            ListError::Unreadable => f.write_str("unreadable model list"),
+        }
+    }
+}
+
+fn fetch_models(provider: Provider, config: &Config) -> Result<Vec<String>, ListError> {
+    let (url, auth) = match provider {
+        Provider::OpenAI => (config.url("models"), "Authorization"),
+        Provider::Mistral => (
+            "https://api.mistral.ai/v1/models".to_string(),
+            "Authorization",
+        ),
+        Provider::Cohere => (
+            "https://api.cohere.com/v1/models".to_string(),
+            "Authorization",
+        ),
+        Provider::Gemini => (
+            "https://generativelanguage.googleapis.com/v1beta/models?pageSize=1000".to_string(),
+            "x-goog-api-key",
+        ),
+        Provider::Mock => {
+            return Ok(CAPABILITIES
+                .iter()
+                .map(|(name, _)| name.to_string())
+                .collect())
+        }
+        Provider::Voyage | Provider::Jina | Provider::Local => return Err(ListError::NoEndpoint),
+    };
+    let key = api_key(provider.as_str()).map_err(ListError::NoKey)?;
+    let value = match provider {
+        Provider::Gemini => key,
+        _ => format!("Bearer {}", key),
+    };
+    let mut request = ureq::agent().get(&url);
+    request
+        .set(auth, &value)
+        .timeout_read(config.timeout().as_millis() as u64);
+    if provider == Provider::OpenAI {
+        for (name, header) in config.openai_headers() {
+            request.set(name, header);
+        }
+    }
+    let response = request.call();
+    if let Some(error) = response.synthetic_error() {
+        return Err(ListError::Unreachable(error.to_string()));
+    }
+    if !response.ok() {
+        let status = response.status();
+        return Err(ListError::Http(
+            status,
+            response.into_string().unwrap_or_default(),
+        ));
+    }
+    let body: Value = response
+        .into_string()
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .ok_or(ListError::Unreadable)?;
+    // OpenAI and Mistral list `data`, Cohere and Gemini list `models`
+    let models = body["data"]
+        .as_array()
+        .or_else(|| body["models"].as_array())
+        .ok_or(ListError::Unreadable)?;
+    Ok(models
+        .iter()
+        .filter_map(|m| m["id"].as_str().or_else(|| m["name"].as_str()))
+        .map(|id| id.trim_start_matches("models/").to_string())
+        .collect())
+}
+
+fn list_key(provider: Provider, config: &Config) -> String {
+    format!("{}:{}", provider.as_str(), config.base_url)
+}
+
+// Models of `provider`, fetched once per base URL
+fn available_models(provider: Provider, config: &Config) -> Result<Vec<String>, String> {
+    if let Some(models) = MODEL_LISTS.lock().unwrap().get(&list_key(provider, config)) {
+        return Ok(models.clone());
+    }
+    let models = fetch_models(provider, config)
+        .map_err(|e| format!("listing the models of {} failed: {}", provider.as_str(), e))?;
+    MODEL_LISTS
+        .lock()
+        .unwrap()
+        .insert(list_key(provider, config), models.clone());
+    Ok(models)
+}
+
+/// Lists the models `provider` serves to the current API key, from its
+/// models endpoint.
+#[pyfunction]
+#[pyo3(signature = (provider="openai"))]
+pub fn list_models(provider: &str) -> PyResult<Vec<String>> {
+    let provider = Provider::from_name(provider)
+        .ok_or_else(|| PyValueError::new_err(format!("unknown provider: {}", provider)))?;
+    // Always refetched, so new models show up
+    let config = config();
+    let models = fetch_models(provider, &config).map_err(|e| {
+        PyRuntimeError::new_err(format!(
+            "listing the models of {} failed: {}",
+            provider.as_str(),
+            e
+        ))
+    })?;
+    MODEL_LISTS
+        .lock()
+        .unwrap()
+        .insert(list_key(provider, &config), models.clone());
+    Ok(models)
+}
+
+/// What `validate_setup` found.
+#[pyclass(frozen)]
+#[derive(Clone, Debug)]
+pub struct SetupDiagnosis {
+    #[pyo3(get)]
+    pub ok: bool,
+    #[pyo3(get)]
+    pub provider: String,
+    #[pyo3(get)]
+    pub model: Option<String>,
+    // missing_key, invalid_key, org_restricted, model_not_found, unreachable,
+    // unreadable_response or http_<status>, None when the setup works
+    #[pyo3(get)]
+    pub problem: Option<String>,
+    #[pyo3(get)]
+    pub message: String,
+}
+
+#[pymethods]
+impl SetupDiagnosis {
+    fn __repr__(&self) -> String {
+        format!(
+            "SetupDiagnosis(ok={}, provider='{}', model={:?}, problem={:?}, message={:?})",
+            if self.ok { "True" } else { "False" },
+            self.provider,
+            self.model,
+            self.problem,
+            self.message
+        )
+    }
+}
+
+// The problem found, if any, and what was found
+fn diagnose(provider: Provider, model: Option<&str>, config: &Config) -> (Option<String>, String) {
+    let name = provider.as_str();
+    let needs_key = !matches!(provider, Provider::Mock | Provider::Local);
+    let key = match api_key(name) {
+        Ok(key) => key,
+        Err(reason) if needs_key => return (Some("keys_disabled".to_string()), reason),
+        Err(_) => String::new(),
+    };
+    if needs_key && key.is_empty() {
+        let source = match key_variable(name) {
+            Some(variable) => format!("set {} or call set_api_key", variable),
+            None => "call set_api_key".to_string(),
+        };
+        return (
+            Some("missing_key".to_string()),
+            format!("no API key for {}: {}", name, source),
+        );
+    }
+    let models = match fetch_models(provider, config) {
+        Ok(models) => models,
+        Err(ListError::NoEndpoint) => {
+            return (
+                None,
+                format!(
+                    "{} has no models endpoint, only the presence of its key was checked",
+                    name
+                ),
+            )
+        }
+        Err(ListError::Http(status, body)) => {
+            let body = scrub(&body);
+            // OpenAI rejects keys used outside their organization or project
+            if status == 403
+                || (status == 401 && (body.contains("organization") || body.contains("project")))
+            {
+                return (
+                    Some("org_restricted".to_string()),
+                    format!(
+                        "the key is not allowed in this organization or project: {}",
+                        body
+                    ),
+                );
+            }
+            if status == 401 {
+                return (
+                    Some("invalid_key".to_string()),
+                    format!("{} rejected the API key", name),
+                );
+            }
+            return (
+                Some(format!("http_{}", status)),
+                format!(
+                    "listing the models of {} failed with HTTP {}: {}",
+                    name, status, body
+                ),
+            );
+        }
+        Err(ListError::Unreachable(reason)) => {
+            return (
+                Some("unreachable".to_string()),
+                format!("{} could not be reached: {}", name, reason),
+            )
+        }
+        Err(ListError::NoKey(reason)) => return (Some("keys_disabled".to_string()), reason),
+        Err(error @ ListError::Unreadable) => {
+            return (Some("unreadable_response".to_string()), error.to_string())
+        }
+    };
+    MODEL_LISTS
+        .lock()
+        .unwrap()
+        .insert(list_key(provider, config), models.clone());
+    match model {
+        Some(model) if !models.iter().any(|m| m == model) => (
+            Some("model_not_found".to_string()),
+            format!(
+                "model {} is not available from {}, list_models() shows the models your key can use",
+                model, name
+            ),
+        ),
+        _ if !needs_key => (None, format!("{} needs no API key", name)),
+        _ => (None, format!("{} accepted the API key", name)),
+    }
+}
+
+/// Checks that requests to `provider` can go through, with a minimal
+/// authenticated call to its models endpoint, and that `model` is one of
+/// the models it serves. OpenAI checks the configured model when `model` is
+/// not given.
+#[pyfunction]
+#[pyo3(signature = (provider="openai", model=None))]
+pub fn validate_setup(provider: &str, model: Option<String>) -> PyResult<SetupDiagnosis> {
+    let parsed = Provider::from_name(provider)
+        .ok_or_else(|| PyValueError::new_err(format!("unknown provider: {}", provider)))?;
+    let config = config();
+    let model = model.or_else(|| (parsed == Provider::OpenAI).then(|| config.model.clone()));
+    let (problem, message) = diagnose(parsed, model.as_deref(), &config);
+    Ok(SetupDiagnosis {
+        ok: problem.is_none(),
+        provider: parsed.as_str().to_string(),
+        model,
+        problem,
+        message,
+    })
+}
+
+/// Checks before anything is sent that `model` can serve the requests of
+/// `options` and whatever else `needs` lists. Models missing from the table
+/// are assumed capable; with `options.preflight` the model also has to be
+/// listed by the provider's models endpoint.
+pub fn preflight(
+    options: &RequestOptions,
+    model: &str,
+    needs: &[Capability],
+) -> Result<(), String> {
+    if let Some(route) = &options.route {
+        route.validate()?;
+    }
+    if options.provider == Provider::Mock {
+        return Ok(());
+    }
+    // Routed calls check each row's candidates when they pick one
+    let models = match &options.route {
+        Some(route) => route.candidates.iter().map(|m| m.as_str()).collect(),
+        None => vec![model],
+    };
+    if options.preflight {
+        let available = available_models(options.provider, &config())?;
+        if let Some(missing) = models.iter().find(|m| !available.iter().any(|a| a == *m)) {
+            return Err(format!(
+                "model {} is not available from {}, list_models() shows the models your key can use",
+                missing,
+                options.provider.as_str()
+            ));
+        }
+    }
+    let Some(caps) = model_capabilities(model).filter(|_| options.route.is_none()) else {
+        return Ok(());
+    };
+    let mut needs = needs.to_vec();
+    if options.tools.is_some() {
+        needs.push(Capability::Tools);
+    }
+    // Other modes can ask for the schema in the prompt
+    if options.response_format.is_some() && options.structured_mode == StructuredMode::Native {
+        needs.push(Capability::StructuredOutput);
+    }
+    for need in needs {
+        let supported = match need {
+            Capability::Tools => caps.tools,
+            Capability::Vision => caps.vision,
+            Capability::StructuredOutput => caps.structured_output,
+        };
+        if !supported {
+            return Err(format!("model {} does not support {}", model, need));
+        }
+    }
+    match options.max_tokens {
+        Some(max_tokens) if max_tokens > caps.max_output_tokens => Err(format!(
+            "max_tokens is {} but model {} writes at most {} tokens",
+            max_tokens, model, caps.max_output_tokens
+        )),
+        _ => Ok(()),
+    }
+}
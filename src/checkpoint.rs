@@ -0,0 +1,150 @@
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// A single line of the checkpoint file
+#[derive(Serialize, Deserialize)]
+struct CheckpointEntry {
+    key: String,
+    // Only kept by fixtures, so recorded exchanges can be inspected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request: Option<Value>,
+    response: String,
+}
+
+/// Append-only JSONL record of completed requests, keyed by request hash.
+///
+/// Re-running a batch against the same checkpoint file only sends the
+/// requests that have not completed yet.
+pub struct Checkpoint {
+    completed: HashMap<String, String>,
+    file: Mutex<File>,
+}
+
+impl Checkpoint {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut completed = HashMap::new();
+
+        if path.exists() {
+            let reader = BufReader::new(File::open(path)?);
+            for line in reader.lines() {
+                let line = line?;
+                // A crash mid-write can leave a truncated last line, skip it
+                if let Ok(entry) = serde_json::from_str::<CheckpointEntry>(&line) {
+                    completed.insert(entry.key, entry.response);
+                }
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Checkpoint {
+            completed,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.completed.get(key).map(|s| s.as_str())
+    }
+
+    pub fn record(&self, key: &str, response: &str) -> io::Result<()> {
+        self.write_entry(CheckpointEntry {
+            key: key.to_string(),
+            request: None,
+            response: response.to_string(),
+        })
+    }
+
+    /// Like `record`, but also stores the request body.
+    pub fn record_exchange(&self, key: &str, request: &str, response: &str) -> io::Result<()> {
+        self.write_entry(CheckpointEntry {
+            key: key.to_string(),
+            request: serde_json::from_str(request).ok(),
+            response: response.to_string(),
+        })
+    }
+
+    fn write_entry(&self, entry: CheckpointEntry) -> io::Result<()> {
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+
+        let mut file = self.file.lock().unwrap();
+        file.write_all(line.as_bytes())?;
+        file.flush()
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FixtureMode {
+    // Replay recorded responses, record the requests that have none
+    #[default]
+    Auto,
+    // Always send requests and record their responses
+    Record,
+    // Only replay, requests without a recorded response are never sent
+    Replay,
+}
+
+/// Recorded request/response pairs, replayed instead of calling the API.
+pub struct Fixture {
+    log: Checkpoint,
+    mode: FixtureMode,
+}
+
+impl Fixture {
+    pub fn open<P: AsRef<Path>>(path: P, mode: FixtureMode) -> io::Result<Self> {
+        Ok(Fixture {
+            log: Checkpoint::open(path)?,
+            mode,
+        })
+    }
+}
+
+/// Local stores consulted before a request is sent, and updated after.
+#[derive(Default)]
+pub struct ResponseStores {
+    pub checkpoint: Option<Checkpoint>,
+    pub fixture: Option<Fixture>,
+}
+
+impl ResponseStores {
+    pub fn lookup(&self, key: &str) -> Option<&str> {
+        let replayed = self
+            .fixture
+            .as_ref()
+            .filter(|f| f.mode != FixtureMode::Record)
+            .and_then(|f| f.log.get(key));
+        replayed.or_else(|| self.checkpoint.as_ref().and_then(|c| c.get(key)))
+    }
+
+    /// False when a replay-only fixture is in use.
+    pub fn allows_network(&self) -> bool {
+        self.fixture
+            .as_ref()
+            .is_none_or(|f| f.mode != FixtureMode::Replay)
+    }
+
+    pub fn record(&self, key: &str, request: &str, response: &str) {
+        // Losing a line only means the request is sent again next time
+        if let Some(checkpoint) = &self.checkpoint {
+            let _ = checkpoint.record(key, response);
+        }
+        if let Some(fixture) = &self.fixture {
+            let _ = fixture.log.record_exchange(key, request, response);
+        }
+    }
+}
+
+/// Stable hash of a request body, used as the checkpoint key.
+pub fn request_hash(body: &str) -> String {
+    format!("{:x}", Sha256::digest(body.as_bytes()))
+}
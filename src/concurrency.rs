@@ -0,0 +1,104 @@
+use crate::config::Config;
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+// Rate limits closer together than this lower the limit once, as requests
+// sent before the first one keep running into it for a while
+const BACKOFF_INTERVAL: Duration = Duration::from_secs(1);
+
+// Chat requests allowed in flight, tuned AIMD-style: halved on rate limits
+// and overloads, raised by one after as many successes as the limit
+#[derive(Default)]
+struct Limiter {
+    // Configured start, the limit starts over when it changes
+    start: usize,
+    limit: usize,
+    ceiling: usize,
+    in_flight: usize,
+    // Successes since the limit last changed
+    successes: usize,
+    last_backoff: Option<Instant>,
+}
+
+// Shared by every call, so a limit learned on one batch carries over to the next
+static LIMITER: Lazy<Mutex<Limiter>> = Lazy::new(|| Mutex::new(Limiter::default()));
+// Woken whenever a request finishes or the limit goes up
+static RELEASED: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// A place among the chat requests in flight, given back when dropped.
+pub struct Slot {
+    counted: bool,
+}
+
+/// Waits until one more chat request may be sent. Without
+/// `adaptive_concurrency` in `config` every request may be sent at once.
+pub async fn acquire(config: &Config) -> Slot {
+    let Some(start) = config.adaptive_concurrency else {
+        return Slot { counted: false };
+    };
+    loop {
+        let released = RELEASED.notified();
+        tokio::pin!(released);
+        released.as_mut().enable();
+        {
+            let mut limiter = LIMITER.lock().unwrap();
+            limiter.ceiling = config.max_concurrency.max(1);
+            if limiter.start != start {
+                limiter.start = start;
+                limiter.limit = start;
+                limiter.successes = 0;
+            }
+            limiter.limit = limiter.limit.clamp(1, limiter.ceiling);
+            if limiter.in_flight < limiter.limit {
+                limiter.in_flight += 1;
+                return Slot { counted: true };
+            }
+        }
+        released.await;
+    }
+}
+
+impl Slot {
+    /// Records a successful request.
+    pub fn succeeded(&self) {
+        if !self.counted {
+            return;
+        }
+        let mut limiter = LIMITER.lock().unwrap();
+        limiter.successes += 1;
+        if limiter.successes >= limiter.limit && limiter.limit < limiter.ceiling {
+            limiter.limit += 1;
+            limiter.successes = 0;
+            RELEASED.notify_waiters();
+        }
+    }
+
+    /// Records a request that was rate limited or found the provider overloaded.
+    pub fn throttled(&self) {
+        if !self.counted {
+            return;
+        }
+        let mut limiter = LIMITER.lock().unwrap();
+        if limiter
+            .last_backoff
+            .is_some_and(|last| last.elapsed() < BACKOFF_INTERVAL)
+        {
+            return;
+        }
+        limiter.limit = (limiter.limit / 2).max(1);
+        limiter.successes = 0;
+        limiter.last_backoff = Some(Instant::now());
+        tracing::warn!(limit = limiter.limit, "rate limited, lowering concurrency");
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if self.counted {
+            LIMITER.lock().unwrap().in_flight -= 1;
+            RELEASED.notify_waiters();
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use once_cell::sync::Lazy;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::Duration;
+
+static CONFIG: Lazy<RwLock<Config>> = Lazy::new(|| RwLock::new(Config::default()));
+
+/// Library-wide defaults, used by every expression unless a kwarg overrides them.
+#[pyclass]
+#[derive(Clone)]
+pub struct Config {
+    #[pyo3(get, set)]
+    pub model: String,
+    #[pyo3(get, set)]
+    pub embedding_model: String,
+    #[pyo3(get, set)]
+    pub base_url: String,
+    #[pyo3(get, set)]
+    pub timeout_secs: f64,
+    #[pyo3(get, set)]
+    pub max_retries: u32,
+    #[pyo3(get, set)]
+    pub max_concurrency: usize,
+    // Relative checkpoint paths are resolved against this directory
+    #[pyo3(get, set)]
+    pub cache_dir: Option<String>,
+    // Sent as OpenAI-Organization / OpenAI-Project so usage is billed accordingly
+    #[pyo3(get, set)]
+    pub organization: Option<String>,
+    #[pyo3(get, set)]
+    pub project: Option<String>,
+    // Spending caps of each expression call, overridden by the kwargs of the same name
+    #[pyo3(get, set)]
+    pub max_cost_usd: Option<f64>,
+    #[pyo3(get, set)]
+    pub max_total_tokens: Option<u64>,
+    // Retries each call may make across all its requests, so a struggling
+    // provider is not hit by every row's retries at once
+    #[pyo3(get, set)]
+    pub retry_budget: Option<u64>,
+    // Rows whose requests are prepared ahead of those being sent
+    #[pyo3(get, set)]
+    pub chunk_size: usize,
+    // Threads of the Tokio runtime requests run on, Tokio's defaults when None
+    #[pyo3(get, set)]
+    pub worker_threads: Option<usize>,
+    #[pyo3(get, set)]
+    pub max_blocking_threads: Option<usize>,
+    // Chat requests in flight to start from, then lowered on rate limits and
+    // raised on successes up to max_concurrency; a fixed max_concurrency when None
+    #[pyo3(get, set)]
+    pub adaptive_concurrency: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            model: "gpt-4-turbo".to_string(),
+            embedding_model: "text-embedding-3-small".to_string(),
+            base_url: "https://api.openai.com/v1".to_string(),
+            timeout_secs: 120.0,
+            max_retries: 3,
+            max_concurrency: 100,
+            cache_dir: None,
+            organization: std::env::var("OPENAI_ORG_ID").ok(),
+            project: std::env::var("OPENAI_PROJECT_ID").ok(),
+            max_cost_usd: None,
+            max_total_tokens: None,
+            retry_budget: None,
+            chunk_size: 5000,
+            worker_threads: None,
+            max_blocking_threads: None,
+            adaptive_concurrency: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.base_url.trim_end_matches('/'), path)
+    }
+
+    /// Extra headers every OpenAI request should carry.
+    pub fn openai_headers(&self) -> Vec<(&'static str, &str)> {
+        let mut headers = Vec::new();
+        if let Some(organization) = &self.organization {
+            headers.push(("OpenAI-Organization", organization.as_str()));
+        }
+        if let Some(project) = &self.project {
+            headers.push(("OpenAI-Project", project.as_str()));
+        }
+        headers
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs_f64(self.timeout_secs)
+    }
+
+    // Settings that would fail every request, or panic, once used
+    fn validate(&self) -> PyResult<()> {
+        if !self.timeout_secs.is_finite() || self.timeout_secs <= 0.0 {
+            return Err(PyValueError::new_err(format!(
+                "timeout_secs must be a positive number of seconds, got {}",
+                self.timeout_secs
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn resolve_path(&self, path: &str) -> PathBuf {
+        match &self.cache_dir {
+            Some(dir) => PathBuf::from(dir).join(path),
+            None => PathBuf::from(path),
+        }
+    }
+}
+
+#[pymethods]
+impl Config {
+    #[new]
+    #[pyo3(signature = (
+        model=None,
+        embedding_model=None,
+        base_url=None,
+        timeout_secs=None,
+        max_retries=None,
+        max_concurrency=None,
+        cache_dir=None,
+        organization=None,
+        project=None,
+        max_cost_usd=None,
+        max_total_tokens=None,
+        retry_budget=None,
+        chunk_size=None,
+        worker_threads=None,
+        max_blocking_threads=None,
+        adaptive_concurrency=None
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn py_new(
+        model: Option<String>,
+        embedding_model: Option<String>,
+        base_url: Option<String>,
+        timeout_secs: Option<f64>,
+        max_retries: Option<u32>,
+        max_concurrency: Option<usize>,
+        cache_dir: Option<String>,
+        organization: Option<String>,
+        project: Option<String>,
+        max_cost_usd: Option<f64>,
+        max_total_tokens: Option<u64>,
+        retry_budget: Option<u64>,
+        chunk_size: Option<usize>,
+        worker_threads: Option<usize>,
+        max_blocking_threads: Option<usize>,
+        adaptive_concurrency: Option<usize>,
+    ) -> PyResult<Self> {
+        let defaults = Config::default();
+        let config = Config {
+            model: model.unwrap_or(defaults.model),
+            embedding_model: embedding_model.unwrap_or(defaults.embedding_model),
+            base_url: base_url.unwrap_or(defaults.base_url),
+            timeout_secs: timeout_secs.unwrap_or(defaults.timeout_secs),
+            max_retries: max_retries.unwrap_or(defaults.max_retries),
+            max_concurrency: max_concurrency.unwrap_or(defaults.max_concurrency),
+            cache_dir: cache_dir.or(defaults.cache_dir),
+            organization: organization.or(defaults.organization),
+            project: project.or(defaults.project),
+            max_cost_usd: max_cost_usd.or(defaults.max_cost_usd),
+            max_total_tokens: max_total_tokens.or(defaults.max_total_tokens),
+            retry_budget: retry_budget.or(defaults.retry_budget),
+            chunk_size: chunk_size.unwrap_or(defaults.chunk_size),
+            worker_threads: worker_threads.or(defaults.worker_threads),
+            max_blocking_threads: max_blocking_threads.or(defaults.max_blocking_threads),
+            adaptive_concurrency: adaptive_concurrency.or(defaults.adaptive_concurrency),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Config(model={:?}, embedding_model={:?}, base_url={:?}, timeout_secs={}, max_retries={}, max_concurrency={}, cache_dir={:?}, organization={:?}, project={:?}, max_cost_usd={:?}, max_total_tokens={:?}, retry_budget={:?}, chunk_size={}, worker_threads={:?}, max_blocking_threads={:?}, adaptive_concurrency={:?})",
+            self.model,
+            self.embedding_model,
+            self.base_url,
+            self.timeout_secs,
+            self.max_retries,
+            self.max_concurrency,
+            self.cache_dir,
+            self.organization,
+            self.project,
+            self.max_cost_usd,
+            self.max_total_tokens,
+            self.retry_budget,
+            self.chunk_size,
+            self.worker_threads,
+            self.max_blocking_threads,
+            self.adaptive_concurrency
+        )
+    }
+}
+
+/// Returns a snapshot of the current configuration.
+pub fn config() -> Config {
+    CONFIG.read().unwrap().clone()
+}
+
+/// Returns a copy of the current configuration, pass it to `set_config` after editing.
+#[pyfunction]
+pub fn get_config() -> Config {
+    config()
+}
+
+#[pyfunction]
+pub fn set_config(config: Config) -> PyResult<()> {
+    config.validate()?;
+    *CONFIG.write().unwrap() = config;
+    Ok(())
+}
+
+#[pyfunction]
+pub fn reset_config() {
+    *CONFIG.write().unwrap() = Config::default();
+}
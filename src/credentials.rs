@@ -0,0 +1,282 @@
+use once_cell::sync::Lazy;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::time::Instant;
+
+pub const OPENAI: &str = "openai";
+
+// Providers that can be given a key, with the environment variable used as fallback
+const PROVIDERS: &[(&str, &str)] = &[
+    (OPENAI, "OPENAI_API_KEY"),
+    ("cohere", "COHERE_API_KEY"),
+    ("voyage", "VOYAGE_API_KEY"),
+    ("gemini", "GEMINI_API_KEY"),
+    ("mistral", "MISTRAL_API_KEY"),
+    ("jina", "JINA_API_KEY"),
+];
+
+// Keys set from Python, these take precedence over the environment
+static API_KEYS: Lazy<RwLock<HashMap<String, Arc<KeyPool>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+#[derive(Clone, Copy)]
+pub enum Rotation {
+    RoundRobin,
+    LeastLoaded,
+}
+
+struct PooledKey {
+    key: String,
+    in_flight: AtomicUsize,
+    // Set once the provider rejects the key for good (revoked, out of quota)
+    disabled: AtomicBool,
+    // Earliest time the next request may use this key
+    next_slot: Mutex<Instant>,
+}
+
+/// Set of keys for one provider that requests are spread across.
+pub struct KeyPool {
+    keys: Vec<PooledKey>,
+    rotation: Rotation,
+    min_interval: Option<Duration>,
+    next: AtomicUsize,
+}
+
+impl KeyPool {
+    pub fn new(keys: Vec<String>, rotation: Rotation, requests_per_minute: Option<u32>) -> Self {
+        let now = Instant::now();
+        KeyPool {
+            keys: keys
+                .into_iter()
+                .map(|key| PooledKey {
+                    key,
+                    in_flight: AtomicUsize::new(0),
+                    disabled: AtomicBool::new(false),
+                    next_slot: Mutex::new(now),
+                })
+                .collect(),
+            rotation,
+            min_interval: requests_per_minute
+                .filter(|rpm| *rpm > 0)
+                .map(|rpm| Duration::from_secs_f64(60.0 / rpm as f64)),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    fn pick(&self) -> Option<usize> {
+        let enabled = |i: &usize| !self.keys[*i].disabled.load(Ordering::Relaxed);
+        match self.rotation {
+            Rotation::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..self.keys.len())
+                    .map(|offset| (start + offset) % self.keys.len())
+                    .find(enabled)
+            }
+            Rotation::LeastLoaded => (0..self.keys.len())
+                .filter(enabled)
+                .min_by_key(|i| self.keys[*i].in_flight.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// A key checked out for one request, releases its in-flight slot on drop.
+pub struct KeyLease {
+    pool: Option<(Arc<KeyPool>, usize)>,
+    key: String,
+}
+
+impl KeyLease {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Takes the key out of rotation if the provider rejected it permanently.
+    pub fn report(&self, status: u16, body: &str) {
+        let Some((pool, index)) = &self.pool else {
+            return;
+        };
+        let hard_failure = status == 401 || (status == 429 && body.contains("insufficient_quota"));
+        if hard_failure {
+            pool.keys[*index].disabled.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for KeyLease {
+    fn drop(&mut self) {
+        if let Some((pool, index)) = &self.pool {
+            pool.keys[*index].in_flight.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn env_key(provider: &str) -> String {
+    key_variable(provider)
+        .and_then(|variable| std::env::var(variable).ok())
+        .unwrap_or_default()
+}
+
+/// Environment variable the key of `provider` is read from.
+pub fn key_variable(provider: &str) -> Option<&'static str> {
+    PROVIDERS
+        .iter()
+        .find(|(name, _)| *name == provider)
+        .map(|(_, variable)| *variable)
+}
+
+fn pool(provider: &str) -> Option<Arc<KeyPool>> {
+    API_KEYS.read().unwrap().get(provider).cloned()
+}
+
+fn all_disabled(provider: &str) -> String {
+    format!("all keys for {} are disabled", provider)
+}
+
+/// Returns a key for `provider` from the keys set in Python, falling back to
+/// its environment variable and then to an empty string. Once every key set
+/// in Python was rejected, there is no key to fall back to.
+pub fn api_key(provider: &str) -> Result<String, String> {
+    match pool(provider) {
+        Some(pool) => pool
+            .pick()
+            .map(|i| pool.keys[i].key.clone())
+            .ok_or_else(|| all_disabled(provider)),
+        None => Ok(env_key(provider)),
+    }
+}
+
+/// Every key set in Python or found in the environment, so they can be
+/// scrubbed from anything written out.
+pub fn known_keys() -> Vec<String> {
+    let mut keys: Vec<String> = API_KEYS
+        .read()
+        .unwrap()
+        .values()
+        .flat_map(|pool| pool.keys.iter().map(|k| k.key.clone()))
+        .collect();
+    keys.extend(PROVIDERS.iter().map(|(name, _)| env_key(name)));
+    keys.retain(|key| !key.is_empty());
+    keys
+}
+
+/// Like `api_key`, but tracks the request against the key and waits for the
+/// key's rate limit if one is set.
+pub async fn acquire_key(provider: &str) -> Result<KeyLease, String> {
+    let Some(pool) = pool(provider) else {
+        return Ok(KeyLease {
+            pool: None,
+            key: env_key(provider),
+        });
+    };
+    let index = pool.pick().ok_or_else(|| all_disabled(provider))?;
+
+    let pooled = &pool.keys[index];
+    pooled.in_flight.fetch_add(1, Ordering::Relaxed);
+    if let Some(interval) = pool.min_interval {
+        let slot = {
+            let mut next_slot = pooled.next_slot.lock().unwrap();
+            let slot = (*next_slot).max(Instant::now());
+            *next_slot = slot + interval;
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+
+    let key = pooled.key.clone();
+    Ok(KeyLease {
+        pool: Some((pool, index)),
+        key,
+    })
+}
+
+fn check_provider(provider: &str) -> PyResult<String> {
+    let provider = provider.to_lowercase();
+    if PROVIDERS.iter().any(|(name, _)| *name == provider) {
+        Ok(provider)
+    } else {
+        Err(PyValueError::new_err(format!(
+            "unknown provider: {}",
+            provider
+        )))
+    }
+}
+
+/// Sets the API key used for `provider` without touching the process environment.
+#[pyfunction]
+pub fn set_api_key(provider: &str, key: String) -> PyResult<()> {
+    let provider = check_provider(provider)?;
+    let pool = KeyPool::new(vec![key], Rotation::RoundRobin, None);
+    API_KEYS.write().unwrap().insert(provider, Arc::new(pool));
+    Ok(())
+}
+
+/// Spreads requests to `provider` across several keys.
+///
+/// `rotation` is either "round_robin" or "least_loaded" (fewest requests in
+/// flight). Keys that are rejected as invalid or out of quota are dropped
+/// from the rotation for the rest of the session.
+#[pyfunction]
+#[pyo3(signature = (provider, keys, rotation="round_robin", requests_per_minute=None))]
+pub fn set_api_keys(
+    provider: &str,
+    keys: Vec<String>,
+    rotation: &str,
+    requests_per_minute: Option<u32>,
+) -> PyResult<()> {
+    let provider = check_provider(provider)?;
+    if keys.is_empty() {
+        return Err(PyValueError::new_err("keys must not be empty"));
+    }
+    let rotation = match rotation {
+        "round_robin" => Rotation::RoundRobin,
+        "least_loaded" => Rotation::LeastLoaded,
+        other => {
+            return Err(PyValueError::new_err(format!(
+                "unknown rotation: {}",
+                other
+            )))
+        }
+    };
+    let pool = KeyPool::new(keys, rotation, requests_per_minute);
+    API_KEYS.write().unwrap().insert(provider, Arc::new(pool));
+    Ok(())
+}
+
+/// Forgets the keys set from Python, the environment variable applies again.
+#[pyfunction]
+pub fn clear_api_key(provider: &str) -> PyResult<()> {
+    let provider = check_provider(provider)?;
+    API_KEYS.write().unwrap().remove(&provider);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_pool_of_disabled_keys_does_not_fall_back_to_the_environment() {
+        let pool = KeyPool::new(
+            vec!["first".into(), "second".into()],
+            Rotation::RoundRobin,
+            None,
+        );
+        for key in &pool.keys {
+            key.disabled.store(true, Ordering::Relaxed);
+        }
+        API_KEYS
+            .write()
+            .unwrap()
+            .insert("jina".to_string(), Arc::new(pool));
+
+        assert_eq!(
+            api_key("jina"),
+            Err("all keys for jina are disabled".to_string())
+        );
+        API_KEYS.write().unwrap().remove("jina");
+    }
+}
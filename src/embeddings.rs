@@ -0,0 +1,295 @@
+use crate::config::{config, Config};
+use crate::credentials::api_key;
+use crate::http::http_client;
+use crate::metrics::CallUsage;
+use crate::provider::Provider;
+use crate::tokens::{count_tokens, encoder};
+use crate::utils::{post_with_retries, FetchError, RequestOptions};
+use futures::stream::{self, StreamExt};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tiktoken_rs::CoreBPE;
+
+/// Provider-specific embedding parameters, passed through when set.
+#[derive(Default, Clone, Deserialize)]
+pub struct EmbeddingParams {
+    #[serde(default)]
+    pub model: Option<String>,
+    // Cohere `input_type`, Voyage `input_type` or Gemini `taskType`
+    #[serde(default)]
+    pub input_type: Option<String>,
+    // Cohere `truncate` (NONE, START, END), any other value than "none"
+    // enables truncation for Voyage
+    #[serde(default)]
+    pub truncate: Option<String>,
+    // Output size for models that support shortened (Matryoshka) embeddings
+    #[serde(default)]
+    pub dimensions: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct DataResponse {
+    data: Vec<DataEmbedding>,
+}
+
+#[derive(Deserialize)]
+struct DataEmbedding {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+#[derive(Deserialize)]
+struct CohereResponse {
+    embeddings: CohereEmbeddings,
+}
+
+#[derive(Deserialize)]
+struct CohereEmbeddings {
+    float: Vec<Vec<f32>>,
+}
+
+#[derive(Deserialize)]
+struct GeminiResponse {
+    embeddings: Vec<GeminiEmbedding>,
+}
+
+#[derive(Deserialize)]
+struct GeminiEmbedding {
+    values: Vec<f32>,
+}
+
+fn default_model(provider: Provider) -> String {
+    match provider {
+        Provider::Cohere => "embed-english-v3.0".to_string(),
+        Provider::Voyage => "voyage-3".to_string(),
+        Provider::Gemini => "text-embedding-004".to_string(),
+        Provider::Mistral => "mistral-embed".to_string(),
+        #[cfg(feature = "local-embeddings")]
+        Provider::Local => crate::local::DEFAULT_MODEL.to_string(),
+        _ => config().embedding_model,
+    }
+}
+
+// (max inputs, max tokens) accepted in one request
+fn batch_limits(provider: Provider) -> (usize, usize) {
+    match provider {
+        Provider::Cohere => (96, 128_000),
+        Provider::Voyage => (1000, 120_000),
+        Provider::Gemini => (100, 200_000),
+        Provider::Mistral => (128, 16_000),
+        // Bounds the memory of one local inference call
+        Provider::Local => (256, usize::MAX),
+        _ => (2048, 300_000),
+    }
+}
+
+/// Scales `vector` to unit length, zero vectors are left unchanged.
+pub fn l2_normalize(vector: &mut [f32]) {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+// Splits inputs into consecutive ranges that fit in one request each
+fn batch_ranges(
+    inputs: &[String],
+    encoder: &CoreBPE,
+    max_inputs: usize,
+    max_tokens: usize,
+) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    let mut tokens = 0;
+    for (i, input) in inputs.iter().enumerate() {
+        let input_tokens = count_tokens(encoder, input);
+        if i > start && (i - start == max_inputs || tokens + input_tokens > max_tokens) {
+            ranges.push((start, i));
+            start = i;
+            tokens = 0;
+        }
+        tokens += input_tokens;
+    }
+    if start < inputs.len() {
+        ranges.push((start, inputs.len()));
+    }
+    ranges
+}
+
+/// Embeds `inputs` with the provider selected in `options`, in input order.
+///
+/// Inputs are sent in as many requests as the provider's batch limits
+/// require, at most `max_concurrency` at a time, an input is None when its
+/// batch failed.
+pub async fn fetch_embeddings(
+    inputs: &[String],
+    options: &RequestOptions,
+    params: &EmbeddingParams,
+) -> Vec<Option<Vec<f32>>> {
+    let (max_inputs, max_tokens) = batch_limits(options.provider);
+    let model = params
+        .model
+        .clone()
+        .unwrap_or_else(|| default_model(options.provider));
+    let config = config();
+    let client = http_client();
+    // Retries of these batches are budgeted apart from any chat call's
+    let usage = CallUsage::default();
+    let (config, client, usage) = (&config, &client, &usage);
+    let batches = batch_ranges(inputs, &encoder(&model), max_inputs, max_tokens)
+        .into_iter()
+        .map(|(start, end)| async move {
+            let batch = &inputs[start..end];
+            match fetch_embedding_batch(client, config, batch, options, params, usage).await {
+                Ok(embeddings) if embeddings.len() == batch.len() => {
+                    embeddings.into_iter().map(Some).collect()
+                }
+                Ok(embeddings) => {
+                    tracing::warn!(
+                        expected = batch.len(),
+                        received = embeddings.len(),
+                        "embedding batch came back incomplete"
+                    );
+                    vec![None; batch.len()]
+                }
+                Err(e) => {
+                    tracing::warn!(error = %e, "embedding batch failed");
+                    vec![None; batch.len()]
+                }
+            }
+        });
+    stream::iter(batches)
+        .buffered(config.max_concurrency.max(1))
+        .collect::<Vec<Vec<_>>>()
+        .await
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+async fn fetch_embedding_batch(
+    client: &Client,
+    config: &Config,
+    inputs: &[String],
+    options: &RequestOptions,
+    params: &EmbeddingParams,
+    usage: &CallUsage,
+) -> Result<Vec<Vec<f32>>, FetchError> {
+    let provider = options.provider;
+    let model = params
+        .model
+        .clone()
+        .unwrap_or_else(|| default_model(provider));
+
+    let (url, body) = match provider {
+        Provider::OpenAI => {
+            let mut body = json!({"input": inputs, "model": model});
+            if let Some(dimensions) = params.dimensions {
+                body["dimensions"] = json!(dimensions);
+            }
+            (config.url("embeddings"), body)
+        }
+        Provider::Mistral => {
+            let mut body = json!({"input": inputs, "model": model});
+            if let Some(dimensions) = params.dimensions {
+                body["output_dimension"] = json!(dimensions);
+            }
+            ("https://api.mistral.ai/v1/embeddings".to_string(), body)
+        }
+        Provider::Voyage => {
+            let mut body = json!({"input": inputs, "model": model});
+            if let Some(dimensions) = params.dimensions {
+                body["output_dimension"] = json!(dimensions);
+            }
+            if let Some(input_type) = &params.input_type {
+                body["input_type"] = json!(input_type);
+            }
+            if let Some(truncate) = &params.truncate {
+                body["truncation"] = json!(!truncate.eq_ignore_ascii_case("none"));
+            }
+            ("https://api.voyageai.com/v1/embeddings".to_string(), body)
+        }
+        Provider::Cohere => {
+            let mut body = json!({
+                "texts": inputs,
+                "model": model,
+                "input_type": params.input_type.as_deref().unwrap_or("search_document"),
+                "embedding_types": ["float"]
+            });
+            if let Some(truncate) = &params.truncate {
+                body["truncate"] = json!(truncate.to_uppercase());
+            }
+            if let Some(dimensions) = params.dimensions {
+                body["output_dimension"] = json!(dimensions);
+            }
+            ("https://api.cohere.com/v2/embed".to_string(), body)
+        }
+        Provider::Gemini => {
+            let requests: Vec<Value> = inputs
+                .iter()
+                .map(|text| {
+                    let mut request = json!({
+                        "model": format!("models/{}", model),
+                        "content": {"parts": [{"text": text}]}
+                    });
+                    if let Some(task_type) = &params.input_type {
+                        request["taskType"] = json!(task_type);
+                    }
+                    if let Some(dimensions) = params.dimensions {
+                        request["outputDimensionality"] = json!(dimensions);
+                    }
+                    request
+                })
+                .collect();
+            (
+                format!(
+                    "https://generativelanguage.googleapis.com/v1beta/models/{}:batchEmbedContents",
+                    model
+                ),
+                json!({ "requests": requests }),
+            )
+        }
+        Provider::Local => {
+            #[cfg(feature = "local-embeddings")]
+            return crate::local::embed(inputs, model).await;
+            #[cfg(not(feature = "local-embeddings"))]
+            return Err(FetchError::Unsupported(
+                "local embeddings need polar-llama built with the local-embeddings feature"
+                    .to_string(),
+            ));
+        }
+        Provider::Mock | Provider::Jina => {
+            return Err(FetchError::Unsupported(format!(
+                "provider {} does not produce embeddings",
+                provider.as_str()
+            )))
+        }
+    };
+
+    // Checked up front, so the batch fails with why there is no key
+    api_key(provider.as_str()).map_err(FetchError::NoKey)?;
+    let (response, _) =
+        match post_with_retries(client, config, options, &url, &body.to_string(), usage).await {
+            Ok(sent) => sent,
+            Err(Some((status, message))) => return Err(FetchError::Http(status, message)),
+            Err(None) => return Err(FetchError::RetriesExhausted),
+        };
+
+    match provider {
+        Provider::Cohere => {
+            let parsed: CohereResponse = response.json().await.map_err(FetchError::Reqwest)?;
+            Ok(parsed.embeddings.float)
+        }
+        Provider::Gemini => {
+            let parsed: GeminiResponse = response.json().await.map_err(FetchError::Reqwest)?;
+            Ok(parsed.embeddings.into_iter().map(|e| e.values).collect())
+        }
+        _ => {
+            let mut parsed: DataResponse = response.json().await.map_err(FetchError::Reqwest)?;
+            // The API does not guarantee the order of the returned embeddings
+            parsed.data.sort_by_key(|d| d.index);
+            Ok(parsed.data.into_iter().map(|d| d.embedding).collect())
+        }
+    }
+}
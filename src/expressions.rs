@@ -1,96 +1,1837 @@
 #![allow(clippy::unused_unit)]
-use crate::utils::*;
-use once_cell::sync::Lazy;
+use crate::capabilities::{preflight, Capability};
+use crate::checkpoint::{Checkpoint, Fixture, FixtureMode, ResponseStores};
+use crate::config::config;
+use crate::embeddings::{fetch_embeddings, l2_normalize, EmbeddingParams};
+use crate::few_shot::{with_examples, Example};
+use crate::guardrails::{injection_instructions, injection_schema, injection_score};
+use crate::http::http_client;
+use crate::json_path::JsonPath;
+use crate::messages::{
+    checked_conversations, conversation_column, conversation_dtype, conversation_from_json,
+    conversation_json, conversations_to_json, data_url, disallowed_role, message_column,
+    message_dtype, message_structs, read_conversations, reply_message, request_messages,
+    with_prediction, EmptyPrompts, Message, ROLES,
+};
+use crate::metrics;
+use crate::pii::{detect_pii, redact, PiiKind, PiiSpan};
+use crate::postprocess::tag_content;
+use crate::pricing::response_cost as price_response;
+use crate::provider::Provider;
+use crate::rerank::{fetch_rerank, RerankParams};
+use crate::responses::OpenAIApi;
+use crate::runtime::block_on;
+use crate::semantic_cache::fetch_data_semantic;
+use crate::structured::{
+    compile_schema, correction_messages, response_format, structure_reply, structured_column,
+    structured_dtype, Structured,
+};
+use crate::tasks::{
+    citation_messages, citation_schema, cited_column, cited_dtype, classification_instructions,
+    classification_schema, entities_column, entities_dtype, entity_instructions,
+    entity_list_column, entity_schema, packed_classification_instructions, packed_messages,
+    packed_schema, tags_column, tags_dtype, task_messages, taxonomy_instructions, taxonomy_schema,
+    unpack_results, Entity, Taxonomy,
+};
+use crate::template::{render, Escape, MissingValues};
+use crate::tokens::{
+    chunk_text as chunk, count_tokens as count, encoder, truncate_tokens as truncate, Truncation,
+};
+use crate::utils::{self, *};
+use futures::stream::{self, StreamExt};
+use jsonschema::Validator;
+use polars::chunked_array::builder::AnonymousOwnedListBuilder;
+use polars::export::arrow::array::Utf8ViewArray;
 use polars::prelude::*;
 use pyo3_polars::derive::polars_expr;
 use serde::Deserialize;
+use serde_json::Value;
 // use serde::{Deserialize, Serialize};
-use std::fmt::Write;
-use tokio::runtime::Runtime;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::time::Duration;
 
-// Initialize a global runtime for all async operations
-static RT: Lazy<Runtime> = Lazy::new(|| Runtime::new().expect("Failed to create Tokio runtime"));
+fn check_chat_provider(options: &RequestOptions) -> PolarsResult<()> {
+    polars_ensure!(
+        options.provider.supports_chat(),
+        ComputeError: "provider {} does not support chat completions", options.provider.as_str()
+    );
+    polars_ensure!(
+        !(options.stream && options.api == OpenAIApi::Responses),
+        ComputeError: "streaming is not supported with the Responses API"
+    );
+    preflight(options, &options.model_name(&config()), &[])
+        .map_err(|e| polars_err!(ComputeError: "{}", e))
+}
+
+// Fails on the first message whose role, once renamed by `role_map`, is not
+// one of the Chat Completions roles every chat provider here accepts, rather
+// than letting the provider reject each row
+fn check_roles(
+    rows: &[Option<String>],
+    first: usize,
+    options: &RequestOptions,
+) -> PolarsResult<()> {
+    for (i, row) in rows.iter().enumerate() {
+        let Some(role) = row
+            .as_deref()
+            .and_then(|row| disallowed_role(row, &options.role_map, ROLES))
+        else {
+            continue;
+        };
+        polars_bail!(
+            ComputeError: "row {} has a message with role {:?}; expected one of {} or a role_map entry for it",
+            first + i, role, ROLES.join(", ")
+        );
+    }
+    Ok(())
+}
 
 #[polars_expr(output_type=String)]
-fn inference(inputs: &[Series]) -> PolarsResult<Series> {
+fn inference(inputs: &[Series], kwargs: RequestOptions) -> PolarsResult<Series> {
     let ca: &StringChunked = inputs[0].str()?;
-    let out = ca.apply_to_buffer(|value: &str, output: &mut String| {
-        let response = fetch_api_response_sync(value, "gpt-4-turbo");
-        response.unwrap().chars().for_each(|c| output.push(c));
+    check_chat_provider(&kwargs)?;
+    // A failed request leaves its row null, as with inference_async
+    let out = StringChunked::from_iter_options(
+        ca.name(),
+        ca.into_iter()
+            .map(|value| fetch_api_response_sync(value?, &kwargs).ok()),
+    );
+    Ok(out.into_series())
+}
+
+#[derive(Deserialize, Clone)]
+pub struct InferenceKwargs {
+    #[serde(default)]
+    checkpoint_path: Option<String>,
+    // Record responses to, and replay them from, a fixture file
+    #[serde(default)]
+    fixture_path: Option<String>,
+    #[serde(default)]
+    fixture_mode: FixtureMode,
+    // Reuse responses for prompts at least this cosine-similar to a cached one
+    #[serde(default)]
+    semantic_cache_threshold: Option<f32>,
+    #[serde(default)]
+    on_empty: EmptyPrompts,
+    #[serde(flatten)]
+    options: RequestOptions,
+}
+
+// Accepts JSON messages, message structs or lists of message structs
+#[polars_expr(output_type=String)]
+fn inference_async(inputs: &[Series], kwargs: InferenceKwargs) -> PolarsResult<Series> {
+    run_inference(&inputs[0], kwargs)
+}
+
+// Rewrites where most of each reply repeats the second input, sent as the
+// predicted output of the row; rows without a prediction are sent as usual
+#[polars_expr(output_type=String)]
+fn inference_predicted(inputs: &[Series], kwargs: InferenceKwargs) -> PolarsResult<Series> {
+    let predictions = inputs[1].cast(&DataType::String)?;
+    let rows = prepare_rows(request_messages(&inputs[0])?, &kwargs)?
+        .into_iter()
+        .zip(predictions.str()?)
+        .map(|(row, prediction)| match (row, prediction) {
+            (Some(row), Some(prediction)) => with_prediction(&row, prediction),
+            (row, _) => row,
+        })
+        .collect();
+    let call = start_call(&kwargs)?;
+    let results = send_rows(rows, &kwargs, &call);
+    let out = StringChunked::from_iter_options("output", results.into_iter());
+    Ok(out.into_series())
+}
+
+// Sends the rows in order of the second input's priority, highest first, so
+// urgent rows get the first requests when concurrency or rate limits hold the
+// rest back. Rows without a priority go last and ties keep their row order;
+// the output is in row order.
+#[polars_expr(output_type=String)]
+fn inference_prioritized(inputs: &[Series], kwargs: InferenceKwargs) -> PolarsResult<Series> {
+    let mut rows = prepare_rows(request_messages(&inputs[0])?, &kwargs)?;
+    let priorities = inputs[1].cast(&DataType::Float64)?;
+    let priorities: Vec<Option<f64>> = priorities.f64()?.into_iter().collect();
+    polars_ensure!(
+        priorities.len() == rows.len(),
+        ComputeError: "priorities and messages must have the same length"
+    );
+    let mut order: Vec<usize> = (0..rows.len()).collect();
+    order.sort_by(|&a, &b| match (priorities[b], priorities[a]) {
+        (Some(b), Some(a)) => b.total_cmp(&a),
+        (b, a) => b.is_some().cmp(&a.is_some()),
     });
+    let sorted = order
+        .into_iter()
+        .filter_map(|i| rows[i].take().map(|row| (i, row)))
+        .collect();
+    let call = start_call(&kwargs)?;
+    let results = send_indexed(sorted, rows.len(), &kwargs, &call);
+    let out = StringChunked::from_iter_options("output", results.into_iter());
     Ok(out.into_series())
 }
 
 #[polars_expr(output_type=String)]
-fn inference_async(inputs: &[Series]) -> PolarsResult<Series> {
+fn inference_messages(inputs: &[Series], kwargs: InferenceKwargs) -> PolarsResult<Series> {
+    polars_ensure!(
+        matches!(inputs[0].dtype(), DataType::List(inner) if matches!(inner.as_ref(), DataType::Struct(_))),
+        ComputeError: "inference_messages expects a list of message structs, got {}", inputs[0].dtype()
+    );
+    run_inference(&inputs[0], kwargs)
+}
+
+fn run_inference(input: &Series, kwargs: InferenceKwargs) -> PolarsResult<Series> {
+    let build = |rows: Range<usize>| request_messages(&slice_rows(input, rows));
+    check_chunks(input.len(), &kwargs.on_empty, &build, |rows, first| {
+        check_rows(rows, first, &kwargs.options)
+    })?;
+    let call = start_call(&kwargs)?.tracking(input.len());
+    let results = map_chunks(input.len(), &kwargs.on_empty, &build, |rows, first| {
+        send_rows_from(rows, first, &kwargs, &call)
+    })?;
+    let out = StringChunked::from_iter_options("output", results.into_iter());
+    Ok(out.into_series())
+}
+
+fn slice_rows(input: &Series, rows: Range<usize>) -> Series {
+    input.slice(rows.start as i64, rows.len())
+}
+
+// Builds the rows of `len` inputs `Config.chunk_size` at a time and runs
+// `check` and the empty prompt policy on each chunk, so that a bad row
+// fails the call before anything is sent. Each chunk is dropped once
+// checked. An empty input is checked as one empty chunk.
+fn check_chunks(
+    len: usize,
+    on_empty: &EmptyPrompts,
+    build: &impl Fn(Range<usize>) -> PolarsResult<Vec<Option<String>>>,
+    mut check: impl FnMut(&[Option<String>], usize) -> PolarsResult<()>,
+) -> PolarsResult<()> {
+    let chunk_size = config().chunk_size.max(1);
+    for first in (0..len.max(1)).step_by(chunk_size) {
+        let rows = build(first..len.min(first + chunk_size))?;
+        check(&rows, first)?;
+        on_empty.apply_from(rows, first)?;
+    }
+    Ok(())
+}
+
+// Builds the rows of `len` inputs `Config.chunk_size` at a time, with the
+// empty prompt policy applied, and passes each chunk to `map` with its
+// first row. The next chunk is built on another thread while `map` runs,
+// so at most two chunks of requests are held at once. Returns what `map`
+// returned for every row, in order.
+fn map_chunks<T>(
+    len: usize,
+    on_empty: &EmptyPrompts,
+    build: &(impl Fn(Range<usize>) -> PolarsResult<Vec<Option<String>>> + Sync),
+    mut map: impl FnMut(Vec<Option<String>>, usize) -> Vec<T>,
+) -> PolarsResult<Vec<T>> {
+    let chunk_size = config().chunk_size.max(1);
+    let mut out = Vec::with_capacity(len);
+    std::thread::scope(|scope| -> PolarsResult<()> {
+        let prepare = |first: usize| {
+            scope.spawn(move || {
+                let rows = build(first..len.min(first + chunk_size))?;
+                on_empty.apply_from(rows, first)
+            })
+        };
+        let mut next = (len > 0).then(|| prepare(0));
+        let mut first = 0;
+        while let Some(building) = next.take() {
+            let rows = building
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))?;
+            let following = first + chunk_size;
+            next = (following < len).then(|| prepare(following));
+            out.extend(map(rows, first));
+            first = following;
+        }
+        Ok(())
+    })?;
+    Ok(out)
+}
+
+// Opens the response stores of a call and starts its metrics
+fn start_call(kwargs: &InferenceKwargs) -> PolarsResult<Call> {
+    let checkpoint = match &kwargs.checkpoint_path {
+        Some(path) => {
+            let path = config().resolve_path(path);
+            let checkpoint = Checkpoint::open(&path).map_err(|e| {
+                polars_err!(ComputeError: "failed to open checkpoint {}: {}", path.display(), e)
+            })?;
+            Some(checkpoint)
+        }
+        None => None,
+    };
+    let fixture = match &kwargs.fixture_path {
+        Some(path) => {
+            let path = config().resolve_path(path);
+            let fixture = Fixture::open(&path, kwargs.fixture_mode).map_err(
+                |e| polars_err!(ComputeError: "failed to open fixture {}: {}", path.display(), e),
+            )?;
+            Some(fixture)
+        }
+        None => None,
+    };
+    metrics::reset();
+    Ok(Call::new(ResponseStores {
+        checkpoint,
+        fixture,
+    }))
+}
+
+// Checks the provider, model and roles of the rows, in input order, before
+// anything is sent
+fn check_rows(rows: &[Option<String>], first: usize, options: &RequestOptions) -> PolarsResult<()> {
+    check_chat_provider(options)?;
+    if rows
+        .iter()
+        .flatten()
+        .any(|row| row.contains("\"image_url\""))
+    {
+        preflight(
+            options,
+            &options.model_name(&config()),
+            &[Capability::Vision],
+        )
+        .map_err(|e| polars_err!(ComputeError: "{}", e))?;
+    }
+    check_roles(rows, first, options)
+}
+
+// `check_rows`, then the empty prompt policy, returning the rows to send
+fn prepare_rows(
+    rows: Vec<Option<String>>,
+    kwargs: &InferenceKwargs,
+) -> PolarsResult<Vec<Option<String>>> {
+    check_rows(&rows, 0, &kwargs.options)?;
+    kwargs.on_empty.apply(rows)
+}
+
+// Sends the JSON messages of the non-null rows, the responses are aligned
+// with `rows` and null where a row was null or its request failed
+fn infer_rows(
+    rows: Vec<Option<String>>,
+    kwargs: &InferenceKwargs,
+) -> PolarsResult<Vec<Option<String>>> {
+    let rows = prepare_rows(rows, kwargs)?;
+    let call = start_call(kwargs)?;
+    Ok(send_rows(rows, kwargs, &call))
+}
+
+// `infer_rows` for a call already started, for follow-up requests
+// made within the same call
+fn send_rows(
+    rows: Vec<Option<String>>,
+    kwargs: &InferenceKwargs,
+    call: &Call,
+) -> Vec<Option<String>> {
+    send_rows_from(rows, 0, kwargs, call)
+}
+
+// `send_rows` for a chunk of rows starting at row `first`
+fn send_rows_from(
+    rows: Vec<Option<String>>,
+    first: usize,
+    kwargs: &InferenceKwargs,
+    call: &Call,
+) -> Vec<Option<String>> {
+    let len = rows.len();
+    let messages = indexed_rows(rows, first);
+    let results = block_on(fetch_messages(&messages, kwargs, call));
+    let mut out = vec![None; len];
+    for ((row, _), result) in messages.into_iter().zip(results) {
+        out[row - first] = result;
+    }
+    out
+}
+
+// The non-null rows of a chunk starting at row `first`, with their row
+fn indexed_rows(rows: Vec<Option<String>>, first: usize) -> Vec<(usize, String)> {
+    rows.into_iter()
+        .enumerate()
+        .filter_map(|(i, row)| row.map(|m| (first + i, m)))
+        .collect()
+}
+
+// `fetch_data` through the semantic cache when the call has a threshold
+async fn fetch_messages(
+    messages: &[(usize, String)],
+    kwargs: &InferenceKwargs,
+    call: &Call,
+) -> Vec<Option<String>> {
+    let options = &kwargs.options;
+    match kwargs.semantic_cache_threshold {
+        Some(threshold) => fetch_data_semantic(messages, threshold, options, call).await,
+        None => fetch_data(messages, options, call).await,
+    }
+}
+
+// Sends messages given with their row, in the order given, the responses
+// are aligned with the `len` rows and null where a row was not sent
+fn send_indexed(
+    messages: Vec<(usize, String)>,
+    len: usize,
+    kwargs: &InferenceKwargs,
+    call: &Call,
+) -> Vec<Option<String>> {
+    let results = block_on(fetch_messages(&messages, kwargs, call));
+    let mut out = vec![None; len];
+    for ((i, _), result) in messages.into_iter().zip(results) {
+        out[i] = result;
+    }
+    out
+}
+
+#[derive(Deserialize)]
+pub struct CompareKwargs {
+    // Models every row is sent to, each optionally prefixed by its provider, e.g. mock/gpt-4o
+    models: Vec<String>,
+    #[serde(flatten)]
+    inference: InferenceKwargs,
+}
+
+fn comparison_dtype() -> DataType {
+    DataType::Struct(vec![
+        Field::new("answer", DataType::String),
+        Field::new("latency_ms", DataType::Float64),
+        Field::new("prompt_tokens", DataType::UInt64),
+        Field::new("completion_tokens", DataType::UInt64),
+    ])
+}
+
+fn compare_output(input_fields: &[Field], kwargs: CompareKwargs) -> PolarsResult<Field> {
+    let fields = kwargs
+        .models
+        .iter()
+        .map(|model| Field::new(model, comparison_dtype()))
+        .collect();
+    Ok(Field::new(input_fields[0].name(), DataType::Struct(fields)))
+}
+
+// The reply, latency and token usage of one model for every row
+fn comparison_column(name: &str, responses: &[(Option<String>, Duration)]) -> PolarsResult<Series> {
+    let parsed: Vec<Option<Value>> = responses
+        .iter()
+        .map(|(r, _)| r.as_deref().and_then(|r| serde_json::from_str(r).ok()))
+        .collect();
+    let usage = |key: &str| {
+        UInt64Chunked::from_iter_options(
+            key,
+            parsed
+                .iter()
+                .map(|r| r.as_ref().and_then(|r| r["usage"][key].as_u64())),
+        )
+        .into_series()
+    };
+    let answer = StringChunked::from_iter_options(
+        "answer",
+        responses
+            .iter()
+            .map(|(r, _)| r.as_deref().and_then(reply_message).map(|m| m.text())),
+    );
+    let latency = Float64Chunked::from_iter_options(
+        "latency_ms",
+        responses
+            .iter()
+            .map(|(r, latency)| r.as_ref().map(|_| latency.as_secs_f64() * 1000.0)),
+    );
+    let fields = [
+        answer.into_series(),
+        latency.into_series(),
+        usage("prompt_tokens"),
+        usage("completion_tokens"),
+    ];
+    Ok(StructChunked::new(name, &fields)?.into_series())
+}
+
+// Sends every row to each of `models` at once, as a struct with a field per
+// model holding its answer, latency and tokens. Rows served from a
+// checkpoint or fixture have a latency of zero.
+#[polars_expr(output_type_func_with_kwargs=compare_output)]
+fn compare_models(inputs: &[Series], kwargs: CompareKwargs) -> PolarsResult<Series> {
+    polars_ensure!(!kwargs.models.is_empty(), ComputeError: "compare_models needs at least one model");
+    let variants: Vec<RequestOptions> = kwargs
+        .models
+        .iter()
+        .map(|entry| {
+            let mut options = kwargs.inference.options.clone();
+            let (provider, model) = match entry.split_once('/') {
+                Some((prefix, model)) => match Provider::from_name(prefix) {
+                    Some(provider) => (provider, model),
+                    None => (options.provider, entry.as_str()),
+                },
+                None => (options.provider, entry.as_str()),
+            };
+            options.provider = provider;
+            options.model = Some(model.to_string());
+            options.route = None;
+            options
+        })
+        .collect();
+    let len = inputs[0].len();
+    let build = |rows: Range<usize>| request_messages(&slice_rows(&inputs[0], rows));
+    let on_empty = &kwargs.inference.on_empty;
+    check_chunks(len, on_empty, &build, |rows, first| {
+        variants
+            .iter()
+            .try_for_each(|options| check_rows(rows, first, options))
+    })?;
+    let call = start_call(&kwargs.inference)?.tracking(len * variants.len());
+    // The responses of every variant for each row
+    let rows: Vec<Vec<(Option<String>, Duration)>> =
+        map_chunks(len, on_empty, &build, |rows, first| {
+            let len = rows.len();
+            let messages = indexed_rows(rows, first);
+            let mut out = vec![vec![(None, Duration::ZERO); variants.len()]; len];
+            let results = block_on(fetch_variants(&messages, &variants, &call));
+            for (variant, responses) in results.into_iter().enumerate() {
+                for ((row, _), response) in messages.iter().zip(responses) {
+                    out[row - first][variant] = response;
+                }
+            }
+            out
+        })?;
+    let mut results: Vec<Vec<(Option<String>, Duration)>> =
+        vec![Vec::with_capacity(len); variants.len()];
+    for row in rows {
+        for (variant, response) in row.into_iter().enumerate() {
+            results[variant].push(response);
+        }
+    }
+    let fields = kwargs
+        .models
+        .iter()
+        .zip(&results)
+        .map(|(name, responses)| comparison_column(name, responses))
+        .collect::<PolarsResult<Vec<_>>>()?;
+    Ok(StructChunked::new(inputs[0].name(), &fields)?.into_series())
+}
+
+#[derive(Deserialize)]
+pub struct XmlTagsKwargs {
+    tags: Vec<String>,
+}
+
+fn xml_tags_output(input_fields: &[Field], kwargs: XmlTagsKwargs) -> PolarsResult<Field> {
+    let fields = kwargs
+        .tags
+        .iter()
+        .map(|tag| Field::new(tag, DataType::String))
+        .collect();
+    Ok(Field::new(input_fields[0].name(), DataType::Struct(fields)))
+}
+
+// Struct with the trimmed text of each of `tags` in every reply, null where
+// a tag is missing. Rows may hold the reply text or the whole response.
+#[polars_expr(output_type_func_with_kwargs=xml_tags_output)]
+fn parse_xml_tags(inputs: &[Series], kwargs: XmlTagsKwargs) -> PolarsResult<Series> {
+    polars_ensure!(!kwargs.tags.is_empty(), ComputeError: "parse_xml_tags needs at least one tag");
+    let replies: Vec<Option<String>> = inputs[0]
+        .str()?
+        .into_iter()
+        .map(|row| {
+            let row = row?;
+            Some(reply_message(row).map_or_else(|| row.to_string(), |m| m.text()))
+        })
+        .collect();
+    let fields: Vec<Series> = kwargs
+        .tags
+        .iter()
+        .map(|tag| {
+            StringChunked::from_iter_options(
+                tag,
+                replies
+                    .iter()
+                    .map(|reply| tag_content(reply.as_deref()?, tag).map(|content| content.trim())),
+            )
+            .into_series()
+        })
+        .collect();
+    Ok(StructChunked::new(inputs[0].name(), &fields)?.into_series())
+}
+
+// Hash each row's request would be checkpointed and cached under, with the
+// same kwargs as the inference expressions. Null for rows that would not be
+// sent.
+#[polars_expr(output_type=String)]
+fn request_fingerprint(inputs: &[Series], kwargs: RequestOptions) -> PolarsResult<Series> {
+    polars_ensure!(
+        kwargs.route.is_none(),
+        ComputeError: "routed requests have no fingerprint until a model is chosen for them"
+    );
+    let model = kwargs.model_name(&config());
+    let messages = request_messages(&inputs[0])?;
+    let out: StringChunked = messages
+        .iter()
+        .map(|message| utils::request_fingerprint(message.as_deref()?, &model, &kwargs))
+        .collect();
+    Ok(out.with_name(inputs[0].name()).into_series())
+}
+
+#[derive(Deserialize)]
+pub struct CostKwargs {
+    // Priced model for responses that do not name theirs, the configured model by default
+    #[serde(default)]
+    model: Option<String>,
+}
+
+// Estimated cost in USD of each response, including dry runs, from the
+// usage it reports. Null for failed requests and unpriced models.
+#[polars_expr(output_type=Float64)]
+fn response_cost(inputs: &[Series], kwargs: CostKwargs) -> PolarsResult<Series> {
+    let model = kwargs.model.unwrap_or_else(|| config().model);
+    let responses = inputs[0].str()?;
+    let out: Float64Chunked = responses
+        .into_iter()
+        .map(|response| price_response(response?, &model))
+        .collect();
+    Ok(out.with_name(inputs[0].name()).into_series())
+}
+
+fn usage_dtype() -> DataType {
+    DataType::Struct(vec![
+        Field::new("prompt_tokens", DataType::UInt64),
+        Field::new("completion_tokens", DataType::UInt64),
+        Field::new("cached_tokens", DataType::UInt64),
+        Field::new("reasoning_tokens", DataType::UInt64),
+    ])
+}
+
+fn usage_output(input_fields: &[Field]) -> PolarsResult<Field> {
+    Ok(Field::new(input_fields[0].name(), usage_dtype()))
+}
+
+// Token usage each response reports, including dry runs, null for failed
+// requests and responses without usage
+#[polars_expr(output_type_func=usage_output)]
+fn response_usage(inputs: &[Series]) -> PolarsResult<Series> {
+    let usages: Vec<Option<metrics::Usage>> = inputs[0]
+        .str()?
+        .into_iter()
+        .map(|response| metrics::response_usage(response?))
+        .collect();
+    let count = |name: &str, count: fn(&metrics::Usage) -> u64| {
+        UInt64Chunked::from_iter_options(name, usages.iter().map(|u| u.as_ref().map(count)))
+            .into_series()
+    };
+    let fields = [
+        count("prompt_tokens", |u| u.prompt_tokens),
+        count("completion_tokens", |u| u.completion_tokens),
+        count("cached_tokens", metrics::Usage::cached_tokens),
+        count("reasoning_tokens", metrics::Usage::reasoning_tokens),
+    ];
+    Ok(StructChunked::new(inputs[0].name(), &fields)?.into_series())
+}
+
+#[derive(Deserialize)]
+pub struct ExtractKwargs {
+    path: String,
+}
+
+// The value at `path` in each row's JSON, such as a raw response or the
+// `json` of a structured one. Strings are returned as they are and other
+// values as JSON, null where the row is not JSON or has nothing there.
+#[polars_expr(output_type=String)]
+fn extract_json(inputs: &[Series], kwargs: ExtractKwargs) -> PolarsResult<Series> {
+    let path = JsonPath::parse(&kwargs.path).map_err(|e| polars_err!(ComputeError: "{}", e))?;
+    let documents = inputs[0].str()?;
+    let out: StringChunked = documents
+        .into_iter()
+        .map(|document| path.extract(document?))
+        .collect();
+    Ok(out.with_name(inputs[0].name()).into_series())
+}
+
+// Runs the conversations of the second input's ids in parallel, and the
+// turns of each conversation one after another in row order. Every row's
+// messages are sent after the earlier turns of its conversation and their
+// replies, the output is the reply of each row.
+#[polars_expr(output_type=String)]
+fn inference_sessions(inputs: &[Series], kwargs: InferenceKwargs) -> PolarsResult<Series> {
+    // Turns are checked and converted a chunk at a time, and the request of
+    // each turn is only built when it is sent
+    let len = inputs[0].len();
+    let build = |rows: Range<usize>| {
+        Ok(conversations_to_json(read_conversations(&slice_rows(
+            &inputs[0], rows,
+        ))?))
+    };
+    check_chunks(len, &kwargs.on_empty, &build, |rows, first| {
+        check_rows(rows, first, &kwargs.options)
+    })?;
+    let turns: Vec<Option<Vec<Message>>> = map_chunks(len, &kwargs.on_empty, &build, |rows, _| {
+        rows.iter()
+            .map(|turn| conversation_from_json(turn.as_deref()?))
+            .collect()
+    })?;
+    let ids = inputs[1].cast(&DataType::String)?;
+    let ids = ids.str()?;
+    polars_ensure!(
+        ids.len() == turns.len(),
+        ComputeError: "conversation ids and messages must have the same length"
+    );
+
+    // Rows of each conversation in order, null ids are conversations of their own
+    let mut sessions: Vec<Vec<usize>> = Vec::new();
+    let mut session_of: HashMap<&str, usize> = HashMap::new();
+    for (row, id) in ids.into_iter().enumerate() {
+        match id {
+            Some(id) => {
+                let session = *session_of.entry(id).or_insert_with(|| {
+                    sessions.push(Vec::new());
+                    sessions.len() - 1
+                });
+                sessions[session].push(row);
+            }
+            None => sessions.push(vec![row]),
+        }
+    }
+
+    let call = start_call(&kwargs)?;
+    let kwargs = &kwargs;
+    let turns = &turns;
+    let call = &call;
+    let replies: Vec<(usize, Option<String>)> = block_on(
+        stream::iter(sessions)
+            .map(|rows| async move {
+                let mut history: Vec<Message> = Vec::new();
+                let mut replies = Vec::with_capacity(rows.len());
+                for row in rows {
+                    let Some(turn) = &turns[row] else {
+                        replies.push((row, None));
+                        continue;
+                    };
+                    history.extend(turn.iter().cloned());
+                    let body = [(row, conversation_json(&history))];
+                    let response = fetch_messages(&body, kwargs, call).await.pop().flatten();
+                    // A failed turn is left out of the history of the next ones
+                    let reply = response.as_deref().and_then(reply_message);
+                    if let Some(reply) = &reply {
+                        history.push(reply.clone());
+                    }
+                    replies.push((row, reply.map(|m| m.text())));
+                }
+                replies
+            })
+            .buffer_unordered(config().max_concurrency.max(1))
+            .flat_map(stream::iter)
+            .collect(),
+    );
+
+    let mut out: Vec<Option<String>> = vec![None; turns.len()];
+    for (row, reply) in replies {
+        out[row] = reply;
+    }
+    Ok(StringChunked::from_iter_options(inputs[0].name(), out.into_iter()).into_series())
+}
+
+#[derive(Deserialize)]
+pub struct AppendKwargs {
+    role: String,
+    #[serde(default)]
+    name: Option<String>,
+}
+
+// Appends a message with the content of the second input to every
+// conversation. Null conversations start empty, null contents leave the
+// conversation unchanged.
+#[polars_expr(output_type_func=conversation_output)]
+fn append_message(inputs: &[Series], kwargs: AppendKwargs) -> PolarsResult<Series> {
+    let mut history = read_conversations(&inputs[0])?;
+    let contents = inputs[1].str()?;
+    // A literal history, such as `pl.lit(None)`, is shared by every row
+    if history.len() == 1 && contents.len() != 1 {
+        history = vec![history[0].clone(); contents.len()];
+    }
+    let rows: Vec<Option<Vec<Message>>> = history
+        .into_iter()
+        .zip(contents)
+        .map(|(history, content)| {
+            let mut history = history.unwrap_or_default();
+            if let Some(content) = content {
+                history.push(Message {
+                    name: kwargs.name.clone(),
+                    ..Message::new(&kwargs.role, content)
+                });
+            }
+            Some(history)
+        })
+        .collect();
+    conversation_column(inputs[0].name(), &rows)
+}
+
+fn turn_output(input_fields: &[Field]) -> PolarsResult<Field> {
+    Ok(Field::new(
+        input_fields[0].name(),
+        DataType::Struct(vec![
+            Field::new("reply", DataType::String),
+            Field::new("history", conversation_dtype()),
+        ]),
+    ))
+}
+
+// One turn of a conversation: sends every conversation and returns the
+// assistant's reply along with the conversation extended by it. Failed
+// requests have a null reply and leave the history unchanged.
+#[polars_expr(output_type_func=turn_output)]
+fn inference_turn(inputs: &[Series], kwargs: InferenceKwargs) -> PolarsResult<Series> {
+    let mut history = read_conversations(&inputs[0])?;
+    let responses = infer_rows(conversations_to_json(history.clone()), &kwargs)?;
+    let replies: Vec<Option<Message>> = responses
+        .iter()
+        .map(|response| reply_message(response.as_deref()?))
+        .collect();
+    for (conversation, reply) in history.iter_mut().zip(&replies) {
+        if let (Some(conversation), Some(reply)) = (conversation.as_mut(), reply) {
+            conversation.push(reply.clone());
+        }
+    }
+    let reply = StringChunked::from_iter_options(
+        "reply",
+        replies.iter().map(|r| r.as_ref().map(Message::text)),
+    );
+    let history = conversation_column("history", &history)?;
+    Ok(StructChunked::new(inputs[0].name(), &[reply.into_series(), history])?.into_series())
+}
+
+#[derive(Deserialize)]
+pub struct StructuredKwargs {
+    // JSON Schema the reply must match, as a dict or JSON text
+    #[serde(default)]
+    schema: Option<Value>,
+    // Retry parsing with `repair_json` when the reply is not valid JSON
+    #[serde(default = "default_true")]
+    repair: bool,
+    // Re-prompts for replies that are not valid JSON or fail the schema,
+    // with the errors as a corrective message
+    #[serde(default)]
+    max_validation_retries: usize,
+    // Normalize the schema for the provider's strict structured outputs,
+    // otherwise it is sent as given
+    #[serde(default = "default_true")]
+    strict: bool,
+    #[serde(flatten)]
+    inference: InferenceKwargs,
+}
+
+fn structured_output(input_fields: &[Field]) -> PolarsResult<Field> {
+    Ok(Field::new(input_fields[0].name(), structured_dtype()))
+}
+
+// Asks for a JSON reply and parses it, validating it against `schema` when
+// given. An optional second input holds a schema per row, as JSON text,
+// overriding `schema` where it is not null. Failed replies are sent back
+// with the errors up to `max_validation_retries` times, rows still failing
+// keep the reason in `error`.
+#[polars_expr(output_type_func=structured_output)]
+fn inference_json(inputs: &[Series], kwargs: StructuredKwargs) -> PolarsResult<Series> {
+    let provider = kwargs.inference.options.provider;
+    let compile = |schema: &Value| {
+        compile_schema(schema, provider, kwargs.strict)
+            .map_err(|e| polars_err!(ComputeError: "invalid response schema: {}", e))
+    };
+
+    // Distinct schemas and the index of each row's schema among them
+    let mut schemas = Vec::new();
+    if let Some(schema) = &kwargs.schema {
+        schemas.push(compile(schema)?);
+    }
+    let default = (!schemas.is_empty()).then_some(0);
+    let mut row_schemas = vec![default; inputs[0].len()];
+    if let Some(column) = inputs.get(1) {
+        let column = column.cast(&DataType::String)?;
+        let mut seen: HashMap<&str, usize> = HashMap::new();
+        for (i, text) in column.str()?.into_iter().enumerate() {
+            let Some(text) = text else { continue };
+            let index = match seen.get(text) {
+                Some(&index) => index,
+                None => {
+                    schemas.push(compile(&Value::String(text.to_string()))?);
+                    seen.insert(text, schemas.len() - 1);
+                    schemas.len() - 1
+                }
+            };
+            row_schemas[i] = Some(index);
+        }
+    }
+
+    let inference = kwargs.inference;
+    let mut rows = prepare_rows(request_messages(&inputs[0])?, &inference)?;
+    let call = start_call(&inference)?;
+    // Rows are sent in groups sharing a schema, each with its own response format
+    let send = |batch: &[Option<String>]| {
+        let mut responses = vec![None; batch.len()];
+        for group in std::iter::once(None).chain((0..schemas.len()).map(Some)) {
+            let rows: Vec<Option<String>> = batch
+                .iter()
+                .zip(&row_schemas)
+                .map(|(row, schema)| row.clone().filter(|_| *schema == group))
+                .collect();
+            if rows.iter().all(|row| row.is_none()) {
+                continue;
+            }
+            let mut group_kwargs = inference.clone();
+            if let Some(group) = group {
+                let format = response_format(&schemas[group].0, kwargs.strict);
+                group_kwargs.options.response_format = Some(format);
+            }
+            let sent = send_rows(rows, &group_kwargs, &call);
+            for (i, response) in sent.into_iter().enumerate() {
+                if response.is_some() {
+                    responses[i] = response;
+                }
+            }
+        }
+        responses
+    };
+
+    let mut results: Vec<Option<Structured>> = vec![None; rows.len()];
+    let mut pending: Vec<usize> = (0..rows.len()).filter(|&i| rows[i].is_some()).collect();
+    let mut responses = send(&rows);
+    for attempt in 0..=kwargs.max_validation_retries {
+        let mut retry = Vec::new();
+        for &i in &pending {
+            let validator = row_schemas[i].map(|schema| &schemas[schema].1);
+            let result = structure_reply(responses[i].as_deref(), validator, kwargs.repair);
+            if attempt < kwargs.max_validation_retries {
+                let correction = rows[i].as_deref().zip(responses[i].as_deref()).and_then(
+                    |(messages, response)| correction_messages(messages, response, &result),
+                );
+                if let Some(correction) = correction {
+                    rows[i] = Some(correction);
+                    retry.push(i);
+                }
+            }
+            results[i] = Some(result);
+        }
+        if retry.is_empty() {
+            break;
+        }
+        tracing::info!(
+            attempt = attempt + 1,
+            rows = retry.len(),
+            "asking again for replies that failed validation"
+        );
+        let mut batch = vec![None; rows.len()];
+        for &i in &retry {
+            batch[i] = rows[i].clone();
+        }
+        responses = send(&batch);
+        pending = retry;
+    }
+    structured_column(inputs[0].name(), &results)
+}
+
+// Copy of `kwargs` asking for replies matching `schema`, with the
+// validator of those replies
+fn structured_kwargs(
+    schema: &Value,
+    kwargs: &InferenceKwargs,
+) -> PolarsResult<(InferenceKwargs, Validator)> {
+    let (schema, validator) = compile_schema(schema, kwargs.options.provider, true)
+        .map_err(|e| polars_err!(ComputeError: "invalid response schema: {}", e))?;
+    let mut kwargs = kwargs.clone();
+    kwargs.options.response_format = Some(response_format(&schema, true));
+    Ok((kwargs, validator))
+}
+
+// Parsed replies of `responses`, null where a reply was missing or unusable
+fn parse_replies(responses: &[Option<String>], validator: &Validator) -> Vec<Option<Value>> {
+    responses
+        .iter()
+        .map(|response| {
+            let result = structure_reply(response.as_deref(), Some(validator), true);
+            match result.error {
+                None => serde_json::from_str(result.json.as_deref()?).ok(),
+                Some(_) => None,
+            }
+        })
+        .collect()
+}
+
+// Sends every row's JSON messages asking for replies matching `schema`, the
+// parsed replies are null where a row was null or its reply was unusable
+fn structured_rows(
+    rows: Vec<Option<String>>,
+    schema: &Value,
+    kwargs: &InferenceKwargs,
+) -> PolarsResult<Vec<Option<Value>>> {
+    let (kwargs, validator) = structured_kwargs(schema, kwargs)?;
+    let responses = infer_rows(rows, &kwargs)?;
+    Ok(parse_replies(&responses, &validator))
+}
+
+// Messages of each text row with the task instructions
+fn task_rows(input: &Series, instructions: &str) -> PolarsResult<Vec<Option<String>>> {
+    let texts = input.cast(&DataType::String)?;
+    Ok(texts
+        .str()?
+        .into_iter()
+        .map(|text| text.map(|text| task_messages(instructions, text)))
+        .collect())
+}
+
+#[derive(Deserialize)]
+pub struct ClassifyKwargs {
+    labels: Vec<String>,
+    // Also return the model's confidence in the label
+    #[serde(default)]
+    confidence: bool,
+    // Texts sent together in one request, one by one when unset
+    #[serde(default)]
+    pack: Option<usize>,
+    #[serde(flatten)]
+    inference: InferenceKwargs,
+}
+
+fn label_dtype(labels: &[String]) -> DataType {
+    let categories = Utf8ViewArray::from_slice_values(labels);
+    create_enum_data_type(categories)
+}
+
+fn classify_output(input_fields: &[Field], kwargs: ClassifyKwargs) -> PolarsResult<Field> {
+    let label = label_dtype(&kwargs.labels);
+    let dtype = if kwargs.confidence {
+        DataType::Struct(vec![
+            Field::new("label", label),
+            Field::new("confidence", DataType::Float64),
+        ])
+    } else {
+        label
+    };
+    Ok(Field::new(input_fields[0].name(), dtype))
+}
+
+// Classifies the non-empty texts `size` at a time, each pack in a single
+// request answered with one numbered result per text. Texts of packs whose
+// reply does not hold exactly one result for each of them are sent again one
+// by one, like empty texts. The empty prompt policy applies once, to the
+// texts as given.
+fn packed_rows(
+    input: &Series,
+    kwargs: &ClassifyKwargs,
+    size: usize,
+) -> PolarsResult<Vec<Option<Value>>> {
+    let inference = &kwargs.inference;
+    let texts = input.cast(&DataType::String)?;
+    let texts: Vec<Option<&str>> = texts.str()?.into_iter().collect();
+    let schema = classification_schema(&kwargs.labels, kwargs.confidence);
+    let (packed_kwargs, packed_validator) = structured_kwargs(&packed_schema(&schema), inference)?;
+    let (single_kwargs, single_validator) = structured_kwargs(&schema, inference)?;
+
+    // Every text as it would be sent on its own, the fallback for failed packs
+    let instructions = classification_instructions(&kwargs.labels);
+    let singles = texts
+        .iter()
+        .map(|text| text.map(|t| task_messages(&instructions, t)))
+        .collect();
+    let singles = prepare_rows(singles, inference)?;
+
+    let packable: Vec<(usize, &str)> = texts
+        .iter()
+        .enumerate()
+        .filter_map(|(i, text)| {
+            text.filter(|t| !t.trim().is_empty() && singles[i].is_some())
+                .map(|t| (i, t))
+        })
+        .collect();
+    let packs: Vec<&[(usize, &str)]> = packable.chunks(size).collect();
+    let instructions = packed_classification_instructions(&kwargs.labels);
+    let rows = packs
+        .iter()
+        .map(|pack| {
+            let texts: Vec<&str> = pack.iter().map(|(_, text)| *text).collect();
+            Some(packed_messages(&instructions, &texts))
+        })
+        .collect();
+    let call = start_call(inference)?;
+    let responses = send_rows(rows, &packed_kwargs, &call);
+
+    let mut replies: Vec<Option<Value>> = vec![None; texts.len()];
+    let mut unpacked = vec![false; texts.len()];
+    for (pack, reply) in packs
+        .iter()
+        .zip(parse_replies(&responses, &packed_validator))
+    {
+        let Some(results) = reply.and_then(|r| unpack_results(&r, pack.len())) else {
+            continue;
+        };
+        for (&(i, _), result) in pack.iter().zip(results) {
+            replies[i] = Some(result);
+            unpacked[i] = true;
+        }
+    }
+
+    let rows: Vec<Option<String>> = singles
+        .into_iter()
+        .zip(&unpacked)
+        .map(|(row, done)| row.filter(|_| !done))
+        .collect();
+    if rows.iter().any(Option::is_some) {
+        let responses = send_rows(rows, &single_kwargs, &call);
+        for (i, reply) in parse_replies(&responses, &single_validator)
+            .into_iter()
+            .enumerate()
+        {
+            if reply.is_some() {
+                replies[i] = reply;
+            }
+        }
+    }
+    Ok(replies)
+}
+
+// Picks one of `labels` for every text, as an Enum of the labels. Rows whose
+// reply is not one of the labels are null.
+#[polars_expr(output_type_func_with_kwargs=classify_output)]
+fn classify(inputs: &[Series], kwargs: ClassifyKwargs) -> PolarsResult<Series> {
+    polars_ensure!(!kwargs.labels.is_empty(), ComputeError: "classify needs at least one label");
+    let replies = match kwargs.pack {
+        Some(size) if size > 1 => packed_rows(&inputs[0], &kwargs, size)?,
+        _ => {
+            let rows = task_rows(&inputs[0], &classification_instructions(&kwargs.labels))?;
+            let schema = classification_schema(&kwargs.labels, kwargs.confidence);
+            structured_rows(rows, &schema, &kwargs.inference)?
+        }
+    };
+
+    let labels = StringChunked::from_iter_options(
+        "label",
+        replies
+            .iter()
+            .map(|r| r.as_ref().and_then(|r| r["label"].as_str())),
+    )
+    .into_series()
+    .cast(&label_dtype(&kwargs.labels))?;
+    if !kwargs.confidence {
+        return Ok(labels.with_name(inputs[0].name()));
+    }
+    let confidence = Float64Chunked::from_iter_options(
+        "confidence",
+        replies
+            .iter()
+            .map(|r| r.as_ref().and_then(|r| r["confidence"].as_f64())),
+    );
+    Ok(StructChunked::new(inputs[0].name(), &[labels, confidence.into_series()])?.into_series())
+}
+
+#[derive(Deserialize)]
+pub struct TagKwargs {
+    taxonomy: Taxonomy,
+    // Allow any number of values per dimension instead of exactly one
+    #[serde(default)]
+    multi_label: bool,
+    // Score every tag with the model's confidence
+    #[serde(default)]
+    confidence: bool,
+    #[serde(flatten)]
+    inference: InferenceKwargs,
+}
+
+fn tag_output(input_fields: &[Field], kwargs: TagKwargs) -> PolarsResult<Field> {
+    let dtype = tags_dtype(&kwargs.taxonomy, kwargs.multi_label, kwargs.confidence);
+    Ok(Field::new(input_fields[0].name(), dtype))
+}
+
+// Tags every text along each dimension of the taxonomy, rows whose reply
+// does not fit the taxonomy are null
+#[polars_expr(output_type_func_with_kwargs=tag_output)]
+fn tag_taxonomy(inputs: &[Series], kwargs: TagKwargs) -> PolarsResult<Series> {
+    polars_ensure!(
+        !kwargs.taxonomy.is_empty() && kwargs.taxonomy.values().all(|v| !v.is_empty()),
+        ComputeError: "tag_taxonomy needs at least one dimension, each with at least one value"
+    );
+    let instructions = taxonomy_instructions(&kwargs.taxonomy, kwargs.multi_label);
+    let rows = task_rows(&inputs[0], &instructions)?;
+    let schema = taxonomy_schema(&kwargs.taxonomy, kwargs.multi_label, kwargs.confidence);
+    let replies = structured_rows(rows, &schema, &kwargs.inference)?;
+    tags_column(
+        inputs[0].name(),
+        &kwargs.taxonomy,
+        &replies,
+        kwargs.multi_label,
+        kwargs.confidence,
+    )
+}
+
+#[derive(Deserialize)]
+pub struct EntityKwargs {
+    entity_types: Vec<String>,
+    #[serde(flatten)]
+    inference: InferenceKwargs,
+}
+
+fn entities_output(input_fields: &[Field]) -> PolarsResult<Field> {
+    Ok(Field::new(input_fields[0].name(), entities_dtype()))
+}
+
+// Extracts the entities of `entity_types` from every text. Spans are the
+// character offsets of each entity in the text, null where the model did
+// not quote it verbatim.
+#[polars_expr(output_type_func=entities_output)]
+fn extract_entities(inputs: &[Series], kwargs: EntityKwargs) -> PolarsResult<Series> {
+    polars_ensure!(
+        !kwargs.entity_types.is_empty(),
+        ComputeError: "extract_entities needs at least one entity type"
+    );
+    let rows = task_rows(&inputs[0], &entity_instructions(&kwargs.entity_types))?;
+    let schema = entity_schema(&kwargs.entity_types);
+    let replies = structured_rows(rows, &schema, &kwargs.inference)?;
+    let texts = inputs[0].cast(&DataType::String)?;
+    entities_column(inputs[0].name(), texts.str()?, &replies)
+}
+
+fn cited_output(input_fields: &[Field]) -> PolarsResult<Field> {
+    Ok(Field::new(input_fields[0].name(), cited_dtype()))
+}
+
+// Answers each question from the documents of the second input, a list of
+// texts, returning the answer with the positions of the documents it cites
+#[polars_expr(output_type_func=cited_output)]
+fn answer_with_citations(inputs: &[Series], kwargs: InferenceKwargs) -> PolarsResult<Series> {
+    let questions = inputs[0].cast(&DataType::String)?;
+    let documents = inputs[1].cast(&DataType::List(Box::new(DataType::String)))?;
+    let mut counts = Vec::with_capacity(questions.len());
+    let mut rows = Vec::with_capacity(questions.len());
+    for (question, documents) in questions.str()?.into_iter().zip(documents.list()?) {
+        let documents = documents.map(|d| d.str().cloned()).transpose()?;
+        // Null documents stay in place as empty ones so positions match the list
+        let documents: Vec<&str> = documents
+            .iter()
+            .flatten()
+            .map(|d| d.unwrap_or_default())
+            .collect();
+        counts.push(documents.len());
+        rows.push(question.map(|question| citation_messages(question, &documents)));
+    }
+    let replies = structured_rows(rows, &citation_schema(), &kwargs)?;
+    cited_column(inputs[0].name(), &replies, &counts)
+}
+
+fn default_pii_kinds() -> Vec<PiiKind> {
+    PiiKind::ALL.to_vec()
+}
+
+#[derive(Deserialize)]
+pub struct PiiKwargs {
+    #[serde(default = "default_pii_kinds")]
+    kinds: Vec<PiiKind>,
+    // Also return what was redacted, as entities with character spans
+    #[serde(default)]
+    return_spans: bool,
+}
+
+fn pii_output(input_fields: &[Field], kwargs: PiiKwargs) -> PolarsResult<Field> {
+    let dtype = if kwargs.return_spans {
+        DataType::Struct(vec![
+            Field::new("text", DataType::String),
+            Field::new("pii", entities_dtype()),
+        ])
+    } else {
+        DataType::String
+    };
+    Ok(Field::new(input_fields[0].name(), dtype))
+}
+
+// Masks emails, phone numbers, SSNs and card numbers with rules run locally,
+// so the text can be sent to a provider without them
+#[polars_expr(output_type_func_with_kwargs=pii_output)]
+fn redact_pii(inputs: &[Series], kwargs: PiiKwargs) -> PolarsResult<Series> {
+    let texts = inputs[0].cast(&DataType::String)?;
+    let texts = texts.str()?;
+    let spans: Vec<Option<Vec<PiiSpan>>> = texts
+        .into_iter()
+        .map(|text| text.map(|text| detect_pii(text, &kwargs.kinds)))
+        .collect();
+    let redacted: StringChunked = texts
+        .into_iter()
+        .zip(&spans)
+        .map(|(text, spans)| Some(redact(text?, spans.as_ref()?)))
+        .collect();
+    let redacted = redacted.with_name(inputs[0].name()).into_series();
+    if !kwargs.return_spans {
+        return Ok(redacted);
+    }
+
+    let pii: Vec<Option<Vec<Entity>>> = texts
+        .into_iter()
+        .zip(&spans)
+        .map(|(text, spans)| {
+            let text = text?;
+            let entities = spans
+                .as_ref()?
+                .iter()
+                .map(|span| {
+                    let start = text[..span.start].chars().count();
+                    let end = start + text[span.start..span.end].chars().count();
+                    Entity {
+                        kind: span.kind.label().to_string(),
+                        text: text[span.start..span.end].to_string(),
+                        span: Some((start as u32, end as u32)),
+                    }
+                })
+                .collect();
+            Some(entities)
+        })
+        .collect();
+    let fields = [redacted.with_name("text"), entity_list_column("pii", &pii)?];
+    Ok(StructChunked::new(inputs[0].name(), &fields)?.into_series())
+}
+
+#[derive(Deserialize)]
+pub struct InjectionKwargs {
+    // Also ask the model to score each text, keeping the higher score
+    #[serde(default)]
+    use_model: bool,
+    #[serde(flatten)]
+    inference: InferenceKwargs,
+}
+
+// Scores how likely each text is to be a prompt injection, from 0 to 1, so
+// untrusted columns can be flagged before they are put in prompts
+#[polars_expr(output_type=Float64)]
+fn detect_injection(inputs: &[Series], kwargs: InjectionKwargs) -> PolarsResult<Series> {
+    let texts = inputs[0].cast(&DataType::String)?;
+    let mut scores: Vec<Option<f64>> = texts
+        .str()?
+        .into_iter()
+        .map(|text| text.map(injection_score))
+        .collect();
+    if kwargs.use_model {
+        let rows = task_rows(&inputs[0], injection_instructions())?;
+        let replies = structured_rows(rows, &injection_schema(), &kwargs.inference)?;
+        for (score, reply) in scores.iter_mut().zip(replies) {
+            let model = reply.and_then(|reply| reply["score"].as_f64());
+            if let (Some(score), Some(model)) = (score.as_mut(), model) {
+                *score = score.max(model);
+            }
+        }
+    }
+    Ok(Float64Chunked::from_iter_options(inputs[0].name(), scores.into_iter()).into_series())
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EmbeddingDtype {
+    #[default]
+    Float32,
+    Float64,
+}
+
+#[derive(Deserialize)]
+pub struct EmbeddingKwargs {
+    #[serde(default)]
+    dtype: EmbeddingDtype,
+    // Scale every vector to unit length
+    #[serde(default)]
+    normalize: bool,
+    #[serde(flatten)]
+    params: EmbeddingParams,
+    #[serde(flatten)]
+    options: RequestOptions,
+}
+
+fn embedding_output(input_fields: &[Field], kwargs: EmbeddingKwargs) -> PolarsResult<Field> {
+    let inner = match kwargs.dtype {
+        EmbeddingDtype::Float32 => DataType::Float32,
+        EmbeddingDtype::Float64 => DataType::Float64,
+    };
+    Ok(Field::new(
+        input_fields[0].name(),
+        DataType::List(Box::new(inner)),
+    ))
+}
+
+#[polars_expr(output_type_func_with_kwargs=embedding_output)]
+fn embedding(inputs: &[Series], kwargs: EmbeddingKwargs) -> PolarsResult<Series> {
     let ca: &StringChunked = inputs[0].str()?;
-    let messages: Vec<String> = ca
+
+    // Null and empty rows are not sent and stay null in the output
+    let (rows, texts): (Vec<usize>, Vec<String>) = ca
         .into_iter()
-        .filter_map(|opt| opt.map(|s| s.to_owned()))
+        .enumerate()
+        .filter_map(|(i, opt)| opt.filter(|s| !s.is_empty()).map(|s| (i, s.to_string())))
+        .unzip();
+
+    let mut vectors: Vec<Option<Vec<f32>>> = vec![None; ca.len()];
+    if !texts.is_empty() {
+        let embeddings = block_on(fetch_embeddings(&texts, &kwargs.options, &kwargs.params));
+        for (&row, mut embedding) in rows.iter().zip(embeddings) {
+            if kwargs.normalize {
+                if let Some(vector) = embedding.as_mut() {
+                    l2_normalize(vector);
+                }
+            }
+            vectors[row] = embedding;
+        }
+    }
+
+    let name = ca.name();
+    let dimensions = vectors
+        .iter()
+        .flatten()
+        .map(|v| v.len())
+        .next()
+        .unwrap_or(0);
+    let out = match kwargs.dtype {
+        EmbeddingDtype::Float32 => {
+            let mut builder = ListPrimitiveChunkedBuilder::<Float32Type>::new(
+                name,
+                vectors.len(),
+                vectors.len() * dimensions,
+                DataType::Float32,
+            );
+            for vector in &vectors {
+                match vector {
+                    Some(v) => builder.append_slice(v),
+                    None => builder.append_null(),
+                }
+            }
+            builder.finish()
+        }
+        EmbeddingDtype::Float64 => {
+            let mut builder = ListPrimitiveChunkedBuilder::<Float64Type>::new(
+                name,
+                vectors.len(),
+                vectors.len() * dimensions,
+                DataType::Float64,
+            );
+            for vector in &vectors {
+                match vector {
+                    Some(v) => {
+                        let widened: Vec<f64> = v.iter().map(|x| *x as f64).collect();
+                        builder.append_slice(&widened)
+                    }
+                    None => builder.append_null(),
+                }
+            }
+            builder.finish()
+        }
+    };
+    Ok(out.into_series())
+}
+
+#[derive(Deserialize)]
+pub struct RerankKwargs {
+    #[serde(flatten)]
+    params: RerankParams,
+    #[serde(flatten)]
+    options: RequestOptions,
+}
+
+fn reranked_dtype() -> DataType {
+    DataType::List(Box::new(DataType::Struct(vec![
+        Field::new("document", DataType::String),
+        Field::new("score", DataType::Float64),
+    ])))
+}
+
+fn rerank_output(input_fields: &[Field]) -> PolarsResult<Field> {
+    Ok(Field::new(input_fields[1].name(), reranked_dtype()))
+}
+
+#[polars_expr(output_type_func=rerank_output)]
+fn rerank(inputs: &[Series], kwargs: RerankKwargs) -> PolarsResult<Series> {
+    let queries: &StringChunked = inputs[0].str()?;
+    let documents: &ListChunked = inputs[1].list()?;
+    polars_ensure!(
+        documents.inner_dtype() == DataType::String,
+        ComputeError: "rerank expects a list of strings, got {}", inputs[1].dtype()
+    );
+
+    // Rows without a query or documents are not sent and stay null
+    let rows: Vec<Option<(String, Vec<String>)>> = queries
+        .into_iter()
+        .zip(documents.into_iter())
+        .map(|(query, docs)| {
+            let query = query?.to_string();
+            let docs: Vec<String> = docs?
+                .str()
+                .ok()?
+                .into_iter()
+                .map(|d| d.unwrap_or_default().to_string())
+                .collect();
+            (!docs.is_empty()).then_some((query, docs))
+        })
         .collect();
 
-    let results = RT.block_on(fetch_data(&messages));
+    let config = config();
+    let concurrency = config.max_concurrency.max(1);
+    let ranked: Vec<Option<Vec<(String, f64)>>> = block_on(async {
+        // Taken once on the runtime, which replaces the client when rebuilt
+        let client = http_client();
+        stream::iter(rows)
+            .map(|row| async {
+                let (query, docs) = row?;
+                let results = fetch_rerank(
+                    &client,
+                    &config,
+                    &query,
+                    &docs,
+                    &kwargs.options,
+                    &kwargs.params,
+                )
+                .await
+                .inspect_err(|e| tracing::warn!(error = %e, "rerank request failed"))
+                .ok()?;
+                Some(
+                    results
+                        .into_iter()
+                        .map(|(index, score)| (docs[index].clone(), score))
+                        .collect(),
+                )
+            })
+            .buffered(concurrency)
+            .collect()
+            .await
+    });
+
+    let name = documents.name();
+    let mut builder = AnonymousOwnedListBuilder::new(name, ranked.len(), Some(reranked_dtype()));
+    for row in ranked {
+        match row {
+            Some(results) => {
+                let (docs, scores): (Vec<String>, Vec<f64>) = results.into_iter().unzip();
+                let item = StructChunked::new(
+                    "",
+                    &[Series::new("document", docs), Series::new("score", scores)],
+                )?;
+                builder.append_series(&item.into_series())?;
+            }
+            None => builder.append_null(),
+        }
+    }
+    Ok(builder.finish().into_series())
+}
 
-    let string_refs: Vec<Option<&str>> = results.iter().map(|opt| opt.as_deref()).collect();
-    let out = StringChunked::from_iter_options("output", string_refs.into_iter());
+#[derive(Deserialize)]
+pub struct TokenKwargs {
+    // Defaults to the configured chat model
+    #[serde(default)]
+    model: Option<String>,
+}
 
+#[polars_expr(output_type=UInt32)]
+fn count_tokens(inputs: &[Series], kwargs: TokenKwargs) -> PolarsResult<Series> {
+    let ca: &StringChunked = inputs[0].str()?;
+    let model = kwargs.model.unwrap_or_else(|| config().model);
+    let encoder = encoder(&model);
+    let out: UInt32Chunked = ca.apply_generic(|opt| opt.map(|text| count(&encoder, text) as u32));
     Ok(out.into_series())
 }
 
+fn default_chunk_tokens() -> usize {
+    512
+}
+
+fn default_true() -> bool {
+    true
+}
+
 #[derive(Deserialize)]
-pub struct MessageKwargs {
+pub struct ChunkKwargs {
+    #[serde(default = "default_chunk_tokens")]
+    max_tokens: usize,
+    // Tokens shared by consecutive chunks
+    #[serde(default)]
+    overlap: usize,
+    // End chunks at sentence boundaries where possible
+    #[serde(default = "default_true")]
+    sentences: bool,
+    #[serde(default)]
+    model: Option<String>,
+}
+
+fn chunk_output(input_fields: &[Field]) -> PolarsResult<Field> {
+    Ok(Field::new(
+        input_fields[0].name(),
+        DataType::List(Box::new(DataType::String)),
+    ))
+}
+
+#[polars_expr(output_type_func=chunk_output)]
+fn chunk_text(inputs: &[Series], kwargs: ChunkKwargs) -> PolarsResult<Series> {
+    let ca: &StringChunked = inputs[0].str()?;
+    polars_ensure!(
+        kwargs.overlap < kwargs.max_tokens,
+        ComputeError: "overlap ({}) must be smaller than max_tokens ({})", kwargs.overlap, kwargs.max_tokens
+    );
+    let model = kwargs.model.unwrap_or_else(|| config().model);
+    let encoder = encoder(&model);
+
+    let mut builder = ListStringChunkedBuilder::new(ca.name(), ca.len(), ca.len());
+    for opt in ca.into_iter() {
+        match opt {
+            Some(text) => {
+                let chunks = chunk(
+                    &encoder,
+                    text,
+                    kwargs.max_tokens,
+                    kwargs.overlap,
+                    kwargs.sentences,
+                );
+                builder.append_values_iter(chunks.iter().map(|c| c.as_str()));
+            }
+            None => builder.append_null(),
+        }
+    }
+    Ok(builder.finish().into_series())
+}
+
+#[derive(Deserialize)]
+pub struct TruncateKwargs {
+    max_tokens: usize,
+    #[serde(default)]
+    strategy: Truncation,
+    #[serde(default)]
+    model: Option<String>,
+}
+
+#[polars_expr(output_type=String)]
+fn truncate_tokens(inputs: &[Series], kwargs: TruncateKwargs) -> PolarsResult<Series> {
+    let ca: &StringChunked = inputs[0].str()?;
+    let model = kwargs.model.unwrap_or_else(|| config().model);
+    let encoder = encoder(&model);
+    let out = ca.apply_to_buffer(|value: &str, output: &mut String| {
+        output.push_str(&truncate(
+            &encoder,
+            value,
+            kwargs.max_tokens,
+            kwargs.strategy,
+        ));
+    });
+    Ok(out.into_series())
+}
+
+#[derive(Deserialize)]
+pub struct TemplateKwargs {
+    template: String,
+    #[serde(default)]
+    escape: Escape,
+    #[serde(default)]
+    missing: MissingValues,
+}
+
+// Every input is bound to the template variable of its name
+#[polars_expr(output_type=String)]
+fn prompt_template(inputs: &[Series], kwargs: TemplateKwargs) -> PolarsResult<Series> {
+    let columns = inputs
+        .iter()
+        .map(|s| s.cast(&DataType::String))
+        .collect::<PolarsResult<Vec<_>>>()?;
+    let columns = columns
+        .iter()
+        .map(|s| s.str())
+        .collect::<PolarsResult<Vec<_>>>()?;
+    let out = render(&kwargs.template, &columns, kwargs.escape, kwargs.missing)?;
+    Ok(out.into_series())
+}
+
+#[derive(Deserialize)]
+pub struct PairwiseKwargs {
+    template: String,
+    #[serde(default = "default_user")]
     message_type: String,
+    #[serde(default)]
+    missing: MissingValues,
+}
+
+// A message per row comparing two or more columns, each bound to the
+// template variable of its name. Values are inserted as they are, the
+// message is built from the rendered text so nothing needs escaping
+#[polars_expr(output_type_func=message_output)]
+fn pairwise_prompt(inputs: &[Series], kwargs: PairwiseKwargs) -> PolarsResult<Series> {
+    polars_ensure!(
+        inputs.len() >= 2,
+        ComputeError: "pairwise_prompt compares at least two columns, got {}", inputs.len()
+    );
+    let columns = inputs
+        .iter()
+        .map(|s| s.cast(&DataType::String))
+        .collect::<PolarsResult<Vec<_>>>()?;
+    let columns = columns
+        .iter()
+        .map(|s| s.str())
+        .collect::<PolarsResult<Vec<_>>>()?;
+    let prompts = render(&kwargs.template, &columns, Escape::None, kwargs.missing)?;
+    message_column(inputs[0].name(), &kwargs.message_type, None, &prompts)
+}
+
+#[derive(Deserialize)]
+pub struct FewShotKwargs {
+    examples: Vec<Example>,
+    // Only use the k examples most similar to each row
+    #[serde(default)]
+    k: Option<usize>,
+    #[serde(flatten)]
+    params: EmbeddingParams,
+    #[serde(flatten)]
+    options: RequestOptions,
 }
 
 #[polars_expr(output_type=String)]
+fn few_shot(inputs: &[Series], kwargs: FewShotKwargs) -> PolarsResult<Series> {
+    let messages = request_messages(&inputs[0])?;
+    let rows: Vec<Option<&str>> = messages.iter().map(|m| m.as_deref()).collect();
+    let out = block_on(with_examples(
+        &rows,
+        &kwargs.examples,
+        kwargs.k,
+        &kwargs.options,
+        &kwargs.params,
+    ))
+    .map_err(|e| polars_err!(ComputeError: "{}", e))?;
+    Ok(StringChunked::from_iter_options(inputs[0].name(), out.into_iter()).into_series())
+}
+
+#[derive(Deserialize)]
+pub struct MessageKwargs {
+    message_type: String,
+    // Participant name, sent as the message's `name`
+    #[serde(default)]
+    name: Option<String>,
+}
+
+fn message_output(input_fields: &[Field]) -> PolarsResult<Field> {
+    Ok(Field::new(input_fields[0].name(), message_dtype()))
+}
+
+#[polars_expr(output_type_func=message_output)]
 fn string_to_message(inputs: &[Series], kwargs: MessageKwargs) -> PolarsResult<Series> {
     let ca: &StringChunked = inputs[0].str()?;
-    let message_type = kwargs.message_type;
+    message_column(ca.name(), &kwargs.message_type, kwargs.name.as_deref(), ca)
+}
 
-    let out: StringChunked = ca.apply_to_buffer(|value: &str, output: &mut String| {
-        write!(
-            output,
-            "{{\"role\": \"{}\", \"content\": \"{}\"}}",
-            message_type, value
-        )
-        .unwrap()
-    });
-    Ok(out.into_series())
+fn default_user() -> String {
+    "user".to_string()
+}
+
+#[derive(Deserialize)]
+pub struct AttachmentKwargs {
+    #[serde(default = "default_user")]
+    role: String,
+    // Type of Binary attachments, or of String images holding bare base64
+    #[serde(default)]
+    mime_type: Option<String>,
+}
+
+// Messages with the text of `texts` followed by one attachment each, null
+// where both are null
+fn attachment_messages(
+    texts: &Series,
+    attachments: Vec<Option<String>>,
+    role: &str,
+    documents: bool,
+) -> PolarsResult<Series> {
+    let messages: Vec<Option<Message>> = texts
+        .str()?
+        .into_iter()
+        .zip(attachments)
+        .map(|(text, attachment)| {
+            (text.is_some() || attachment.is_some()).then(|| {
+                let attachment: Vec<String> = attachment.into_iter().collect();
+                let (images, documents) = match documents {
+                    true => (Vec::new(), attachment),
+                    false => (attachment, Vec::new()),
+                };
+                Message::with_attachments(role, text.unwrap_or_default(), images, documents)
+            })
+        })
+        .collect();
+    let messages: Vec<Option<&Message>> = messages.iter().map(|m| m.as_ref()).collect();
+    message_structs(texts.name(), &messages)
+}
+
+// A message with the text of the first input followed by the image of the
+// second, given as a URL, a base64 string or the bytes of the file
+#[polars_expr(output_type_func=message_output)]
+fn image_message(inputs: &[Series], kwargs: AttachmentKwargs) -> PolarsResult<Series> {
+    let mime_type = kwargs.mime_type.as_deref();
+    let images: Vec<Option<String>> = match inputs[1].dtype() {
+        DataType::Binary => inputs[1]
+            .binary()?
+            .into_iter()
+            .map(|bytes| Some(data_url(bytes?, mime_type)))
+            .collect(),
+        DataType::String => inputs[1]
+            .str()?
+            .into_iter()
+            .map(|image| {
+                let image = image?;
+                let is_url = ["http://", "https://", "data:"]
+                    .iter()
+                    .any(|scheme| image.starts_with(scheme));
+                Some(match mime_type {
+                    Some(mime_type) if !is_url => format!("data:{};base64,{}", mime_type, image),
+                    _ => image.to_string(),
+                })
+            })
+            .collect(),
+        dtype => polars_bail!(ComputeError: "images must be String or Binary, got {}", dtype),
+    };
+    attachment_messages(&inputs[0], images, &kwargs.role, false)
+}
+
+// A message with the text of the first input followed by the PDF of the
+// second, given as the bytes of the file, a file path or a data URL
+#[polars_expr(output_type_func=message_output)]
+fn document_message(inputs: &[Series], kwargs: AttachmentKwargs) -> PolarsResult<Series> {
+    let mime_type = Some(kwargs.mime_type.as_deref().unwrap_or("application/pdf"));
+    let documents: Vec<Option<String>> = match inputs[1].dtype() {
+        DataType::Binary => inputs[1]
+            .binary()?
+            .into_iter()
+            .map(|bytes| bytes.map(|bytes| data_url(bytes, mime_type)))
+            .collect(),
+        DataType::String => inputs[1]
+            .str()?
+            .into_iter()
+            .map(|document| match document {
+                Some(url) if url.starts_with("data:") => Ok(Some(url.to_string())),
+                Some(path) => {
+                    let bytes = std::fs::read(path).map_err(
+                        |e| polars_err!(ComputeError: "failed to read document {}: {}", path, e),
+                    )?;
+                    Ok(Some(data_url(&bytes, mime_type)))
+                }
+                None => Ok(None),
+            })
+            .collect::<PolarsResult<_>>()?,
+        dtype => polars_bail!(ComputeError: "documents must be String or Binary, got {}", dtype),
+    };
+    attachment_messages(&inputs[0], documents, &kwargs.role, true)
+}
+
+fn conversation_output(input_fields: &[Field]) -> PolarsResult<Field> {
+    Ok(Field::new(input_fields[0].name(), conversation_dtype()))
+}
+
+// Concatenates messages and conversations, in argument order, into one
+// conversation per row. A row is null if any of its parts is, and parts that
+// are not messages, or have unknown roles, are errors. Literal parts, such
+// as a `pl.lit` system prompt, are shared by every row.
+#[polars_expr(output_type_func=conversation_output)]
+fn combine_messages(inputs: &[Series]) -> PolarsResult<Series> {
+    let len = inputs.iter().map(|input| input.len()).max().unwrap_or(0);
+    let mut rows: Vec<Option<Vec<Message>>> = vec![Some(Vec::new()); len];
+    for input in inputs {
+        let mut parts = checked_conversations(input)?;
+        if parts.len() == 1 && len != 1 {
+            parts = vec![parts[0].clone(); len];
+        }
+        polars_ensure!(
+            parts.len() == len,
+            ComputeError: "combine_messages got {} rows in {} and {} in another input",
+            parts.len(), input.name(), len
+        );
+        for (row, part) in rows.iter_mut().zip(parts) {
+            match (row.as_mut(), part) {
+                (Some(row), Some(part)) => row.extend(part),
+                _ => *row = None,
+            }
+        }
+    }
+    conversation_column(inputs[0].name(), &rows)
 }
-// To be used later for the OpenAI API parsing
-// #[derive(Deserialize)]
-// pub struct BodyKwargs {
-//     Model: String,
-// }
-
-// #[derive(Serialize, Deserialize, Debug)]
-// struct ChatCompletion {
-//     id: String,
-//     object: String,
-//     created: i64,
-//     model: String,
-//     choices: Vec<Choice>,
-//     usage: Usage,
-//     system_fingerprint: String,
-// }
-
-// #[derive(Serialize, Deserialize, Debug)]
-// struct Choice {
-//     index: i32,
-//     message: Message,
-//     logprobs: Option<serde_json::Value>, // Use serde_json::Value if the structure is not defined or varies
-//     finish_reason: String,
-// }
-
-// #[derive(Serialize, Deserialize, Debug)]
-// struct Message {
-//     role: String,
-//     content: String,
-// }
-
-// #[derive(Serialize, Deserialize, Debug)]
-// struct Usage {
-//     prompt_tokens: i32,
-//     completion_tokens: i32,
-//     total_tokens: i32,
-// }
@@ -0,0 +1,96 @@
+use crate::embeddings::{fetch_embeddings, EmbeddingParams};
+use crate::search::top_k;
+use crate::utils::RequestOptions;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// An input and the answer expected for it.
+#[derive(Clone, Deserialize)]
+pub struct Example {
+    pub input: String,
+    pub output: String,
+}
+
+/// Messages of a row, which holds either one message or an array of them.
+pub fn row_messages(row: &str) -> Option<Vec<Value>> {
+    match serde_json::from_str(row).ok()? {
+        Value::Array(messages) => Some(messages),
+        message @ Value::Object(_) => Some(vec![message]),
+        _ => None,
+    }
+}
+
+// Text the examples of a row are chosen by, its last user message
+fn query_text(messages: &[Value]) -> String {
+    messages
+        .iter()
+        .rev()
+        .find(|m| m["role"] == "user")
+        .and_then(|m| m["content"].as_str())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Prepends `examples` to every row as user/assistant message pairs.
+///
+/// With `k` only the `k` examples whose input is the most similar to each
+/// row's last user message are used. Rows that are not messages are None.
+pub async fn with_examples(
+    rows: &[Option<&str>],
+    examples: &[Example],
+    k: Option<usize>,
+    options: &RequestOptions,
+    params: &EmbeddingParams,
+) -> Result<Vec<Option<String>>, String> {
+    let rows: Vec<Option<Vec<Value>>> = rows.iter().map(|r| r.and_then(row_messages)).collect();
+
+    let mut selected: Vec<Vec<usize>> = vec![(0..examples.len()).collect(); rows.len()];
+    if let Some(k) = k.filter(|&k| k < examples.len()) {
+        // Rows without a user message to compare get the first k examples
+        let (query_rows, queries): (Vec<usize>, Vec<String>) = rows
+            .iter()
+            .enumerate()
+            .filter_map(|(i, r)| Some((i, query_text(r.as_ref()?))))
+            .filter(|(_, q)| !q.is_empty())
+            .unzip();
+        let mut texts: Vec<String> = examples.iter().map(|e| e.input.clone()).collect();
+        texts.extend(queries);
+        let mut vectors = fetch_embeddings(&texts, options, params).await;
+        let query_vectors = vectors.split_off(examples.len());
+        if vectors.iter().any(|v| v.is_none()) {
+            return Err("failed to embed the examples".to_string());
+        }
+        selected.iter_mut().for_each(|s| s.truncate(k));
+        for (row, query) in query_rows.into_iter().zip(query_vectors) {
+            if let Some(query) = query {
+                // Most similar last, closest to the question
+                selected[row] = top_k(&query, &vectors, k)
+                    .into_iter()
+                    .rev()
+                    .map(|(i, _)| i)
+                    .collect();
+            }
+        }
+    }
+
+    Ok(rows
+        .into_iter()
+        .zip(selected)
+        .map(|(messages, chosen)| {
+            let mut messages = messages?;
+            let shots = chosen.into_iter().flat_map(|i| {
+                [
+                    json!({"role": "user", "content": examples[i].input}),
+                    json!({"role": "assistant", "content": examples[i].output}),
+                ]
+            });
+            // System messages stay first
+            let system = messages
+                .iter()
+                .take_while(|m| m["role"] == "system")
+                .count();
+            messages.splice(system..system, shots);
+            Some(Value::Array(messages).to_string())
+        })
+        .collect())
+}
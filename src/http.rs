@@ -0,0 +1,108 @@
+use once_cell::sync::Lazy;
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::prelude::*;
+use reqwest::Client;
+use std::sync::RwLock;
+use std::time::Duration;
+
+// Shared by every request so connections are pooled across rows and calls,
+// with the settings it was built with
+static HTTP_CLIENT: Lazy<RwLock<(HttpSettings, Client)>> = Lazy::new(|| {
+    let settings = HttpSettings::default();
+    let client = settings.build().expect("Failed to create HTTP client");
+    RwLock::new((settings, client))
+});
+
+/// Connection settings of the shared HTTP client.
+#[derive(Clone)]
+pub struct HttpSettings {
+    pub pool_max_idle_per_host: usize,
+    pub pool_idle_timeout: Duration,
+    pub tcp_keepalive: Duration,
+    pub tcp_nodelay: bool,
+    pub http2_prior_knowledge: bool,
+    pub connect_timeout: Duration,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        HttpSettings {
+            pool_max_idle_per_host: 64,
+            pool_idle_timeout: Duration::from_secs(90),
+            tcp_keepalive: Duration::from_secs(60),
+            tcp_nodelay: true,
+            http2_prior_knowledge: false,
+            connect_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl HttpSettings {
+    pub fn build(&self) -> reqwest::Result<Client> {
+        let mut builder = Client::builder()
+            .pool_max_idle_per_host(self.pool_max_idle_per_host)
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .tcp_keepalive(self.tcp_keepalive)
+            .tcp_nodelay(self.tcp_nodelay)
+            .connect_timeout(self.connect_timeout)
+            .http2_adaptive_window(true);
+        if self.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        builder.build()
+    }
+}
+
+/// Returns a handle to the shared HTTP client, cloning it only bumps a refcount.
+pub fn http_client() -> Client {
+    HTTP_CLIENT.read().unwrap().1.clone()
+}
+
+/// Replaces the shared HTTP client with a new one of the same settings.
+///
+/// Pooled connections are driven by the runtime they were opened on, so
+/// they cannot outlive it; a new runtime needs a client of its own.
+pub fn rebuild_client() {
+    let mut shared = HTTP_CLIENT.write().unwrap();
+    shared.1 = shared.0.build().expect("Failed to create HTTP client");
+}
+
+/// Replaces the shared HTTP client, requests already in flight keep the old one.
+#[pyfunction]
+#[pyo3(signature = (
+    pool_max_idle_per_host=None,
+    pool_idle_timeout_secs=None,
+    tcp_keepalive_secs=None,
+    tcp_nodelay=None,
+    http2_prior_knowledge=None,
+    connect_timeout_secs=None
+))]
+pub fn configure_http(
+    pool_max_idle_per_host: Option<usize>,
+    pool_idle_timeout_secs: Option<f64>,
+    tcp_keepalive_secs: Option<f64>,
+    tcp_nodelay: Option<bool>,
+    http2_prior_knowledge: Option<bool>,
+    connect_timeout_secs: Option<f64>,
+) -> PyResult<()> {
+    let defaults = HttpSettings::default();
+    let settings = HttpSettings {
+        pool_max_idle_per_host: pool_max_idle_per_host.unwrap_or(defaults.pool_max_idle_per_host),
+        pool_idle_timeout: pool_idle_timeout_secs
+            .map(Duration::from_secs_f64)
+            .unwrap_or(defaults.pool_idle_timeout),
+        tcp_keepalive: tcp_keepalive_secs
+            .map(Duration::from_secs_f64)
+            .unwrap_or(defaults.tcp_keepalive),
+        tcp_nodelay: tcp_nodelay.unwrap_or(defaults.tcp_nodelay),
+        http2_prior_knowledge: http2_prior_knowledge.unwrap_or(defaults.http2_prior_knowledge),
+        connect_timeout: connect_timeout_secs
+            .map(Duration::from_secs_f64)
+            .unwrap_or(defaults.connect_timeout),
+    };
+    let client = settings
+        .build()
+        .map_err(|e| PyRuntimeError::new_err(format!("failed to build HTTP client: {}", e)))?;
+    *HTTP_CLIENT.write().unwrap() = (settings, client);
+    Ok(())
+}
@@ -0,0 +1,129 @@
+use serde_json::Value;
+
+/// One step of a path into a JSON document.
+#[derive(Clone, Debug, PartialEq)]
+enum Step {
+    Key(String),
+    Index(usize),
+}
+
+/// A compiled path, written as JSONPath (`$.choices[0].message.content`,
+/// `$['usage']`) or as a dot path (`choices.0.message.content`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct JsonPath {
+    steps: Vec<Step>,
+}
+
+impl JsonPath {
+    pub fn parse(path: &str) -> Result<JsonPath, String> {
+        let invalid = |reason: &str| format!("invalid JSON path {}: {}", path, reason);
+        let mut rest = path.trim();
+        rest = rest.strip_prefix('$').unwrap_or(rest);
+        let mut steps = Vec::new();
+        while !rest.is_empty() {
+            if let Some(bracketed) = rest.strip_prefix('[') {
+                let end = bracketed.find(']').ok_or_else(|| invalid("unclosed ["))?;
+                let inside = bracketed[..end].trim();
+                let quoted = inside
+                    .strip_prefix('\'')
+                    .and_then(|s| s.strip_suffix('\''))
+                    .or_else(|| inside.strip_prefix('"').and_then(|s| s.strip_suffix('"')));
+                steps.push(match quoted {
+                    Some(key) => Step::Key(key.to_string()),
+                    None => Step::Index(
+                        inside
+                            .parse()
+                            .map_err(|_| invalid("only indices and quoted keys go in brackets"))?,
+                    ),
+                });
+                rest = &bracketed[end + 1..];
+                continue;
+            }
+            rest = rest.strip_prefix('.').unwrap_or(rest);
+            let end = rest.find(['.', '[']).unwrap_or(rest.len());
+            let segment = &rest[..end];
+            if segment.is_empty() {
+                return Err(invalid("empty segment"));
+            }
+            // Numbers index arrays in dot paths, objects with numeric keys still match
+            steps.push(match segment.parse() {
+                Ok(index) => Step::Index(index),
+                Err(_) => Step::Key(segment.to_string()),
+            });
+            rest = &rest[end..];
+        }
+        Ok(JsonPath { steps })
+    }
+
+    /// The value at the path in `document`, None when any step is missing.
+    pub fn find<'a>(&self, document: &'a Value) -> Option<&'a Value> {
+        self.steps
+            .iter()
+            .try_fold(document, |value, step| match (step, value) {
+                (Step::Key(key), Value::Object(map)) => map.get(key),
+                (Step::Index(index), Value::Array(items)) => items.get(*index),
+                (Step::Index(index), Value::Object(map)) => map.get(&index.to_string()),
+                _ => None,
+            })
+    }
+
+    /// The value at the path in the JSON text `document`: strings as they
+    /// are, other values as JSON, and None for nulls and missing values.
+    pub fn extract(&self, document: &str) -> Option<String> {
+        let document: Value = serde_json::from_str(document).ok()?;
+        match self.find(&document)? {
+            Value::Null => None,
+            Value::String(text) => Some(text.clone()),
+            value => Some(value.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn jsonpath_and_dot_paths_find_the_same_value() {
+        let document = json!({"choices": [{"message": {"content": "hi"}}]});
+
+        for path in [
+            "$.choices[0].message.content",
+            "$['choices'][0]['message'][\"content\"]",
+            "choices.0.message.content",
+        ] {
+            let found = JsonPath::parse(path).unwrap().find(&document).cloned();
+            assert_eq!(found, Some(json!("hi")), "{}", path);
+        }
+    }
+
+    #[test]
+    fn numeric_segments_also_match_object_keys() {
+        let document = json!({"scores": {"1": 0.5}});
+
+        let found = JsonPath::parse("scores.1")
+            .unwrap()
+            .find(&document)
+            .cloned();
+        assert_eq!(found, Some(json!(0.5)));
+    }
+
+    #[test]
+    fn extract_returns_strings_bare_and_skips_nulls() {
+        let document = r#"{"a": "text", "b": {"c": 1}, "d": null}"#;
+        let extract = |path: &str| JsonPath::parse(path).unwrap().extract(document);
+
+        assert_eq!(extract("a").as_deref(), Some("text"));
+        assert_eq!(extract("b").as_deref(), Some(r#"{"c":1}"#));
+        assert_eq!(extract("d"), None);
+        assert_eq!(extract("e"), None);
+    }
+
+    #[test]
+    fn malformed_paths_are_errors() {
+        assert!(JsonPath::parse("$.a[0").is_err());
+        assert!(JsonPath::parse("a..b").is_err());
+        assert!(JsonPath::parse("a[key]").is_err());
+    }
+}
@@ -0,0 +1,107 @@
+use crate::pricing::response_cost;
+use crate::provider::Provider;
+use once_cell::sync::Lazy;
+use polars::prelude::*;
+use pyo3::prelude::*;
+use pyo3_polars::error::PyPolarsErr;
+use pyo3_polars::PyDataFrame;
+use serde_json::Value;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Every request sent since the module was loaded or the ledger cleared
+static LEDGER: Lazy<Mutex<Vec<LedgerEntry>>> = Lazy::new(|| Mutex::new(Vec::new()));
+// Numbers the expression calls, so requests can be grouped by the call that made them
+static CALL: AtomicU64 = AtomicU64::new(0);
+
+struct LedgerEntry {
+    call: u64,
+    tag: Option<String>,
+    // Unix time in seconds when the response arrived
+    timestamp: f64,
+    provider: &'static str,
+    model: String,
+    success: bool,
+    prompt_tokens: u64,
+    cached_tokens: u64,
+    completion_tokens: u64,
+    cost_usd: Option<f64>,
+    latency_ms: f64,
+}
+
+/// Starts a new expression call, the requests recorded next belong to it.
+pub fn start_call() {
+    CALL.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Number of the expression call in progress.
+pub fn current_call() -> u64 {
+    CALL.load(Ordering::Relaxed)
+}
+
+/// Records a request sent to `provider` for `model` and its response, null
+/// when the request failed.
+pub fn record(
+    provider: Provider,
+    model: &str,
+    response: Option<&str>,
+    latency: Duration,
+    tag: Option<&str>,
+) {
+    let parsed: Value = response
+        .and_then(|r| serde_json::from_str(r).ok())
+        .unwrap_or_default();
+    let usage = &parsed["usage"];
+    let model = parsed["model"].as_str().unwrap_or(model);
+    let entry = LedgerEntry {
+        call: current_call(),
+        tag: tag.map(|t| t.to_string()),
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default(),
+        provider: provider.as_str(),
+        model: model.to_string(),
+        success: response.is_some(),
+        prompt_tokens: usage["prompt_tokens"].as_u64().unwrap_or(0),
+        cached_tokens: usage["prompt_tokens_details"]["cached_tokens"]
+            .as_u64()
+            .unwrap_or(0),
+        completion_tokens: usage["completion_tokens"].as_u64().unwrap_or(0),
+        cost_usd: response.and_then(|r| response_cost(r, model)),
+        latency_ms: latency.as_secs_f64() * 1000.0,
+    };
+    LEDGER.lock().unwrap().push(entry);
+}
+
+fn usage_report() -> PolarsResult<DataFrame> {
+    let ledger = LEDGER.lock().unwrap();
+    df!(
+        "call" => ledger.iter().map(|e| e.call).collect::<Vec<_>>(),
+        "tag" => ledger.iter().map(|e| e.tag.as_deref()).collect::<Vec<_>>(),
+        "timestamp" => ledger.iter().map(|e| e.timestamp).collect::<Vec<_>>(),
+        "provider" => ledger.iter().map(|e| e.provider).collect::<Vec<_>>(),
+        "model" => ledger.iter().map(|e| e.model.as_str()).collect::<Vec<_>>(),
+        "success" => ledger.iter().map(|e| e.success).collect::<Vec<_>>(),
+        "prompt_tokens" => ledger.iter().map(|e| e.prompt_tokens).collect::<Vec<_>>(),
+        "cached_tokens" => ledger.iter().map(|e| e.cached_tokens).collect::<Vec<_>>(),
+        "completion_tokens" => ledger.iter().map(|e| e.completion_tokens).collect::<Vec<_>>(),
+        "cost_usd" => ledger.iter().map(|e| e.cost_usd).collect::<Vec<_>>(),
+        "latency_ms" => ledger.iter().map(|e| e.latency_ms).collect::<Vec<_>>(),
+    )
+}
+
+/// Returns every request sent through the plugin so far as a DataFrame,
+/// one row per request with its call, tag, provider, model, tokens,
+/// estimated cost and latency.
+#[pyfunction]
+pub fn get_usage_report() -> PyResult<PyDataFrame> {
+    Ok(PyDataFrame(usage_report().map_err(PyPolarsErr::from)?))
+}
+
+/// Empties the usage ledger.
+#[pyfunction]
+pub fn clear_usage_report() {
+    LEDGER.lock().unwrap().clear();
+}
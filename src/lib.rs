@@ -1,4 +1,40 @@
+mod audit;
+mod capabilities;
+mod checkpoint;
+mod concurrency;
+mod config;
+mod credentials;
+mod embeddings;
 mod expressions;
+mod few_shot;
+mod guardrails;
+mod http;
+mod json_path;
+mod ledger;
+#[cfg(feature = "local-embeddings")]
+mod local;
+mod logging;
+mod messages;
+mod metrics;
+mod mock;
+mod pii;
+mod postprocess;
+mod pricing;
+mod progress;
+mod provider;
+mod rerank;
+mod responses;
+mod routing;
+mod runtime;
+mod schema;
+mod search;
+mod secrets;
+mod semantic_cache;
+mod stream;
+mod structured;
+mod tasks;
+mod template;
+mod tokens;
 mod utils;
 
 #[cfg(target_os = "linux")]
@@ -9,11 +45,39 @@ use jemallocator::Jemalloc;
 static ALLOC: Jemalloc = Jemalloc;
 
 use pyo3::types::PyModule;
-use pyo3::{pymodule, PyResult, Python};
+use pyo3::{pymodule, wrap_pyfunction, PyResult, Python};
 
 #[pymodule]
 #[allow(deprecated)]
 fn polar_llama(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
     m.add("__version__", env!("CARGO_PKG_VERSION"))?;
+    m.add_class::<config::Config>()?;
+    provider::register(m)?;
+    m.add_function(wrap_pyfunction!(config::get_config, m)?)?;
+    m.add_function(wrap_pyfunction!(config::set_config, m)?)?;
+    m.add_function(wrap_pyfunction!(config::reset_config, m)?)?;
+    m.add_class::<metrics::CacheMetrics>()?;
+    m.add_function(wrap_pyfunction!(metrics::cache_metrics, m)?)?;
+    m.add_class::<metrics::RunStats>()?;
+    m.add_function(wrap_pyfunction!(metrics::last_run_stats, m)?)?;
+    m.add_class::<capabilities::ModelCapabilities>()?;
+    m.add_function(wrap_pyfunction!(capabilities::py_model_capabilities, m)?)?;
+    m.add_function(wrap_pyfunction!(capabilities::list_models, m)?)?;
+    m.add_class::<capabilities::SetupDiagnosis>()?;
+    m.add_function(wrap_pyfunction!(capabilities::validate_setup, m)?)?;
+    m.add_function(wrap_pyfunction!(pricing::set_model_price, m)?)?;
+    m.add_function(wrap_pyfunction!(ledger::get_usage_report, m)?)?;
+    m.add_function(wrap_pyfunction!(ledger::clear_usage_report, m)?)?;
+    m.add_function(wrap_pyfunction!(http::configure_http, m)?)?;
+    m.add_function(wrap_pyfunction!(logging::configure_logging, m)?)?;
+    m.add_function(wrap_pyfunction!(audit::configure_audit_log, m)?)?;
+    m.add_function(wrap_pyfunction!(stream::set_stream_callback, m)?)?;
+    m.add_class::<progress::Progress>()?;
+    m.add_function(wrap_pyfunction!(progress::set_progress_callback, m)?)?;
+    m.add_function(wrap_pyfunction!(mock::configure_mock, m)?)?;
+    m.add_function(wrap_pyfunction!(search::semantic_join, m)?)?;
+    m.add_function(wrap_pyfunction!(credentials::set_api_key, m)?)?;
+    m.add_function(wrap_pyfunction!(credentials::set_api_keys, m)?)?;
+    m.add_function(wrap_pyfunction!(credentials::clear_api_key, m)?)?;
     Ok(())
 }
@@ -0,0 +1,41 @@
+use crate::config::config;
+use crate::utils::FetchError;
+use fastembed::{EmbeddingModel, InitOptions, TextEmbedding};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub const DEFAULT_MODEL: &str = "BAAI/bge-small-en-v1.5";
+
+// Models are loaded once per process, loading one takes seconds
+static MODELS: Lazy<Mutex<HashMap<String, Arc<TextEmbedding>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn load(model: &str) -> Result<Arc<TextEmbedding>, FetchError> {
+    let mut models = MODELS.lock().unwrap();
+    if let Some(loaded) = models.get(model) {
+        return Ok(loaded.clone());
+    }
+    let name: EmbeddingModel = model.parse().map_err(FetchError::Unsupported)?;
+    let options = InitOptions::new(name)
+        .with_cache_dir(config().resolve_path(".fastembed_cache"))
+        .with_show_download_progress(false);
+    let loaded = Arc::new(
+        TextEmbedding::try_new(options).map_err(|e| FetchError::Unsupported(e.to_string()))?,
+    );
+    models.insert(model.to_string(), loaded.clone());
+    Ok(loaded)
+}
+
+/// Embeds `inputs` with a local ONNX model, downloading it on first use.
+pub async fn embed(inputs: &[String], model: String) -> Result<Vec<Vec<f32>>, FetchError> {
+    let inputs = inputs.to_vec();
+    // Inference is CPU bound, keep it off the runtime's worker threads
+    tokio::task::spawn_blocking(move || {
+        load(&model)?
+            .embed(inputs, None)
+            .map_err(|e| FetchError::Unsupported(e.to_string()))
+    })
+    .await
+    .map_err(|e| FetchError::Unsupported(e.to_string()))?
+}
@@ -0,0 +1,137 @@
+use crate::secrets::scrub;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Once;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+
+// Most verbose level logged, 0 turns logging off
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(0);
+static TO_PYTHON: AtomicBool = AtomicBool::new(false);
+static INSTALL: Once = Once::new();
+
+fn level_rank(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 1,
+        Level::WARN => 2,
+        Level::INFO => 3,
+        Level::DEBUG => 4,
+        Level::TRACE => 5,
+    }
+}
+
+// Python logging level of the same name
+fn python_level(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 40,
+        Level::WARN => 30,
+        Level::INFO => 20,
+        Level::DEBUG | Level::TRACE => 10,
+    }
+}
+
+// Writes the fields of a span or event as ` key=value`, the message first
+#[derive(Default)]
+struct Fields(String);
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let fields = std::mem::take(&mut self.0);
+            let _ = write!(self.0, "{:?}{}", value, fields);
+        } else {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.record_debug(field, &format_args!("{}", value));
+        } else {
+            let _ = write!(self.0, " {}={}", field.name(), value);
+        }
+    }
+}
+
+// Prints events with the fields of the spans they happen in, to stderr or
+// to the `polar_llama` Python logger
+struct PluginLayer;
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for PluginLayer {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(fields);
+        }
+    }
+
+    fn enabled(&self, metadata: &tracing::Metadata<'_>, _ctx: Context<'_, S>) -> bool {
+        level_rank(metadata.level()) <= MAX_LEVEL.load(Ordering::Relaxed)
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut message = Fields::default();
+        event.record(&mut message);
+        let mut line = message.0;
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(fields) = span.extensions().get::<Fields>() {
+                    let _ = write!(line, " [{}{}]", span.name(), fields.0);
+                }
+            }
+        }
+
+        let line = scrub(&line);
+        let level = event.metadata().level();
+        if TO_PYTHON.load(Ordering::Relaxed) {
+            Python::with_gil(|py| {
+                let logged = py
+                    .import_bound("logging")
+                    .and_then(|logging| logging.call_method1("getLogger", ("polar_llama",)))
+                    .and_then(|logger| logger.call_method1("log", (python_level(level), &line)));
+                if let Err(e) = logged {
+                    e.print(py);
+                }
+            });
+        } else {
+            eprintln!("polar_llama {} {}", level, line);
+        }
+    }
+}
+
+/// Sets the most verbose level logged, one of off, error, warn, info, debug
+/// and trace. Events go to stderr, or with `forward_to_python` to the
+/// `polar_llama` logger of Python's `logging`, which then filters them too.
+#[pyfunction]
+#[pyo3(signature = (level="warn", forward_to_python=false))]
+pub fn configure_logging(level: &str, forward_to_python: bool) -> PyResult<()> {
+    let rank = match level.to_ascii_lowercase().as_str() {
+        "off" => 0,
+        "error" => 1,
+        "warn" | "warning" => 2,
+        "info" => 3,
+        "debug" => 4,
+        "trace" => 5,
+        _ => {
+            return Err(PyValueError::new_err(format!(
+                "unknown log level {}, expected off, error, warn, info, debug or trace",
+                level
+            )))
+        }
+    };
+    INSTALL.call_once(|| {
+        let _ = tracing_subscriber::registry().with(PluginLayer).try_init();
+    });
+    MAX_LEVEL.store(rank, Ordering::Relaxed);
+    TO_PYTHON.store(forward_to_python, Ordering::Relaxed);
+    // Level checks are cached per call site, they depend on MAX_LEVEL now
+    tracing::callsite::rebuild_interest_cache();
+    Ok(())
+}
@@ -0,0 +1,807 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use polars::chunked_array::builder::AnonymousOwnedListBuilder;
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+/// One chat message, the element of a conversation column.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub role: String,
+    pub content: Content,
+    // Distinguishes participants that share a role
+    pub name: Option<String>,
+    // Prompt caching breakpoint type, such as "ephemeral"
+    pub cache_control: Option<String>,
+    // Other fields of the message, such as OpenAI's `tool_calls` and
+    // `tool_call_id`, sent back as they are
+    pub extra: Map<String, Value>,
+}
+
+/// The content of a message: text, or a list of parts kept in the order
+/// they were given and sent back as they are.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+    // No content, as in an assistant turn that only calls tools
+    Null,
+}
+
+/// A part of a message's content, kept so conversations from other SDKs
+/// round-trip. Parts with fields not declared here are kept whole as
+/// `Other`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ContentPart {
+    Text {
+        text: String,
+    },
+    ImageUrl {
+        image_url: ImageUrl,
+    },
+    // A document, such as a PDF, as a data URL or an uploaded file's id
+    File {
+        file: File,
+    },
+    // Anthropic's tool call, made by the assistant
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+    // Anthropic's tool result, its content either text or a list of parts
+    ToolResult {
+        tool_use_id: String,
+        #[serde(default, skip_serializing_if = "Value::is_null")]
+        content: Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        is_error: Option<bool>,
+    },
+    // Parts of any other type, as they are
+    #[serde(untagged)]
+    Other(Value),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImageUrl {
+    pub url: String,
+    // Resolution the image is looked at in, "low", "high" or "auto"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct File {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_data: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_id: Option<String>,
+}
+
+impl ContentPart {
+    pub fn image(url: String) -> ContentPart {
+        ContentPart::ImageUrl {
+            image_url: ImageUrl { url, detail: None },
+        }
+    }
+
+    // The `number`th document of a message, named after its position
+    pub fn document(data_url: String, number: usize) -> ContentPart {
+        ContentPart::File {
+            file: File {
+                filename: Some(format!("document-{}.pdf", number)),
+                file_data: Some(data_url),
+                file_id: None,
+            },
+        }
+    }
+}
+
+impl Message {
+    /// A text message from `role`.
+    pub fn new(role: &str, text: &str) -> Message {
+        Message {
+            role: role.to_string(),
+            content: Content::Text(text.to_string()),
+            name: None,
+            cache_control: None,
+            extra: Map::new(),
+        }
+    }
+
+    /// A message from `role` with `text`, when not empty, followed by
+    /// image URLs and then document data URLs.
+    pub fn with_attachments(
+        role: &str,
+        text: &str,
+        images: Vec<String>,
+        documents: Vec<String>,
+    ) -> Message {
+        if images.is_empty() && documents.is_empty() {
+            return Message::new(role, text);
+        }
+        let text = (!text.is_empty()).then(|| ContentPart::Text {
+            text: text.to_string(),
+        });
+        let images = images.into_iter().map(ContentPart::image);
+        let documents = documents
+            .into_iter()
+            .enumerate()
+            .map(|(i, url)| ContentPart::document(url, i + 1));
+        Message {
+            content: Content::Parts(text.into_iter().chain(images).chain(documents).collect()),
+            ..Message::new(role, "")
+        }
+    }
+
+    /// The text of the message, its text parts joined by newlines.
+    pub fn text(&self) -> String {
+        match &self.content {
+            Content::Text(text) => text.clone(),
+            Content::Null => String::new(),
+            Content::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ContentPart::Text { text } => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+
+    /// The parts of the message, empty when its content is text.
+    pub fn parts(&self) -> &[ContentPart] {
+        match &self.content {
+            Content::Text(_) | Content::Null => &[],
+            Content::Parts(parts) => parts,
+        }
+    }
+
+    /// URLs of the message's images, in order.
+    pub fn images(&self) -> Vec<&str> {
+        self.parts()
+            .iter()
+            .filter_map(|part| match part {
+                ContentPart::ImageUrl { image_url } => Some(image_url.url.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Data URLs, or file ids, of the message's documents, in order.
+    pub fn documents(&self) -> Vec<&str> {
+        self.parts()
+            .iter()
+            .filter_map(|part| match part {
+                ContentPart::File { file } => file.file_data.as_deref().or(file.file_id.as_deref()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn to_json(&self) -> Value {
+        let content = match &self.content {
+            Content::Text(text) => json!(text),
+            Content::Parts(parts) => Value::Array(
+                parts
+                    .iter()
+                    .filter_map(|p| serde_json::to_value(p).ok())
+                    .collect(),
+            ),
+            Content::Null => Value::Null,
+        };
+        let mut message = json!({"role": self.role, "content": content});
+        if let Some(name) = &self.name {
+            message["name"] = json!(name);
+        }
+        if let Some(cache_control) = &self.cache_control {
+            message["cache_control"] = json!({ "type": cache_control });
+        }
+        for (key, value) in &self.extra {
+            message[key] = value.clone();
+        }
+        message
+    }
+
+    pub fn from_json(value: &Value) -> Option<Message> {
+        // Content is either text, a list of text, image, file and other
+        // parts, or null next to the tool calls of an assistant turn
+        let content = match &value["content"] {
+            Value::Null => Content::Null,
+            Value::String(text) => Content::Text(text.clone()),
+            Value::Array(parts) => Content::Parts(
+                parts
+                    .iter()
+                    .map(|part| serde_json::from_value(part.clone()).ok())
+                    .collect::<Option<_>>()?,
+            ),
+            _ => return None,
+        };
+        Some(Message {
+            role: value["role"].as_str()?.to_string(),
+            content,
+            name: value["name"].as_str().map(|s| s.to_string()),
+            cache_control: value["cache_control"]["type"]
+                .as_str()
+                .map(|s| s.to_string()),
+            extra: value
+                .as_object()?
+                .iter()
+                .filter(|(key, _)| !MESSAGE_FIELDS.contains(&key.as_str()))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+        })
+    }
+}
+
+// Fields of a JSON message read into the fields of `Message`
+const MESSAGE_FIELDS: &[&str] = &["role", "content", "name", "cache_control"];
+
+// File type from the file signature, for bytes without an explicit type
+fn sniff_file_type(bytes: &[u8]) -> &'static str {
+    match bytes {
+        [b'%', b'P', b'D', b'F', ..] => "application/pdf",
+        [0x89, b'P', b'N', b'G', ..] => "image/png",
+        [0xFF, 0xD8, 0xFF, ..] => "image/jpeg",
+        [b'G', b'I', b'F', b'8', ..] => "image/gif",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Base64 data URL of an image or document file's bytes.
+pub fn data_url(bytes: &[u8], mime_type: Option<&str>) -> String {
+    let mime_type = mime_type.unwrap_or_else(|| sniff_file_type(bytes));
+    format!("data:{};base64,{}", mime_type, STANDARD.encode(bytes))
+}
+
+/// `Struct{role, content, name, cache_control, images, documents, parts, extra}`.
+/// `content`, `images` and `documents` are views of the text, images and
+/// documents of the message, `parts` holds every content part as JSON, in
+/// order, and is empty for text messages. `extra` holds the other fields
+/// of the message, such as tool calls, as a JSON object, or null.
+pub fn message_dtype() -> DataType {
+    DataType::Struct(vec![
+        Field::new("role", DataType::String),
+        Field::new("content", DataType::String),
+        Field::new("name", DataType::String),
+        Field::new("cache_control", DataType::String),
+        Field::new("images", DataType::List(Box::new(DataType::String))),
+        Field::new("documents", DataType::List(Box::new(DataType::String))),
+        Field::new("parts", DataType::List(Box::new(DataType::String))),
+        Field::new("extra", DataType::String),
+    ])
+}
+
+/// `List[Struct{role, content, name, cache_control, images, documents, parts, extra}]`
+pub fn conversation_dtype() -> DataType {
+    DataType::List(Box::new(message_dtype()))
+}
+
+/// Builds a message struct column, null messages have every field null.
+pub fn message_structs(column: &str, messages: &[Option<&Message>]) -> PolarsResult<Series> {
+    let field = |name: &str, value: fn(&Message) -> Option<&str>| {
+        let values: Vec<Option<&str>> = messages.iter().map(|m| m.and_then(value)).collect();
+        Series::new(name, values)
+    };
+    let list_field = |name: &str, values: fn(&Message) -> Vec<String>| {
+        let mut builder = ListStringChunkedBuilder::new(name, messages.len(), messages.len());
+        for message in messages {
+            match message {
+                Some(m) => builder.append_values_iter(values(m).iter().map(|s| s.as_str())),
+                None => builder.append_null(),
+            }
+        }
+        builder.finish().into_series()
+    };
+    let texts: Vec<Option<String>> = messages
+        .iter()
+        .map(|m| m.filter(|m| m.content != Content::Null).map(Message::text))
+        .collect();
+    let extras: Vec<Option<String>> = messages
+        .iter()
+        .map(|m| {
+            let m = m.filter(|m| !m.extra.is_empty())?;
+            serde_json::to_string(&m.extra).ok()
+        })
+        .collect();
+    let fields = [
+        field("role", |m| Some(m.role.as_str())),
+        Series::new("content", texts),
+        field("name", |m| m.name.as_deref()),
+        field("cache_control", |m| m.cache_control.as_deref()),
+        list_field("images", |m| {
+            m.images().into_iter().map(str::to_string).collect()
+        }),
+        list_field("documents", |m| {
+            m.documents().into_iter().map(str::to_string).collect()
+        }),
+        list_field("parts", |m| {
+            m.parts()
+                .iter()
+                .filter_map(|p| serde_json::to_string(p).ok())
+                .collect()
+        }),
+        Series::new("extra", extras),
+    ];
+    Ok(StructChunked::new(column, &fields)?.into_series())
+}
+
+/// Builds a message struct column with the same `role` and participant
+/// `name` for every row, rows without content are null.
+pub fn message_column(
+    column: &str,
+    role: &str,
+    name: Option<&str>,
+    content: &StringChunked,
+) -> PolarsResult<Series> {
+    let messages: Vec<Option<Message>> = content
+        .into_iter()
+        .map(|content| {
+            Some(Message {
+                name: name.map(|s| s.to_string()),
+                ..Message::new(role, content?)
+            })
+        })
+        .collect();
+    let messages: Vec<Option<&Message>> = messages.iter().map(|m| m.as_ref()).collect();
+    message_structs(column, &messages)
+}
+
+// Optional struct field, missing fields are all null
+fn optional_field(ca: &StructChunked, name: &str) -> PolarsResult<Series> {
+    match ca.field_by_name(name) {
+        Ok(field) => field.cast(&DataType::String),
+        Err(_) => Ok(Series::full_null(name, ca.len(), &DataType::String)),
+    }
+}
+
+// Optional list field, missing fields are all empty
+fn optional_list_field(ca: &StructChunked, name: &str) -> PolarsResult<Vec<Vec<String>>> {
+    let Ok(field) = ca.field_by_name(name) else {
+        return Ok(vec![Vec::new(); ca.len()]);
+    };
+    field
+        .list()?
+        .into_iter()
+        .map(|row| match row {
+            Some(row) => Ok(row
+                .cast(&DataType::String)?
+                .str()?
+                .into_iter()
+                .flatten()
+                .map(|s| s.to_string())
+                .collect()),
+            None => Ok(Vec::new()),
+        })
+        .collect()
+}
+
+fn struct_rows(ca: &StructChunked) -> PolarsResult<Vec<Option<Message>>> {
+    let roles = ca.field_by_name("role")?.cast(&DataType::String)?;
+    let contents = ca.field_by_name("content")?.cast(&DataType::String)?;
+    let names = optional_field(ca, "name")?;
+    let cache_control = optional_field(ca, "cache_control")?;
+    let images = optional_list_field(ca, "images")?;
+    let documents = optional_list_field(ca, "documents")?;
+    let extras = optional_field(ca, "extra")?
+        .str()?
+        .into_iter()
+        .map(|extra| match extra {
+            Some(extra) => serde_json::from_str(extra)
+                .map_err(|e| polars_err!(ComputeError: "invalid message fields {}: {}", extra, e)),
+            None => Ok(Map::new()),
+        })
+        .collect::<PolarsResult<Vec<Map<String, Value>>>>()?;
+    let parts = optional_list_field(ca, "parts")?
+        .into_iter()
+        .map(|parts| {
+            parts
+                .iter()
+                .map(|part| {
+                    serde_json::from_str(part).map_err(
+                        |e| polars_err!(ComputeError: "invalid message part {}: {}", part, e),
+                    )
+                })
+                .collect::<PolarsResult<Vec<ContentPart>>>()
+        })
+        .collect::<PolarsResult<Vec<_>>>()?;
+    Ok(roles
+        .str()?
+        .into_iter()
+        .zip(contents.str()?)
+        .zip(names.str()?)
+        .zip(cache_control.str()?)
+        .zip(images)
+        .zip(documents)
+        .zip(parts)
+        .zip(extras)
+        .map(
+            |(((((((role, content), name), cache_control), images), documents), parts), extra)| {
+                // Parts hold the whole content when given, structs built
+                // by hand may only have the text, images and documents.
+                // Content is only null next to other fields, such as the
+                // tool calls of an assistant turn, otherwise the row is.
+                let mut message = match (parts.is_empty(), content) {
+                    (false, _) => Message {
+                        content: Content::Parts(parts),
+                        ..Message::new(role?, "")
+                    },
+                    (true, None) if !extra.is_empty() => Message {
+                        content: Content::Null,
+                        ..Message::new(role?, "")
+                    },
+                    (true, content) => {
+                        Message::with_attachments(role?, content?, images, documents)
+                    }
+                };
+                message.name = name.map(|s| s.to_string());
+                message.cache_control = cache_control.map(|s| s.to_string());
+                message.extra = extra;
+                Some(message)
+            },
+        )
+        .collect())
+}
+
+/// Roles a message can have, those of OpenAI's Chat Completions API, which
+/// every chat provider accepts.
+pub const ROLES: &[&str] = &["system", "developer", "user", "assistant", "tool"];
+
+// Messages of a JSON message or array of messages, nested arrays flattened
+fn json_messages(value: &Value) -> Option<Vec<Message>> {
+    match value {
+        Value::Array(items) => items.iter().try_fold(Vec::new(), |mut messages, item| {
+            messages.extend(json_messages(item)?);
+            Some(messages)
+        }),
+        message => Some(vec![Message::from_json(message)?]),
+    }
+}
+
+/// `read_conversations` that fails on rows which are not messages and on
+/// roles not in `ROLES`, rather than leaving the rows null.
+pub fn checked_conversations(series: &Series) -> PolarsResult<Vec<Option<Vec<Message>>>> {
+    let rows = read_conversations(series)?;
+    let present = series.is_not_null();
+    for (i, (row, present)) in rows.iter().zip(&present).enumerate() {
+        let Some(messages) = row else {
+            polars_ensure!(
+                present != Some(true),
+                ComputeError: "row {} of {} is not a message or list of messages", i, series.name()
+            );
+            continue;
+        };
+        if let Some(message) = messages.iter().find(|m| !ROLES.contains(&m.role.as_str())) {
+            polars_bail!(
+                ComputeError: "row {} of {} has a message with unknown role {:?}, expected one of {}",
+                i, series.name(), message.role, ROLES.join(", ")
+            );
+        }
+    }
+    Ok(rows)
+}
+
+/// The conversation of a JSON message or array of messages, nested arrays
+/// flattened.
+pub fn conversation_from_json(row: &str) -> Option<Vec<Message>> {
+    json_messages(&serde_json::from_str(row).ok()?)
+}
+
+/// Reads the conversation of every row from a column of JSON messages or
+/// message arrays, message structs or lists of message structs.
+pub fn read_conversations(series: &Series) -> PolarsResult<Vec<Option<Vec<Message>>>> {
+    match series.dtype() {
+        DataType::String => Ok(series
+            .str()?
+            .into_iter()
+            .map(|row| conversation_from_json(row?))
+            .collect()),
+        DataType::Struct(_) => Ok(struct_rows(series.struct_()?)?
+            .into_iter()
+            .map(|message| message.map(|m| vec![m]))
+            .collect()),
+        DataType::List(inner) if matches!(inner.as_ref(), DataType::Struct(_)) => series
+            .list()?
+            .into_iter()
+            .map(|row| match row {
+                Some(row) => Ok(struct_rows(row.struct_()?)?.into_iter().collect()),
+                None => Ok(None),
+            })
+            .collect(),
+        DataType::Null => Ok(vec![None; series.len()]),
+        dtype => polars_bail!(
+            ComputeError: "expected messages as JSON strings, structs or lists of structs, got {}", dtype
+        ),
+    }
+}
+
+/// Builds a `List[Struct{role, content, name, cache_control, images, documents, parts, extra}]` column.
+pub fn conversation_column(name: &str, rows: &[Option<Vec<Message>>]) -> PolarsResult<Series> {
+    let messages: Vec<Option<&Message>> = rows.iter().flatten().flatten().map(Some).collect();
+    let all = message_structs("", &messages)?;
+
+    let mut builder = AnonymousOwnedListBuilder::new(name, rows.len(), Some(message_dtype()));
+    let mut offset = 0;
+    for row in rows {
+        match row {
+            Some(row) => {
+                builder.append_series(&all.slice(offset as i64, row.len()))?;
+                offset += row.len();
+            }
+            None => builder.append_null(),
+        }
+    }
+    Ok(builder.finish().into_series())
+}
+
+/// Serializes a conversation to the JSON message array sent to the API.
+pub fn conversation_json(messages: &[Message]) -> String {
+    Value::Array(messages.iter().map(Message::to_json).collect()).to_string()
+}
+
+/// Serializes every conversation with `conversation_json`.
+pub fn conversations_to_json(rows: Vec<Option<Vec<Message>>>) -> Vec<Option<String>> {
+    rows.into_iter()
+        .map(|row| Some(conversation_json(&row?)))
+        .collect()
+}
+
+/// The assistant message of a chat completion response.
+pub fn reply_message(response: &str) -> Option<Message> {
+    let response: Value = serde_json::from_str(response).ok()?;
+    Message::from_json(&response["choices"][0]["message"])
+}
+
+/// The messages of a JSON row and the request fields sent for that row
+/// only. A row holds a single message, a conversation, or an object with
+/// the conversation under `messages` next to fields such as `prediction`.
+pub fn request_parts(row: &str) -> Option<(Vec<Value>, Map<String, Value>)> {
+    match serde_json::from_str(row).ok()? {
+        Value::Array(messages) => Some((messages, Map::new())),
+        Value::Object(mut fields) if fields.get("messages").is_some_and(Value::is_array) => {
+            let Some(Value::Array(messages)) = fields.remove("messages") else {
+                return None;
+            };
+            Some((messages, fields))
+        }
+        message => Some((vec![message], Map::new())),
+    }
+}
+
+/// Renames the roles of `messages` found in `role_map`.
+pub fn map_roles(messages: &mut [Value], role_map: &HashMap<String, String>) {
+    if role_map.is_empty() {
+        return;
+    }
+    for message in messages {
+        if let Some(role) = message["role"].as_str().and_then(|r| role_map.get(r)) {
+            message["role"] = Value::String(role.clone());
+        }
+    }
+}
+
+/// The first role of a JSON row that is not in `allowed` once renamed by
+/// `role_map`.
+pub fn disallowed_role(
+    row: &str,
+    role_map: &HashMap<String, String>,
+    allowed: &[&str],
+) -> Option<String> {
+    let (mut messages, _) = request_parts(row)?;
+    map_roles(&mut messages, role_map);
+    messages
+        .iter()
+        .filter_map(|m| m["role"].as_str())
+        .find(|role| !allowed.contains(role))
+        .map(str::to_string)
+}
+
+/// A JSON row sent with OpenAI's predicted output, text most of the reply
+/// is expected to repeat, which makes such replies faster and cheaper.
+pub fn with_prediction(row: &str, prediction: &str) -> Option<String> {
+    let (messages, mut fields) = request_parts(row)?;
+    fields.insert("messages".to_string(), Value::Array(messages));
+    fields.insert(
+        "prediction".to_string(),
+        json!({"type": "content", "content": prediction}),
+    );
+    Some(Value::Object(fields).to_string())
+}
+
+/// A JSON conversation, or single message, continued with the assistant's
+/// `reply` and a new user message. Fields sent with the row are dropped,
+/// since they were meant for the first request.
+pub fn follow_up(messages: &str, reply: &str, user: &str) -> Option<String> {
+    let (mut messages, _) = request_parts(messages)?;
+    messages.push(json!({"role": "assistant", "content": reply}));
+    messages.push(json!({"role": "user", "content": user}));
+    Some(Value::Array(messages).to_string())
+}
+
+/// JSON messages of every row as sent in a request, JSON columns are passed
+/// through unchanged.
+pub fn request_messages(series: &Series) -> PolarsResult<Vec<Option<String>>> {
+    match series.dtype() {
+        DataType::String => Ok(series
+            .str()?
+            .into_iter()
+            .map(|row| row.map(|s| s.to_string()))
+            .collect()),
+        _ => Ok(conversations_to_json(read_conversations(series)?)),
+    }
+}
+
+/// What is done with rows whose prompt is null or empty, from the
+/// `on_empty` kwarg: `skip`, `error` or `default:<text>`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum EmptyPrompts {
+    // Not sent, the row's output is null
+    #[default]
+    Skip,
+    // The whole call fails
+    Error,
+    // Sent as a user message with this text instead
+    Default(String),
+}
+
+impl TryFrom<String> for EmptyPrompts {
+    type Error = String;
+
+    fn try_from(policy: String) -> Result<Self, String> {
+        match policy.as_str() {
+            "skip" => Ok(EmptyPrompts::Skip),
+            "error" => Ok(EmptyPrompts::Error),
+            _ => match policy.strip_prefix("default:") {
+                Some(text) => Ok(EmptyPrompts::Default(text.to_string())),
+                None => Err(format!(
+                    "unknown on_empty policy {}, expected skip, error or default:<text>",
+                    policy
+                )),
+            },
+        }
+    }
+}
+
+// Rows without any text, image or document to send, including empty
+// conversations; rows that are not messages at all are left to the API
+fn is_empty_prompt(row: &str) -> bool {
+    let blank = |message: &Value| {
+        Message::from_json(message).is_some_and(|m| match &m.content {
+            Content::Text(text) => text.trim().is_empty(),
+            // A turn with only tool calls still has them to send
+            Content::Null => m.extra.is_empty(),
+            Content::Parts(parts) => parts
+                .iter()
+                .all(|part| matches!(part, ContentPart::Text { text } if text.trim().is_empty())),
+        })
+    };
+    match request_parts(row) {
+        Some((messages, _)) => messages.iter().all(blank),
+        None => row.trim().is_empty(),
+    }
+}
+
+impl EmptyPrompts {
+    /// `rows` with the policy applied to null and empty prompts, which are
+    /// null afterwards unless they get the default text.
+    pub fn apply(&self, rows: Vec<Option<String>>) -> PolarsResult<Vec<Option<String>>> {
+        self.apply_from(rows, 0)
+    }
+
+    /// `apply` for a chunk of rows starting at row `first`, so errors
+    /// report the row of the whole input.
+    pub fn apply_from(
+        &self,
+        rows: Vec<Option<String>>,
+        first: usize,
+    ) -> PolarsResult<Vec<Option<String>>> {
+        rows.into_iter()
+            .enumerate()
+            .map(|(i, row)| {
+                if row.as_deref().is_some_and(|row| !is_empty_prompt(row)) {
+                    return Ok(row);
+                }
+                match self {
+                    EmptyPrompts::Skip => Ok(None),
+                    EmptyPrompts::Error => {
+                        polars_bail!(ComputeError: "row {} has an empty prompt", first + i)
+                    }
+                    EmptyPrompts::Default(text) => {
+                        Ok(Some(json!([{"role": "user", "content": text}]).to_string()))
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parts_round_trip_in_order() {
+        let message = json!({
+            "role": "user",
+            "content": [
+                {"type": "image_url", "image_url": {"url": "https://example.com/a.png"}},
+                {"type": "text", "text": "Compare"},
+                {"type": "file", "file": {"file_id": "file-1"}},
+                {"type": "text", "text": "and this", "cache_control": {"type": "ephemeral"}},
+                {"type": "tool_use", "id": "call_1", "name": "weather", "input": {"city": "Paris"}},
+            ],
+            "name": "analyst",
+        });
+
+        let parsed = Message::from_json(&message).unwrap();
+
+        assert_eq!(parsed.to_json(), message);
+        assert_eq!(parsed.text(), "Compare");
+        assert_eq!(parsed.images(), ["https://example.com/a.png"]);
+        assert_eq!(parsed.documents(), ["file-1"]);
+    }
+
+    #[test]
+    fn attachments_follow_the_text() {
+        let message = Message::with_attachments(
+            "user",
+            "Summarize",
+            vec!["https://example.com/a.png".into()],
+            vec!["data:application/pdf;base64,JVBERi0xLjQ=".into()],
+        );
+
+        assert_eq!(
+            message.to_json()["content"],
+            json!([
+                {"type": "text", "text": "Summarize"},
+                {"type": "image_url", "image_url": {"url": "https://example.com/a.png"}},
+                {"type": "file", "file": {
+                    "filename": "document-1.pdf",
+                    "file_data": "data:application/pdf;base64,JVBERi0xLjQ="
+                }},
+            ])
+        );
+        assert_eq!(Message::from_json(&message.to_json()), Some(message));
+    }
+
+    #[test]
+    fn tool_call_turns_round_trip() {
+        let conversation = json!([
+            {"role": "user", "content": "Weather in Paris?"},
+            {"role": "assistant", "content": null, "tool_calls": [{
+                "id": "call_1",
+                "type": "function",
+                "function": {"name": "weather", "arguments": "{\"city\":\"Paris\"}"},
+            }]},
+            {"role": "tool", "tool_call_id": "call_1", "content": "18°C and sunny"},
+        ]);
+
+        let messages = conversation_from_json(&conversation.to_string()).unwrap();
+        assert_eq!(conversation_json(&messages), conversation.to_string());
+
+        let column = conversation_column("conversation", &[Some(messages.clone())]).unwrap();
+        assert_eq!(read_conversations(&column).unwrap(), [Some(messages)]);
+        assert!(!is_empty_prompt(&json!([conversation[1]]).to_string()));
+    }
+
+    #[test]
+    fn blank_parts_are_empty_prompts() {
+        assert!(is_empty_prompt(r#"[{"role": "user", "content": "  "}]"#));
+        assert!(is_empty_prompt(
+            r#"[{"role": "user", "content": [{"type": "text", "text": ""}]}]"#
+        ));
+        assert!(!is_empty_prompt(
+            r#"[{"role": "user", "content": [{"type": "image_url", "image_url": {"url": "a.png"}}]}]"#
+        ));
+    }
+}
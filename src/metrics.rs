@@ -0,0 +1,342 @@
+use crate::config::config;
+use crate::ledger;
+use crate::pricing::{cached_tokens_saved, response_cost};
+use once_cell::sync::Lazy;
+use pyo3::prelude::*;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Metrics of the most recent inference call
+static LAST_RUN: Lazy<Mutex<CacheMetrics>> = Lazy::new(|| Mutex::new(CacheMetrics::default()));
+// Requests of the most recent inference call, summarized by `last_run_stats`
+static LAST_LOG: Lazy<Mutex<RunLog>> = Lazy::new(|| Mutex::new(RunLog::new()));
+
+#[derive(Deserialize)]
+struct CompletionUsage {
+    usage: Option<Usage>,
+    #[serde(default)]
+    model: Option<String>,
+}
+
+/// Token usage a chat completion reports.
+#[derive(Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+    #[serde(default)]
+    prompt_tokens_details: Option<PromptTokensDetails>,
+    #[serde(default)]
+    completion_tokens_details: Option<CompletionTokensDetails>,
+}
+
+#[derive(Deserialize)]
+struct PromptTokensDetails {
+    #[serde(default)]
+    cached_tokens: u64,
+}
+
+#[derive(Deserialize)]
+struct CompletionTokensDetails {
+    #[serde(default)]
+    reasoning_tokens: u64,
+}
+
+impl Usage {
+    /// Prompt tokens served from the provider's prompt cache.
+    pub fn cached_tokens(&self) -> u64 {
+        self.prompt_tokens_details
+            .as_ref()
+            .map_or(0, |details| details.cached_tokens)
+    }
+
+    /// Completion tokens spent reasoning before the reply.
+    pub fn reasoning_tokens(&self) -> u64 {
+        self.completion_tokens_details
+            .as_ref()
+            .map_or(0, |details| details.reasoning_tokens)
+    }
+}
+
+/// Usage reported in a chat completion response body, None when it has none.
+pub fn response_usage(body: &str) -> Option<Usage> {
+    serde_json::from_str::<CompletionUsage>(body).ok()?.usage
+}
+
+/// Prompt caching report for the most recent inference call.
+#[pyclass(frozen)]
+#[derive(Clone, Default)]
+pub struct CacheMetrics {
+    #[pyo3(get)]
+    pub requests: u64,
+    #[pyo3(get)]
+    pub prompt_tokens: u64,
+    #[pyo3(get)]
+    pub cached_tokens: u64,
+    #[pyo3(get)]
+    pub completion_tokens: u64,
+    // Estimated from the pricing table, responses of unpriced models add nothing
+    #[pyo3(get)]
+    pub cost_usd: f64,
+    // Prompt tokens not billed at full price, from the cached input discount
+    // of each response's model. Unpriced models add nothing.
+    #[pyo3(get)]
+    pub billed_tokens_saved: f64,
+}
+
+#[pymethods]
+impl CacheMetrics {
+    /// Fraction of prompt tokens served from the provider's prompt cache.
+    #[getter]
+    fn hit_rate(&self) -> f64 {
+        if self.prompt_tokens == 0 {
+            return 0.0;
+        }
+        self.cached_tokens as f64 / self.prompt_tokens as f64
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "CacheMetrics(requests={}, prompt_tokens={}, cached_tokens={}, hit_rate={:.3})",
+            self.requests,
+            self.prompt_tokens,
+            self.cached_tokens,
+            self.hit_rate()
+        )
+    }
+}
+
+/// Performance report for the most recent inference call.
+#[pyclass(frozen)]
+#[derive(Clone)]
+pub struct RunStats {
+    #[pyo3(get)]
+    pub rows: u64,
+    // Requests that got to the provider, answered or not
+    #[pyo3(get)]
+    pub requests: u64,
+    #[pyo3(get)]
+    pub retries: u64,
+    #[pyo3(get)]
+    pub elapsed_s: f64,
+    #[pyo3(get)]
+    pub rows_per_sec: f64,
+    #[pyo3(get)]
+    pub latency_p50_ms: Option<f64>,
+    #[pyo3(get)]
+    pub latency_p95_ms: Option<f64>,
+    #[pyo3(get)]
+    pub latency_p99_ms: Option<f64>,
+    // Failed rows by error type, e.g. http_429, connection_error, budget_exceeded
+    #[pyo3(get)]
+    pub errors: BTreeMap<String, u64>,
+    // Lowest quota left in the rate limit headers of the responses
+    #[pyo3(get)]
+    pub rate_limit_remaining_requests: Option<u64>,
+    #[pyo3(get)]
+    pub rate_limit_remaining_tokens: Option<u64>,
+}
+
+#[pymethods]
+impl RunStats {
+    fn __repr__(&self) -> String {
+        let ms = |latency: Option<f64>| latency.map_or("None".to_string(), |l| format!("{:.1}", l));
+        format!(
+            "RunStats(rows={}, requests={}, retries={}, rows_per_sec={:.2}, latency_p50_ms={}, latency_p95_ms={}, latency_p99_ms={}, errors={:?})",
+            self.rows,
+            self.requests,
+            self.retries,
+            self.rows_per_sec,
+            ms(self.latency_p50_ms),
+            ms(self.latency_p95_ms),
+            ms(self.latency_p99_ms),
+            self.errors
+        )
+    }
+}
+
+struct RunLog {
+    started: Instant,
+    // When the last row completed
+    finished: Option<Instant>,
+    rows: u64,
+    latencies_ms: Vec<f64>,
+    retries: u64,
+    errors: BTreeMap<String, u64>,
+    remaining_requests: Option<u64>,
+    remaining_tokens: Option<u64>,
+}
+
+impl RunLog {
+    fn new() -> Self {
+        RunLog {
+            started: Instant::now(),
+            finished: None,
+            rows: 0,
+            latencies_ms: Vec::new(),
+            retries: 0,
+            errors: BTreeMap::new(),
+            remaining_requests: None,
+            remaining_tokens: None,
+        }
+    }
+
+    fn stats(&self) -> RunStats {
+        let elapsed_s = self
+            .finished
+            .map_or(0.0, |f| f.duration_since(self.started).as_secs_f64());
+        let mut latencies = self.latencies_ms.clone();
+        latencies.sort_by(f64::total_cmp);
+        RunStats {
+            rows: self.rows,
+            requests: latencies.len() as u64,
+            retries: self.retries,
+            elapsed_s,
+            rows_per_sec: if elapsed_s > 0.0 {
+                self.rows as f64 / elapsed_s
+            } else {
+                0.0
+            },
+            latency_p50_ms: percentile(&latencies, 0.5),
+            latency_p95_ms: percentile(&latencies, 0.95),
+            latency_p99_ms: percentile(&latencies, 0.99),
+            errors: self.errors.clone(),
+            rate_limit_remaining_requests: self.remaining_requests,
+            rate_limit_remaining_tokens: self.remaining_tokens,
+        }
+    }
+}
+
+// Nearest-rank percentile of sorted values
+fn percentile(sorted: &[f64], p: f64) -> Option<f64> {
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted.get(rank.max(1) - 1).copied()
+}
+
+/// Starts the metrics of a new inference call, which the usage ledger
+/// also numbers its requests by.
+pub fn reset() {
+    *LAST_RUN.lock().unwrap() = CacheMetrics::default();
+    *LAST_LOG.lock().unwrap() = RunLog::new();
+    ledger::start_call();
+}
+
+/// Records how long a request sent in the current run took.
+pub fn record_latency(latency: Duration) {
+    LAST_LOG
+        .lock()
+        .unwrap()
+        .latencies_ms
+        .push(latency.as_secs_f64() * 1000.0);
+}
+
+/// Records a request of the current run being sent again.
+pub fn record_retry() {
+    LAST_LOG.lock().unwrap().retries += 1;
+}
+
+/// Records a failure of the current run by its type.
+pub fn record_error(kind: &str) {
+    *LAST_LOG
+        .lock()
+        .unwrap()
+        .errors
+        .entry(kind.to_string())
+        .or_default() += 1;
+}
+
+/// Records the requests and tokens a response says are left in the
+/// provider's rate limits, keeping the lowest of the current run.
+pub fn record_rate_limits(requests: Option<u64>, tokens: Option<u64>) {
+    let lowest = |seen: Option<u64>, new: Option<u64>| match (seen, new) {
+        (Some(seen), Some(new)) => Some(seen.min(new)),
+        _ => seen.or(new),
+    };
+    let mut log = LAST_LOG.lock().unwrap();
+    log.remaining_requests = lowest(log.remaining_requests, requests);
+    log.remaining_tokens = lowest(log.remaining_tokens, tokens);
+}
+
+/// Records `rows` rows of the current run completing.
+pub fn record_rows(rows: usize) {
+    let mut log = LAST_LOG.lock().unwrap();
+    log.rows += rows as u64;
+    log.finished = Some(Instant::now());
+}
+
+/// Tokens, estimated cost and retries one expression call has spent, which
+/// its budgets are checked against. Every call has its own, so calls running
+/// at once, such as expressions on several columns, do not count each other's.
+#[derive(Default)]
+pub struct CallUsage {
+    spent: Mutex<(u64, f64)>,
+    retries: Mutex<u64>,
+}
+
+impl CallUsage {
+    /// Adds the usage reported in a chat completion response body.
+    pub fn record_response(&self, body: &str) {
+        let Some(usage) = response_usage(body) else {
+            return;
+        };
+        let cost = response_cost(body, &config().model).unwrap_or(0.0);
+        let mut spent = self.spent.lock().unwrap();
+        spent.0 += usage.prompt_tokens + usage.completion_tokens;
+        spent.1 += cost;
+    }
+
+    /// Tokens and estimated cost spent so far.
+    pub fn spent(&self) -> (u64, f64) {
+        *self.spent.lock().unwrap()
+    }
+
+    /// Counts one more retry, unless the call has already made `budget`.
+    /// Returns whether the retry may go ahead.
+    pub fn take_retry(&self, budget: Option<u64>) -> bool {
+        let mut retries = self.retries.lock().unwrap();
+        if budget.is_some_and(|budget| *retries >= budget) {
+            return false;
+        }
+        *retries += 1;
+        true
+    }
+}
+
+/// Adds the usage reported in a chat completion response body to the current run.
+pub fn record_response(body: &str) {
+    let Ok(CompletionUsage {
+        usage: Some(usage),
+        model,
+    }) = serde_json::from_str::<CompletionUsage>(body)
+    else {
+        return;
+    };
+    let cached = usage.cached_tokens();
+    let model = model.unwrap_or_else(|| config().model.clone());
+
+    let cost = response_cost(body, &model).unwrap_or(0.0);
+    let saved = cached_tokens_saved(&model, cached).unwrap_or(0.0);
+
+    let mut metrics = LAST_RUN.lock().unwrap();
+    metrics.requests += 1;
+    metrics.prompt_tokens += usage.prompt_tokens;
+    metrics.cached_tokens += cached;
+    metrics.completion_tokens += usage.completion_tokens;
+    metrics.cost_usd += cost;
+    metrics.billed_tokens_saved += saved;
+}
+
+/// Returns the prompt caching report of the most recent inference call.
+#[pyfunction]
+pub fn cache_metrics() -> CacheMetrics {
+    LAST_RUN.lock().unwrap().clone()
+}
+
+/// Returns latency percentiles, throughput, retries and errors by type of
+/// the most recent inference call.
+#[pyfunction]
+pub fn last_run_stats() -> RunStats {
+    LAST_LOG.lock().unwrap().stats()
+}
@@ -0,0 +1,118 @@
+use crate::checkpoint::request_hash;
+use crate::stream;
+use once_cell::sync::Lazy;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use serde_json::{json, Value};
+use std::sync::RwLock;
+use std::time::Duration;
+
+static MOCK: Lazy<RwLock<MockSettings>> = Lazy::new(|| RwLock::new(MockSettings::default()));
+
+/// Behaviour of `Provider::Mock`.
+#[derive(Clone)]
+pub struct MockSettings {
+    // `{content}` is replaced by the last message content, `{model}` by the model
+    pub template: String,
+    pub latency: Duration,
+    // Fraction of prompts that fail, the same prompts fail on every run
+    pub failure_rate: f64,
+}
+
+impl Default for MockSettings {
+    fn default() -> Self {
+        MockSettings {
+            template: "Mock response to: {content}".to_string(),
+            latency: Duration::ZERO,
+            failure_rate: 0.0,
+        }
+    }
+}
+
+/// Configures the responses of the `mock` provider.
+#[pyfunction]
+#[pyo3(signature = (template="Mock response to: {content}", latency_ms=0, failure_rate=0.0))]
+pub fn configure_mock(template: &str, latency_ms: u64, failure_rate: f64) -> PyResult<()> {
+    if !(0.0..=1.0).contains(&failure_rate) {
+        return Err(PyValueError::new_err(
+            "failure_rate must be between 0 and 1",
+        ));
+    }
+    *MOCK.write().unwrap() = MockSettings {
+        template: template.to_string(),
+        latency: Duration::from_millis(latency_ms),
+        failure_rate,
+    };
+    Ok(())
+}
+
+// Maps the request to [0, 1) so failures are deterministic per request
+fn failure_draw(body: &str) -> f64 {
+    let hash = request_hash(body);
+    let prefix = u32::from_str_radix(&hash[..8], 16).unwrap_or(0);
+    prefix as f64 / (u32::MAX as f64 + 1.0)
+}
+
+fn completion(body: &str, settings: &MockSettings) -> Option<String> {
+    if failure_draw(body) < settings.failure_rate {
+        return None;
+    }
+
+    let request: Value = serde_json::from_str(body).ok()?;
+    let model = request["model"].as_str().unwrap_or_default();
+    let content = request["messages"]
+        .as_array()
+        .and_then(|messages| messages.last())
+        .and_then(|message| message["content"].as_str())
+        .unwrap_or_default();
+    let answer = settings
+        .template
+        .replace("{content}", content)
+        .replace("{model}", model);
+
+    // Rough token counts so usage-based reporting has something to work with
+    let prompt_tokens = body.len() / 4;
+    let completion_tokens = answer.len() / 4;
+    let response = json!({
+        "id": format!("mock-{}", &request_hash(body)[..12]),
+        "object": "chat.completion",
+        "created": 0,
+        "model": model,
+        "choices": [{
+            "index": 0,
+            "message": {"role": "assistant", "content": answer},
+            "finish_reason": "stop"
+        }],
+        "usage": {
+            "prompt_tokens": prompt_tokens,
+            "completion_tokens": completion_tokens,
+            "total_tokens": prompt_tokens + completion_tokens
+        }
+    });
+    Some(response.to_string())
+}
+
+/// Answers a chat completion request body without touching the network.
+pub async fn respond(body: &str, stream_row: Option<usize>) -> Option<String> {
+    let settings = MOCK.read().unwrap().clone();
+    if !settings.latency.is_zero() {
+        tokio::time::sleep(settings.latency).await;
+    }
+    let response = completion(body, &settings)?;
+    if let Some(row) = stream_row {
+        let parsed: Value = serde_json::from_str(&response).ok()?;
+        if let Some(content) = parsed["choices"][0]["message"]["content"].as_str() {
+            stream::emit(row, content);
+        }
+    }
+    Some(response)
+}
+
+/// Blocking version of `respond`, for the synchronous expression.
+pub fn respond_sync(body: &str) -> Option<String> {
+    let settings = MOCK.read().unwrap().clone();
+    if !settings.latency.is_zero() {
+        std::thread::sleep(settings.latency);
+    }
+    completion(body, &settings)
+}
@@ -0,0 +1,152 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Deserialize;
+
+/// Kind of personal information `redact_pii` looks for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PiiKind {
+    Email,
+    CreditCard,
+    Ssn,
+    Phone,
+}
+
+impl PiiKind {
+    // In order of precedence, the first kind to claim some text keeps it
+    pub const ALL: [PiiKind; 4] = [
+        PiiKind::Email,
+        PiiKind::CreditCard,
+        PiiKind::Ssn,
+        PiiKind::Phone,
+    ];
+
+    /// Name used in the mask and the detected spans.
+    pub fn label(&self) -> &'static str {
+        match self {
+            PiiKind::Email => "EMAIL",
+            PiiKind::CreditCard => "CREDIT_CARD",
+            PiiKind::Ssn => "SSN",
+            PiiKind::Phone => "PHONE",
+        }
+    }
+
+    fn pattern(&self) -> &'static Regex {
+        match self {
+            PiiKind::Email => &EMAIL,
+            PiiKind::CreditCard => &CREDIT_CARD,
+            PiiKind::Ssn => &SSN,
+            PiiKind::Phone => &PHONE,
+        }
+    }
+}
+
+static EMAIL: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}").unwrap());
+static CREDIT_CARD: Lazy<Regex> = Lazy::new(|| Regex::new(r"\b(?:\d[ -]?){12,18}\d\b").unwrap());
+static SSN: Lazy<Regex> = Lazy::new(|| Regex::new(r"\b\d{3}-\d{2}-\d{4}\b").unwrap());
+static PHONE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?:\+\d{1,3}[\s.-]?)?(?:\(\d{2,4}\)\s?|\b\d{2,4}[\s.-]?)\d{3,4}[\s.-]?\d{3,4}\b")
+        .unwrap()
+});
+
+// Card numbers carry a Luhn check digit, which rules out most other digit runs
+fn luhn_valid(number: &str) -> bool {
+    let digits: Vec<u32> = number.chars().filter_map(|c| c.to_digit(10)).collect();
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| match (i % 2, d * 2) {
+            (0, _) => d,
+            (_, doubled) if doubled > 9 => doubled - 9,
+            (_, doubled) => doubled,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+// Whether the characters leaving a match go on with another digit group
+fn continues_digits(mut chars: impl Iterator<Item = char>) -> bool {
+    match chars.next() {
+        Some(' ' | '-' | '.') => chars.next().is_some_and(|c| c.is_ascii_digit()),
+        Some(c) => c.is_ascii_digit(),
+        None => false,
+    }
+}
+
+// Whether a match is only part of a longer run of digit groups, such as a
+// card number failing the Luhn check, rather than a number of its own
+fn inside_digit_run(text: &str, start: usize, end: usize) -> bool {
+    continues_digits(text[end..].chars()) || continues_digits(text[..start].chars().rev())
+}
+
+/// Personal information found in a text, with byte offsets.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PiiSpan {
+    pub kind: PiiKind,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Finds the personal information of `kinds` in a text, as
+/// non-overlapping spans in text order.
+pub fn detect_pii(text: &str, kinds: &[PiiKind]) -> Vec<PiiSpan> {
+    let mut spans: Vec<PiiSpan> = Vec::new();
+    for kind in PiiKind::ALL.iter().filter(|k| kinds.contains(k)) {
+        for found in kind.pattern().find_iter(text) {
+            if *kind == PiiKind::CreditCard && !luhn_valid(found.as_str()) {
+                continue;
+            }
+            if *kind == PiiKind::Phone && inside_digit_run(text, found.start(), found.end()) {
+                continue;
+            }
+            let overlaps = spans
+                .iter()
+                .any(|s| found.start() < s.end && s.start < found.end());
+            if !overlaps {
+                spans.push(PiiSpan {
+                    kind: *kind,
+                    start: found.start(),
+                    end: found.end(),
+                });
+            }
+        }
+    }
+    spans.sort_by_key(|s| s.start);
+    spans
+}
+
+/// Replaces every span with its kind's label in brackets, e.g. `[EMAIL]`.
+pub fn redact(text: &str, spans: &[PiiSpan]) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut last = 0;
+    for span in spans {
+        out.push_str(&text[last..span.start]);
+        out.push('[');
+        out.push_str(span.kind.label());
+        out.push(']');
+        last = span.end;
+    }
+    out.push_str(&text[last..]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn card_numbers_must_pass_the_luhn_check() {
+        assert!(luhn_valid("4111 1111 1111 1111"));
+        assert!(luhn_valid("5500-0000-0000-0004"));
+        assert!(!luhn_valid("4111 1111 1111 1112"));
+    }
+
+    #[test]
+    fn digit_runs_failing_the_luhn_check_are_not_cards_or_phones() {
+        let text = "order 4111 1111 1111 1112 shipped";
+
+        assert_eq!(detect_pii(text, &PiiKind::ALL), []);
+    }
+}
@@ -0,0 +1,101 @@
+use crate::structured::strip_code_fences;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+
+#[derive(Deserialize)]
+struct PostProcessSpec {
+    #[serde(default)]
+    strip_fences: bool,
+    #[serde(default)]
+    trim: bool,
+    #[serde(default)]
+    extract_tag: Option<String>,
+    #[serde(default)]
+    extract_pattern: Option<String>,
+}
+
+/// Clean-up applied to every reply before it is returned: code fences
+/// stripped, the answer extracted, whitespace trimmed, in that order.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "PostProcessSpec")]
+pub struct PostProcess {
+    strip_fences: bool,
+    trim: bool,
+    // Only the text between <tag> and </tag> is kept
+    extract_tag: Option<String>,
+    // Only the first capture group, or the whole match without groups, is kept
+    extract_pattern: Option<Regex>,
+}
+
+impl TryFrom<PostProcessSpec> for PostProcess {
+    type Error = regex::Error;
+
+    fn try_from(spec: PostProcessSpec) -> Result<Self, Self::Error> {
+        Ok(PostProcess {
+            strip_fences: spec.strip_fences,
+            trim: spec.trim,
+            extract_tag: spec.extract_tag,
+            extract_pattern: spec
+                .extract_pattern
+                .as_deref()
+                .map(Regex::new)
+                .transpose()?,
+        })
+    }
+}
+
+/// Text between the first `<tag>` in `text` and the `</tag>` after it, or
+/// the end of the text when the model left the tag open.
+pub fn tag_content<'a>(text: &'a str, tag: &str) -> Option<&'a str> {
+    let (_, after) = text.split_once(format!("<{}>", tag).as_str())?;
+    Some(
+        after
+            .split_once(format!("</{}>", tag).as_str())
+            .map_or(after, |(inner, _)| inner),
+    )
+}
+
+impl PostProcess {
+    /// `reply` cleaned up. Extractions that find nothing leave the reply
+    /// as it was.
+    pub fn apply(&self, reply: &str) -> String {
+        let mut reply = reply;
+        if self.strip_fences {
+            reply = strip_code_fences(reply);
+        }
+        if let Some(inner) = self
+            .extract_tag
+            .as_deref()
+            .and_then(|t| tag_content(reply, t))
+        {
+            reply = inner;
+        }
+        if let Some(pattern) = &self.extract_pattern {
+            if let Some(captures) = pattern.captures(reply) {
+                if let Some(found) = captures.get(1).or_else(|| captures.get(0)) {
+                    reply = found.as_str();
+                }
+            }
+        }
+        if self.trim {
+            reply = reply.trim();
+        }
+        reply.to_string()
+    }
+
+    /// A chat completion `response` with the content of its reply cleaned up.
+    pub fn apply_to_response(&self, response: String) -> String {
+        let Ok(mut parsed) = serde_json::from_str::<Value>(&response) else {
+            return response;
+        };
+        let Some(content) = parsed.pointer_mut("/choices/0/message/content") else {
+            return response;
+        };
+        let Some(reply) = content.as_str() else {
+            return response;
+        };
+        *content = Value::String(self.apply(reply));
+        parsed.to_string()
+    }
+}
@@ -0,0 +1,165 @@
+use once_cell::sync::Lazy;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// USD per million tokens.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ModelPrice {
+    pub input: f64,
+    // Prompt tokens served from the prompt cache
+    pub cached_input: f64,
+    pub output: f64,
+}
+
+const fn price(input: f64, cached_input: f64, output: f64) -> ModelPrice {
+    ModelPrice {
+        input,
+        cached_input,
+        output,
+    }
+}
+
+// List prices of OpenAI models, dated snapshots match their model's prefix
+const DEFAULT_PRICES: &[(&str, ModelPrice)] = &[
+    ("gpt-5", price(1.25, 0.125, 10.0)),
+    ("gpt-5-mini", price(0.25, 0.025, 2.0)),
+    ("gpt-5-nano", price(0.05, 0.005, 0.4)),
+    ("gpt-4.1", price(2.0, 0.5, 8.0)),
+    ("gpt-4.1-mini", price(0.4, 0.1, 1.6)),
+    ("gpt-4.1-nano", price(0.1, 0.025, 0.4)),
+    ("gpt-4o", price(2.5, 1.25, 10.0)),
+    ("gpt-4o-mini", price(0.15, 0.075, 0.6)),
+    ("gpt-4-turbo", price(10.0, 10.0, 30.0)),
+    ("gpt-3.5-turbo", price(0.5, 0.5, 1.5)),
+    ("o1", price(15.0, 7.5, 60.0)),
+    ("o1-mini", price(1.1, 0.55, 4.4)),
+    ("o3", price(2.0, 0.5, 8.0)),
+    ("o3-mini", price(1.1, 0.55, 4.4)),
+    ("o4-mini", price(1.1, 0.275, 4.4)),
+    ("text-embedding-3-small", price(0.02, 0.02, 0.0)),
+    ("text-embedding-3-large", price(0.13, 0.13, 0.0)),
+    ("text-embedding-ada-002", price(0.1, 0.1, 0.0)),
+];
+
+static OVERRIDES: Lazy<RwLock<HashMap<String, ModelPrice>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Sets the price of a model in USD per million tokens, replacing the
+/// built-in one. Cached input defaults to the input price.
+#[pyfunction]
+#[pyo3(signature = (model, input, output, cached_input=None))]
+pub fn set_model_price(
+    model: &str,
+    input: f64,
+    output: f64,
+    cached_input: Option<f64>,
+) -> PyResult<()> {
+    let cached_input = cached_input.unwrap_or(input);
+    if [input, output, cached_input].iter().any(|p| *p < 0.0) {
+        return Err(PyValueError::new_err("prices cannot be negative"));
+    }
+    OVERRIDES
+        .write()
+        .unwrap()
+        .insert(model.to_string(), price(input, cached_input, output));
+    Ok(())
+}
+
+/// Whether `model` is `name` or one of its dated snapshots, e.g.
+/// gpt-4o-2024-08-06 for gpt-4o.
+pub fn matches_model(model: &str, name: &str) -> bool {
+    model == name
+        || model
+            .strip_prefix(name)
+            .is_some_and(|rest| rest.starts_with('-'))
+}
+
+/// Price of `model`, from the overrides or the built-in table. Dated
+/// snapshots such as gpt-4o-2024-08-06 get the price of the longest
+/// model name they start with.
+pub fn model_price(model: &str) -> Option<ModelPrice> {
+    let model = model.rsplit('/').next().unwrap_or(model);
+    let overrides = OVERRIDES.read().unwrap();
+    if let Some(price) = overrides.get(model) {
+        return Some(*price);
+    }
+    let matches = |name: &str| matches_model(model, name);
+    let overridden = overrides
+        .iter()
+        .filter(|(name, _)| matches(name))
+        .map(|(name, price)| (name.as_str(), *price));
+    let built_in = DEFAULT_PRICES
+        .iter()
+        .filter(|(name, _)| matches(name))
+        .map(|(name, price)| (*name, *price));
+    overridden
+        .chain(built_in)
+        .max_by_key(|(name, _)| name.len())
+        .map(|(_, price)| price)
+}
+
+/// Cost in USD of a request's tokens.
+pub fn usage_cost(
+    model: &str,
+    prompt_tokens: u64,
+    cached_tokens: u64,
+    completion_tokens: u64,
+) -> Option<f64> {
+    let price = model_price(model)?;
+    let uncached = prompt_tokens.saturating_sub(cached_tokens);
+    let cost = uncached as f64 * price.input
+        + cached_tokens as f64 * price.cached_input
+        + completion_tokens as f64 * price.output;
+    Some(cost / 1_000_000.0)
+}
+
+/// Prompt tokens' worth of input price `cached_tokens` did not cost, from
+/// the cached input discount of `model`.
+pub fn cached_tokens_saved(model: &str, cached_tokens: u64) -> Option<f64> {
+    let price = model_price(model)?;
+    if price.input <= 0.0 {
+        return Some(0.0);
+    }
+    Some(cached_tokens as f64 * (1.0 - price.cached_input / price.input))
+}
+
+// Price of requests served on OpenAI's flex tier relative to the standard one
+const FLEX_PRICE: f64 = 0.5;
+
+/// Cost in USD of a chat completion response, from its usage and model,
+/// or `model` when the response does not name one. Responses served on the
+/// flex tier cost half.
+pub fn response_cost(response: &str, model: &str) -> Option<f64> {
+    let response: Value = serde_json::from_str(response).ok()?;
+    let usage = &response["usage"];
+    let model = response["model"].as_str().unwrap_or(model);
+    let tier = match response["service_tier"].as_str() {
+        Some("flex") => FLEX_PRICE,
+        _ => 1.0,
+    };
+    let cost = usage_cost(
+        model,
+        usage["prompt_tokens"].as_u64()?,
+        usage["prompt_tokens_details"]["cached_tokens"]
+            .as_u64()
+            .unwrap_or(0),
+        usage["completion_tokens"].as_u64().unwrap_or(0),
+    )?;
+    Some(cost * tier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cached_tokens_save_the_discount_of_their_model() {
+        assert_eq!(cached_tokens_saved("gpt-4o-2024-08-06", 1000), Some(500.0));
+        assert_eq!(cached_tokens_saved("gpt-4.1", 1000), Some(750.0));
+        assert_eq!(cached_tokens_saved("gpt-4-turbo", 1000), Some(0.0));
+        assert_eq!(cached_tokens_saved("unpriced-model", 1000), None);
+    }
+}
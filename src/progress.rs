@@ -0,0 +1,145 @@
+use once_cell::sync::Lazy;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use serde_json::Value;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+// Called with a `Progress` while a batch of requests runs, at most once per interval
+static PROGRESS_CALLBACK: Lazy<RwLock<Option<(PyObject, Duration)>>> =
+    Lazy::new(|| RwLock::new(None));
+
+/// How far a batch of requests has got.
+#[pyclass(frozen)]
+#[derive(Clone)]
+pub struct Progress {
+    #[pyo3(get)]
+    pub completed: usize,
+    #[pyo3(get)]
+    pub total: usize,
+    // Rows whose request failed or came back as an error response
+    #[pyo3(get)]
+    pub errors: usize,
+    #[pyo3(get)]
+    pub elapsed: f64,
+    // Seconds left at the rate so far, unknown until a row has completed
+    #[pyo3(get)]
+    pub remaining: Option<f64>,
+}
+
+#[pymethods]
+impl Progress {
+    fn __repr__(&self) -> String {
+        format!(
+            "Progress(completed={}, total={}, errors={}, elapsed={:.1})",
+            self.completed, self.total, self.errors, self.elapsed
+        )
+    }
+}
+
+/// Sets the function called with a `Progress` as the rows of a batch
+/// complete, at most every `interval` seconds and once more when the batch
+/// is done. Pass `None` to remove it.
+#[pyfunction]
+#[pyo3(signature = (callback=None, interval=1.0))]
+pub fn set_progress_callback(callback: Option<PyObject>, interval: f64) -> PyResult<()> {
+    if !interval.is_finite() || interval < 0.0 {
+        return Err(PyValueError::new_err(
+            "interval must be a positive number of seconds",
+        ));
+    }
+    *PROGRESS_CALLBACK.write().unwrap() =
+        callback.map(|cb| (cb, Duration::from_secs_f64(interval)));
+    Ok(())
+}
+
+struct Counts {
+    completed: usize,
+    errors: usize,
+    last_report: Option<Instant>,
+}
+
+/// Counts the rows of one batch as they complete and reports to the
+/// progress callback.
+pub struct Tracker {
+    total: usize,
+    started: Instant,
+    counts: Mutex<Counts>,
+}
+
+impl Tracker {
+    /// Starts tracking a batch of `total` rows, `None` when no callback is set.
+    pub fn start(total: usize) -> Option<Tracker> {
+        PROGRESS_CALLBACK.read().unwrap().as_ref()?;
+        Some(Tracker {
+            total,
+            started: Instant::now(),
+            counts: Mutex::new(Counts {
+                completed: 0,
+                errors: 0,
+                last_report: None,
+            }),
+        })
+    }
+
+    /// Counts `rows` rows completed with the same response, null when the
+    /// request failed.
+    pub fn rows_done(&self, response: Option<&str>, rows: usize) {
+        let failed = response
+            .and_then(|r| serde_json::from_str::<Value>(r).ok())
+            .is_none_or(|r| r.get("error").is_some());
+        let progress = {
+            let mut counts = self.counts.lock().unwrap();
+            counts.completed += rows;
+            if failed {
+                counts.errors += rows;
+            }
+            let due = match counts.last_report {
+                Some(last) => last.elapsed() >= interval(),
+                None => true,
+            };
+            if !due && counts.completed < self.total {
+                return;
+            }
+            counts.last_report = Some(Instant::now());
+            self.progress(&counts)
+        };
+        report(progress);
+    }
+
+    fn progress(&self, counts: &Counts) -> Progress {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let remaining = (counts.completed > 0)
+            .then(|| elapsed / counts.completed as f64 * (self.total - counts.completed) as f64);
+        Progress {
+            completed: counts.completed,
+            total: self.total,
+            errors: counts.errors,
+            elapsed,
+            remaining,
+        }
+    }
+}
+
+fn interval() -> Duration {
+    PROGRESS_CALLBACK
+        .read()
+        .unwrap()
+        .as_ref()
+        .map_or(Duration::ZERO, |(_, interval)| *interval)
+}
+
+fn report(progress: Progress) {
+    Python::with_gil(|py| {
+        // Release the lock before calling so the callback may replace itself
+        let callback = PROGRESS_CALLBACK
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|(cb, _)| cb.clone_ref(py));
+        if let Some(callback) = callback {
+            // A failing callback must not fail the batch
+            let _ = callback.call1(py, (progress,));
+        }
+    });
+}
@@ -0,0 +1,130 @@
+use pyo3::basic::CompareOp;
+use pyo3::prelude::*;
+use pyo3::types::{PyString, PyType};
+use serde::Deserialize;
+
+// Declares the providers once, with the name each goes by in the `provider`
+// kwarg, so the enum, `Provider::ALL` and the Python class cannot drift apart
+macro_rules! providers {
+    ($($(#[$attr:meta])* $variant:ident => $name:literal,)*) => {
+        /// Backend that serves the requests of an expression.
+        #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize)]
+        pub enum Provider {
+            $($(#[$attr])* #[serde(rename = $name)] $variant,)*
+        }
+
+        impl Provider {
+            /// Every provider, in declaration order.
+            pub const ALL: &'static [Provider] = &[$(Provider::$variant,)*];
+
+            /// Name used for API keys, matching the `provider` kwarg.
+            pub fn as_str(&self) -> &'static str {
+                match self {
+                    $(Provider::$variant => $name,)*
+                }
+            }
+        }
+    };
+}
+
+providers! {
+    #[default]
+    OpenAI => "openai",
+    // Canned responses without any network, see `mock::configure_mock`
+    Mock => "mock",
+    // Embedding and reranking providers
+    Cohere => "cohere",
+    Voyage => "voyage",
+    Gemini => "gemini",
+    Mistral => "mistral",
+    Jina => "jina",
+    // ONNX embedding models run in process, needs the local-embeddings feature
+    Local => "local",
+}
+
+impl Provider {
+    /// Provider named `name`, as in the `provider` kwarg.
+    pub fn from_name(name: &str) -> Option<Provider> {
+        serde_json::from_value(serde_json::Value::String(name.to_lowercase())).ok()
+    }
+
+    pub fn supports_chat(&self) -> bool {
+        matches!(self, Provider::OpenAI | Provider::Mock)
+    }
+}
+
+/// A provider as seen from Python, e.g. `Provider.OPENAI`. Equal to, and
+/// hashed like, its name, so it can be passed wherever a name is expected.
+#[pyclass(frozen, name = "Provider")]
+#[derive(Clone, Copy)]
+pub struct PyProvider(Provider);
+
+#[pymethods]
+impl PyProvider {
+    #[new]
+    fn py_new(name: &str) -> PyResult<Self> {
+        Provider::from_name(name).map(PyProvider).ok_or_else(|| {
+            pyo3::exceptions::PyValueError::new_err(format!("unknown provider: {}", name))
+        })
+    }
+
+    /// Every provider.
+    #[classmethod]
+    fn list(_cls: &Bound<'_, PyType>) -> Vec<PyProvider> {
+        Provider::ALL.iter().copied().map(PyProvider).collect()
+    }
+
+    #[getter]
+    fn name(&self) -> &'static str {
+        self.0.as_str()
+    }
+
+    #[getter]
+    fn supports_chat(&self) -> bool {
+        self.0.supports_chat()
+    }
+
+    fn __richcmp__(&self, other: &Bound<'_, PyAny>, op: CompareOp, py: Python<'_>) -> PyObject {
+        let other = match other.extract::<PyProvider>() {
+            Ok(other) => Some(other.0),
+            Err(_) => other
+                .extract::<String>()
+                .ok()
+                .and_then(|name| Provider::from_name(&name)),
+        };
+        match (op, other) {
+            (CompareOp::Eq, Some(other)) => (self.0 == other).into_py(py),
+            (CompareOp::Ne, Some(other)) => (self.0 != other).into_py(py),
+            (CompareOp::Eq, None) => false.into_py(py),
+            (CompareOp::Ne, None) => true.into_py(py),
+            _ => py.NotImplemented(),
+        }
+    }
+
+    fn __hash__(&self, py: Python<'_>) -> PyResult<isize> {
+        PyString::new_bound(py, self.0.as_str()).hash()
+    }
+
+    fn __str__(&self) -> &'static str {
+        self.0.as_str()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Provider.{}", self.0.as_str().to_uppercase())
+    }
+}
+
+/// Adds the `Provider` class to the module, with a class attribute such as
+/// `Provider.OPENAI` for every provider.
+#[allow(deprecated)]
+pub fn register(m: &PyModule) -> PyResult<()> {
+    m.add_class::<PyProvider>()?;
+    let class = m.getattr("Provider")?;
+    for provider in Provider::ALL {
+        class.setattr(
+            provider.as_str().to_uppercase().as_str(),
+            PyProvider(*provider).into_py(m.py()),
+        )?;
+    }
+    Ok(())
+}
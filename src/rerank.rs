@@ -0,0 +1,107 @@
+use crate::config::Config;
+use crate::credentials::api_key;
+use crate::provider::Provider;
+use crate::utils::{with_headers, FetchError, RequestOptions};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::json;
+
+/// Reranker parameters, passed through when set.
+#[derive(Default, Clone, Deserialize)]
+pub struct RerankParams {
+    #[serde(default)]
+    pub model: Option<String>,
+    // Only return the best `top_n` documents of each row
+    #[serde(default)]
+    pub top_n: Option<usize>,
+}
+
+// Cohere and Jina answer with `results`, Voyage with `data`
+#[derive(Deserialize)]
+struct RerankResponse {
+    #[serde(alias = "data")]
+    results: Vec<RerankResult>,
+}
+
+#[derive(Deserialize)]
+struct RerankResult {
+    index: usize,
+    relevance_score: f64,
+}
+
+fn default_model(provider: Provider) -> Option<&'static str> {
+    match provider {
+        Provider::Cohere => Some("rerank-v3.5"),
+        Provider::Voyage => Some("rerank-2"),
+        Provider::Jina => Some("jina-reranker-v2-base-multilingual"),
+        _ => None,
+    }
+}
+
+/// Scores `documents` against `query`, returning (document index, relevance
+/// score) pairs from the most to the least relevant.
+pub async fn fetch_rerank(
+    client: &Client,
+    config: &Config,
+    query: &str,
+    documents: &[String],
+    options: &RequestOptions,
+    params: &RerankParams,
+) -> Result<Vec<(usize, f64)>, FetchError> {
+    let provider = options.provider;
+    let model = match (&params.model, default_model(provider)) {
+        (Some(model), _) => model.clone(),
+        (None, Some(model)) => model.to_string(),
+        (None, None) => {
+            return Err(FetchError::Unsupported(format!(
+                "provider {} does not support reranking",
+                provider.as_str()
+            )))
+        }
+    };
+
+    let mut body = json!({"model": model, "query": query, "documents": documents});
+    let url = match provider {
+        Provider::Cohere => "https://api.cohere.com/v2/rerank",
+        Provider::Voyage => "https://api.voyageai.com/v1/rerank",
+        _ => "https://api.jina.ai/v1/rerank",
+    };
+    if let Some(top_n) = params.top_n {
+        match provider {
+            Provider::Voyage => body["top_k"] = json!(top_n),
+            _ => body["top_n"] = json!(top_n),
+        }
+    }
+    if provider == Provider::Jina {
+        body["return_documents"] = json!(false);
+    }
+
+    let request = client
+        .post(url)
+        .timeout(config.timeout())
+        .bearer_auth(api_key(provider.as_str()).map_err(FetchError::NoKey)?);
+    let response = with_headers(request, config, options)
+        .json(&body)
+        .send()
+        .await
+        .map_err(FetchError::Reqwest)?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let message = response
+            .text()
+            .await
+            .unwrap_or_else(|_| "Unknown error".to_string());
+        return Err(FetchError::Http(status.as_u16(), message));
+    }
+
+    let parsed: RerankResponse = response.json().await.map_err(FetchError::Reqwest)?;
+    let mut results: Vec<(usize, f64)> = parsed
+        .results
+        .into_iter()
+        .filter(|r| r.index < documents.len())
+        .map(|r| (r.index, r.relevance_score))
+        .collect();
+    results.sort_by(|a, b| b.1.total_cmp(&a.1));
+    Ok(results)
+}
@@ -0,0 +1,110 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// OpenAI endpoint chat requests are sent to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OpenAIApi {
+    #[default]
+    ChatCompletions,
+    // /v1/responses, which also runs built-in tools such as web_search
+    Responses,
+}
+
+impl OpenAIApi {
+    pub fn path(&self) -> &'static str {
+        match self {
+            OpenAIApi::ChatCompletions => "chat/completions",
+            OpenAIApi::Responses => "responses",
+        }
+    }
+}
+
+/// Translates a chat completions request body to a Responses API one.
+pub fn request_body(chat_body: &str, tools: Option<&Value>) -> Option<String> {
+    let chat: Value = serde_json::from_str(chat_body).ok()?;
+    let mut body = json!({"model": chat["model"], "input": chat["messages"]});
+    for field in [
+        "temperature",
+        "top_p",
+        "user",
+        "safety_identifier",
+        "prompt_cache_key",
+        "service_tier",
+        "store",
+        "metadata",
+    ] {
+        if !chat[field].is_null() {
+            body[field] = chat[field].clone();
+        }
+    }
+    let max_tokens = &chat["max_completion_tokens"];
+    let max_tokens = if max_tokens.is_null() {
+        &chat["max_tokens"]
+    } else {
+        max_tokens
+    };
+    if !max_tokens.is_null() {
+        body["max_output_tokens"] = max_tokens.clone();
+    }
+    if let Some(effort) = chat["reasoning_effort"].as_str() {
+        body["reasoning"] = json!({ "effort": effort });
+    }
+    // Structured outputs move from response_format to text.format
+    let format = &chat["response_format"];
+    if format["type"] == "json_schema" {
+        let mut schema = format["json_schema"].clone();
+        schema["type"] = json!("json_schema");
+        body["text"] = json!({ "format": schema });
+    } else if !format.is_null() {
+        body["text"] = json!({ "format": format });
+    }
+    if let Some(tools) = tools {
+        body["tools"] = tools.clone();
+    }
+    Some(body.to_string())
+}
+
+/// Reshapes a Responses API response like a chat completion, so the rest of
+/// the library reads both the same way. The original `output` items, with
+/// tool calls and citations, are kept under `output`.
+pub fn to_chat_completion(response: &str) -> Option<String> {
+    let response: Value = serde_json::from_str(response).ok()?;
+    let output = response["output"].as_array()?;
+    let text: String = output
+        .iter()
+        .filter(|item| item["type"] == "message")
+        .flat_map(|item| item["content"].as_array().into_iter().flatten())
+        .filter(|part| part["type"] == "output_text")
+        .filter_map(|part| part["text"].as_str())
+        .collect();
+    let usage = &response["usage"];
+    let finish_reason = match response["status"].as_str() {
+        Some("incomplete") => "length",
+        _ => "stop",
+    };
+    Some(
+        json!({
+            "id": response["id"],
+            "object": "chat.completion",
+            "created": response["created_at"],
+            "model": response["model"],
+            "service_tier": response["service_tier"],
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": text},
+                "finish_reason": finish_reason
+            }],
+            "usage": {
+                "prompt_tokens": usage["input_tokens"],
+                "completion_tokens": usage["output_tokens"],
+                "total_tokens": usage["total_tokens"],
+                "prompt_tokens_details": {
+                    "cached_tokens": usage["input_tokens_details"]["cached_tokens"]
+                }
+            },
+            "output": output
+        })
+        .to_string(),
+    )
+}
@@ -0,0 +1,203 @@
+use crate::capabilities::model_capabilities;
+use crate::pricing::usage_cost;
+use crate::structured::StructuredMode;
+use crate::tokens;
+use crate::utils::RequestOptions;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Completion length assumed for pricing when a call sets no max_tokens
+const EXPECTED_COMPLETION_TOKENS: u64 = 500;
+// Weight of the newest request in a model's moving average latency
+const LATENCY_SMOOTHING: f64 = 0.2;
+
+// Moving average latency in milliseconds of each model, over every call
+static LATENCIES: Lazy<Mutex<HashMap<String, f64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteStrategy {
+    // Lowest estimated cost of the row's prompt and completion
+    #[default]
+    Cost,
+    // Lowest recent latency, models without any requests yet are tried first
+    Latency,
+    // A/B test: a `split` fraction of the rows goes to the second candidate
+    Split,
+    // The first candidate serves every row, each is also sent to the second
+    Shadow,
+}
+
+fn default_split() -> f64 {
+    0.5
+}
+
+/// Models a call's rows are spread across, each row going to the best
+/// candidate able to serve it, or to the arms of a model comparison.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Route {
+    pub candidates: Vec<String>,
+    #[serde(default)]
+    pub strategy: RouteStrategy,
+    // Fraction of the rows sent to the second candidate by `Split`
+    #[serde(default = "default_split")]
+    pub split: f64,
+}
+
+/// Where a row is sent.
+pub struct Routed {
+    pub model: String,
+    // a or b for model comparisons
+    pub arm: Option<&'static str>,
+    // Model the row is mirrored to, whose reply is only recorded
+    pub shadow: Option<String>,
+}
+
+impl RouteStrategy {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RouteStrategy::Cost => "cost",
+            RouteStrategy::Latency => "latency",
+            RouteStrategy::Split => "split",
+            RouteStrategy::Shadow => "shadow",
+        }
+    }
+}
+
+// Where a row falls between 0 and 1, the same for the same messages on every run
+fn bucket(messages: &[Value]) -> f64 {
+    let digest = Sha256::digest(Value::from(messages.to_vec()).to_string().as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(bytes) as f64 / u64::MAX as f64
+}
+
+/// Records how long a request to `model` took.
+pub fn observe_latency(model: &str, latency: Duration) {
+    let latency = latency.as_secs_f64() * 1000.0;
+    let mut latencies = LATENCIES.lock().unwrap();
+    latencies
+        .entry(model.to_string())
+        .and_modify(|mean| *mean += LATENCY_SMOOTHING * (latency - *mean))
+        .or_insert(latency);
+}
+
+impl Route {
+    /// Why the route cannot be used, if it cannot.
+    pub fn validate(&self) -> Result<(), String> {
+        match self.strategy {
+            RouteStrategy::Split | RouteStrategy::Shadow if self.candidates.len() != 2 => {
+                Err(format!(
+                    "the {} strategy compares two models, got {} candidates",
+                    self.strategy.as_str(),
+                    self.candidates.len()
+                ))
+            }
+            RouteStrategy::Split if !(0.0..=1.0).contains(&self.split) => {
+                Err("split must be between 0 and 1".to_string())
+            }
+            _ if self.candidates.is_empty() => Err("a route needs candidate models".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Picks the model for a row's `messages`.
+    pub fn choose(&self, messages: &[Value], options: &RequestOptions) -> Result<Routed, String> {
+        self.validate()?;
+        let arm = |index: usize| Routed {
+            model: self.candidates[index].clone(),
+            arm: Some(["a", "b"][index]),
+            shadow: None,
+        };
+        match self.strategy {
+            RouteStrategy::Split => Ok(arm(usize::from(bucket(messages) < self.split))),
+            RouteStrategy::Shadow => Ok(Routed {
+                shadow: Some(self.candidates[1].clone()),
+                ..arm(0)
+            }),
+            RouteStrategy::Cost | RouteStrategy::Latency => {
+                let model = self.best(messages, options)?;
+                Ok(Routed {
+                    model,
+                    arm: None,
+                    shadow: None,
+                })
+            }
+        }
+    }
+
+    // Candidates are skipped when the capability table says they cannot fit
+    // the prompt and completion or do what `options` needs; models missing
+    // from the table are kept
+    fn best(&self, messages: &[Value], options: &RequestOptions) -> Result<String, String> {
+        let needs_vision = messages
+            .iter()
+            .any(|m| m["content"].to_string().contains("\"image_url\""));
+        let completion_tokens = options
+            .max_tokens
+            .map_or(EXPECTED_COMPLETION_TOKENS, u64::from);
+        let able: Vec<(&String, u64)> = self
+            .candidates
+            .iter()
+            .map(|model| {
+                let prompt_tokens = tokens::count_message_tokens(&tokens::encoder(model), messages);
+                (model, prompt_tokens as u64)
+            })
+            .filter(|(model, prompt_tokens)| {
+                let Some(caps) = model_capabilities(model) else {
+                    return true;
+                };
+                prompt_tokens + completion_tokens <= u64::from(caps.context_window)
+                    && (!needs_vision || caps.vision)
+                    && (options.response_format.is_none()
+                        || options.structured_mode != StructuredMode::Native
+                        || caps.structured_output)
+                    && (options.tools.is_none() || caps.tools)
+            })
+            .collect();
+        let chosen = if self.strategy == RouteStrategy::Cost {
+            // Unpriced models are only used when no priced one fits
+            able.into_iter()
+                .map(|(model, prompt_tokens)| {
+                    let cost = usage_cost(model, prompt_tokens, 0, completion_tokens);
+                    (model, cost.unwrap_or(f64::INFINITY))
+                })
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+        } else {
+            let latencies = LATENCIES.lock().unwrap();
+            able.into_iter()
+                .map(|(model, _)| (model, latencies.get(model).copied().unwrap_or(0.0)))
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+        };
+        chosen.map(|(model, _)| model.clone()).ok_or_else(|| {
+            format!(
+                "none of the candidate models {} can serve this row",
+                self.candidates.join(", ")
+            )
+        })
+    }
+
+    /// Adds where a row was sent to its response, as `{"route": {"strategy":
+    /// .., "model": ..}}` with the `arm` of model comparisons and the reply
+    /// of the shadow model, null when its request failed.
+    pub fn tag(&self, response: String, routed: &Routed, shadow_response: Option<&str>) -> String {
+        let Ok(mut parsed) = serde_json::from_str::<Value>(&response) else {
+            return response;
+        };
+        let mut route = json!({"strategy": self.strategy.as_str(), "model": routed.model});
+        if let Some(arm) = routed.arm {
+            route["arm"] = json!(arm);
+        }
+        if let Some(shadow) = &routed.shadow {
+            let response = shadow_response.and_then(|r| serde_json::from_str::<Value>(r).ok());
+            route["shadow"] = json!({"model": shadow, "response": response});
+        }
+        parsed["route"] = route;
+        parsed.to_string()
+    }
+}
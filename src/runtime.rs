@@ -0,0 +1,58 @@
+use crate::config::config;
+use crate::http::rebuild_client;
+use once_cell::sync::Lazy;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::runtime::{Builder, Runtime};
+
+// Thread settings a runtime was built with, as (worker_threads, max_blocking_threads)
+type Threads = (Option<usize>, Option<usize>);
+type SharedRuntime = (Threads, Arc<Runtime>);
+
+// Built on first use and rebuilt when the thread settings in the config change
+static RUNTIME: Lazy<Mutex<Option<SharedRuntime>>> = Lazy::new(|| Mutex::new(None));
+
+fn build(threads: Threads) -> Runtime {
+    let mut builder = Builder::new_multi_thread();
+    builder.enable_all().thread_name("polar-llama");
+    if let Some(workers) = threads.0 {
+        builder.worker_threads(workers.max(1));
+    }
+    if let Some(blocking) = threads.1 {
+        builder.max_blocking_threads(blocking.max(1));
+    }
+    builder.build().expect("Failed to create Tokio runtime")
+}
+
+/// Returns the shared runtime, built with the worker and blocking thread
+/// counts of the current config.
+///
+/// A runtime replaced after a config change is shut down once the calls
+/// still running on it have finished and dropped their handle. The shared
+/// HTTP client is replaced with it, as its pooled connections belong to the
+/// old runtime.
+pub fn runtime() -> Arc<Runtime> {
+    let config = config();
+    let threads = (config.worker_threads, config.max_blocking_threads);
+    let mut runtime = RUNTIME.lock().unwrap();
+    match &*runtime {
+        Some((built, current)) if *built == threads => current.clone(),
+        previous => {
+            if previous.is_some() {
+                rebuild_client();
+            }
+            let current = Arc::new(build(threads));
+            *runtime = Some((threads, current.clone()));
+            current
+        }
+    }
+}
+
+/// Runs `future` to completion on the shared runtime.
+///
+/// The future is driven by the calling thread, so expressions evaluated on
+/// several Polars threads at once each make progress on their own thread
+/// and only share the runtime's workers for I/O and timers.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    runtime().block_on(future)
+}
@@ -0,0 +1,144 @@
+use crate::provider::Provider;
+use serde_json::{json, Map, Value};
+
+// Keywords OpenAI structured outputs reject in strict mode
+const OPENAI_UNSUPPORTED: &[&str] = &[
+    "allOf",
+    "not",
+    "if",
+    "then",
+    "else",
+    "dependentRequired",
+    "dependentSchemas",
+    "patternProperties",
+    "unevaluatedProperties",
+    "unevaluatedItems",
+    "propertyNames",
+    "minProperties",
+    "maxProperties",
+    "contains",
+    "minContains",
+    "maxContains",
+    "uniqueItems",
+];
+
+/// Rewrites a user's response schema into the form `provider` accepts, or
+/// lists the constructs it cannot express.
+pub fn normalize_schema(schema: &Value, provider: Provider) -> Result<Value, String> {
+    match provider {
+        // The mock provider stands in for OpenAI
+        Provider::OpenAI | Provider::Mock => openai_strict(schema),
+        _ => Ok(schema.clone()),
+    }
+}
+
+// OpenAI strict mode: objects list every property as required and forbid
+// additional ones, properties that were optional become nullable instead
+fn openai_strict(schema: &Value) -> Result<Value, String> {
+    let mut errors = Vec::new();
+    if schema["type"] != "object" {
+        errors.push("/: the root must be an object".to_string());
+    }
+    let mut schema = schema.clone();
+    strict_node(&mut schema, "", &mut errors);
+    if errors.is_empty() {
+        Ok(schema)
+    } else {
+        Err(format!(
+            "unsupported by OpenAI structured outputs: {}",
+            errors.join("; ")
+        ))
+    }
+}
+
+fn is_object(node: &Map<String, Value>) -> bool {
+    match node.get("type") {
+        Some(Value::String(t)) => t == "object",
+        Some(Value::Array(types)) => types.iter().any(|t| t == "object"),
+        _ => node.contains_key("properties"),
+    }
+}
+
+fn strict_node(node: &mut Value, path: &str, errors: &mut Vec<String>) {
+    let Some(node) = node.as_object_mut() else {
+        return;
+    };
+    for keyword in OPENAI_UNSUPPORTED {
+        if node.contains_key(*keyword) {
+            errors.push(format!("{}/{}: not supported", path, keyword));
+        }
+    }
+
+    if is_object(node) {
+        if matches!(node.get("additionalProperties"), Some(a) if a != false) {
+            errors.push(format!(
+                "{}/additionalProperties: must be false, objects cannot have extra keys",
+                path
+            ));
+        }
+        let required: Vec<Value> = match node.get("required") {
+            Some(Value::Array(required)) => required.clone(),
+            _ => Vec::new(),
+        };
+        let properties = node.entry("properties").or_insert_with(|| json!({}));
+        let mut names = Vec::new();
+        if let Some(properties) = properties.as_object_mut() {
+            for (name, property) in properties.iter_mut() {
+                if !required.iter().any(|r| r == name) {
+                    make_nullable(property);
+                }
+                strict_node(property, &format!("{}/properties/{}", path, name), errors);
+                names.push(json!(name));
+            }
+        }
+        node.insert("required".to_string(), Value::Array(names));
+        node.insert("additionalProperties".to_string(), json!(false));
+    }
+
+    if let Some(items) = node.get_mut("items") {
+        strict_node(items, &format!("{}/items", path), errors);
+    }
+    if let Some(Value::Array(options)) = node.get_mut("anyOf") {
+        for (i, option) in options.iter_mut().enumerate() {
+            strict_node(option, &format!("{}/anyOf/{}", path, i), errors);
+        }
+    }
+    for defs in ["$defs", "definitions"] {
+        if let Some(Value::Object(definitions)) = node.get_mut(defs) {
+            for (name, definition) in definitions.iter_mut() {
+                strict_node(definition, &format!("{}/{}/{}", path, defs, name), errors);
+            }
+        }
+    }
+}
+
+// Lets a property be null, standing in for leaving it out
+fn make_nullable(property: &mut Value) {
+    let Some(node) = property.as_object_mut() else {
+        return;
+    };
+    if let Some(Value::Array(values)) = node.get_mut("enum") {
+        if !values.contains(&Value::Null) {
+            values.push(Value::Null);
+        }
+    }
+    match node.get_mut("type") {
+        Some(Value::String(t)) if t != "null" => {
+            let t = json!([t, "null"]);
+            node.insert("type".to_string(), t);
+        }
+        Some(Value::Array(types)) => {
+            if !types.iter().any(|t| t == "null") {
+                types.push(json!("null"));
+            }
+        }
+        Some(_) => {}
+        None => match node.get_mut("anyOf") {
+            Some(Value::Array(options)) => options.push(json!({"type": "null"})),
+            _ => {
+                let inner = property.take();
+                *property = json!({"anyOf": [inner, {"type": "null"}]});
+            }
+        },
+    }
+}
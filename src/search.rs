@@ -0,0 +1,102 @@
+use crate::semantic_cache::cosine_similarity;
+use polars::prelude::*;
+use pyo3::prelude::*;
+use pyo3_polars::error::PyPolarsErr;
+use pyo3_polars::PyDataFrame;
+
+// Reads an embedding column as one optional vector per row
+fn vectors(df: &DataFrame, column: &str) -> PolarsResult<Vec<Option<Vec<f32>>>> {
+    let series = df
+        .column(column)?
+        .cast(&DataType::List(Box::new(DataType::Float32)))?;
+    let ca = series.list()?;
+    ca.into_iter()
+        .map(|opt| {
+            opt.map(|s| Ok(s.f32()?.into_iter().map(|x| x.unwrap_or(0.0)).collect()))
+                .transpose()
+        })
+        .collect()
+}
+
+/// Indices of the `k` corpus vectors most similar to `query`, best first.
+pub fn top_k(query: &[f32], corpus: &[Option<Vec<f32>>], k: usize) -> Vec<(usize, f32)> {
+    let mut scores: Vec<(usize, f32)> = corpus
+        .iter()
+        .enumerate()
+        .filter_map(|(i, v)| v.as_ref().map(|v| (i, cosine_similarity(query, v))))
+        .collect();
+    let k = k.min(scores.len());
+    if k == 0 {
+        return Vec::new();
+    }
+    scores.select_nth_unstable_by(k - 1, |a, b| b.1.total_cmp(&a.1));
+    scores.truncate(k);
+    scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+    scores
+}
+
+fn join(
+    queries: &DataFrame,
+    corpus: &DataFrame,
+    query_column: &str,
+    corpus_column: &str,
+    k: usize,
+) -> PolarsResult<DataFrame> {
+    let query_vectors = vectors(queries, query_column)?;
+    let corpus_vectors = vectors(corpus, corpus_column)?;
+
+    let mut query_idx: Vec<IdxSize> = Vec::new();
+    let mut corpus_idx: Vec<IdxSize> = Vec::new();
+    let mut scores: Vec<f32> = Vec::new();
+    let mut ranks: Vec<u32> = Vec::new();
+    for (q, query) in query_vectors.iter().enumerate() {
+        let Some(query) = query else {
+            continue;
+        };
+        for (rank, (c, score)) in top_k(query, &corpus_vectors, k).into_iter().enumerate() {
+            query_idx.push(q as IdxSize);
+            corpus_idx.push(c as IdxSize);
+            scores.push(score);
+            ranks.push(rank as u32 + 1);
+        }
+    }
+
+    let left = queries.take(&IdxCa::from_vec("query_index", query_idx))?;
+    let right = corpus.take(&IdxCa::from_vec("corpus_index", corpus_idx))?;
+
+    // Corpus columns that clash with query columns get a "_right" suffix
+    let mut columns: Vec<Series> = right
+        .get_columns()
+        .iter()
+        .map(|s| {
+            let mut s = s.clone();
+            if left.get_column_names().contains(&s.name()) {
+                let name = format!("{}_right", s.name());
+                s.rename(&name);
+            }
+            s
+        })
+        .collect();
+    columns.push(Series::new("similarity", scores));
+    columns.push(Series::new("rank", ranks));
+    left.hstack(&columns)
+}
+
+/// Returns, for every query row, its `k` most similar corpus rows.
+///
+/// Similarity is the cosine similarity of the two embedding columns, computed
+/// by brute force. The result has the query columns, the matching corpus
+/// columns and `similarity` and `rank` (1 is the best match) columns.
+#[pyfunction]
+#[pyo3(signature = (queries, corpus, query_column="embedding", corpus_column="embedding", k=5))]
+pub fn semantic_join(
+    queries: PyDataFrame,
+    corpus: PyDataFrame,
+    query_column: &str,
+    corpus_column: &str,
+    k: usize,
+) -> PyResult<PyDataFrame> {
+    let df =
+        join(&queries.0, &corpus.0, query_column, corpus_column, k).map_err(PyPolarsErr::from)?;
+    Ok(PyDataFrame(df))
+}
@@ -0,0 +1,41 @@
+use crate::credentials::known_keys;
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+pub const REDACTED: &str = "[REDACTED]";
+
+// Credentials in the usual formats, for keys that were not set through the
+// plugin, with the part of the match to keep before the redaction
+static SECRET_PATTERNS: Lazy<Vec<Regex>> = Lazy::new(|| {
+    [
+        // OpenAI style keys
+        r"\b()(sk|rk|pk)-[A-Za-z0-9_\-]{16,}",
+        // Google API keys
+        r"\b()AIza[0-9A-Za-z_\-]{35}",
+        // Credential headers echoed back as `name: value` or in JSON
+        r#"(?i)\b((?:authorization|x-api-key|x-goog-api-key|api-key)"?\s*[:=]\s*"?(?:Bearer\s+)?)[^"\s,}]+"#,
+        r"(?i)\b(Bearer\s+)[A-Za-z0-9._~+/\-]+=*",
+        // Keys passed in a query string
+        r"(?i)([?&](?:key|api_key|apikey|access_token|token)=)[^&\s#]+",
+    ]
+    .into_iter()
+    .map(|pattern| Regex::new(pattern).unwrap())
+    .collect()
+});
+
+/// Replaces API keys in `text`, both the keys the plugin knows about and
+/// anything that looks like a credential, so it can be logged or returned.
+pub fn scrub(text: &str) -> String {
+    let mut text = text.to_string();
+    for key in known_keys() {
+        text = text.replace(key.as_str(), REDACTED);
+    }
+    for pattern in SECRET_PATTERNS.iter() {
+        if pattern.is_match(&text) {
+            text = pattern
+                .replace_all(&text, format!("${{1}}{}", REDACTED))
+                .into_owned();
+        }
+    }
+    text
+}
@@ -0,0 +1,222 @@
+use crate::checkpoint::request_hash;
+use crate::config::config;
+use crate::embeddings::{fetch_embeddings, EmbeddingParams};
+use crate::utils::{chat_request_body, fetch_data, Call, RequestOptions};
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::sync::RwLock;
+
+// Responses are shared across every expression call in the process
+static SEMANTIC_CACHE: Lazy<RwLock<SemanticCache>> =
+    Lazy::new(|| RwLock::new(SemanticCache::new(CAPACITY)));
+
+// Responses kept before the oldest are evicted
+const CAPACITY: usize = 10_000;
+
+/// Cache of previous responses keyed on the embedding of their prompt,
+/// within the scope of the rest of their request: a response is only
+/// reused for a request with the same provider, model and parameters.
+pub struct SemanticCache {
+    scopes: HashMap<String, VecDeque<(Vec<f32>, String)>>,
+    // Scope of every entry, oldest first, to evict in insertion order
+    order: VecDeque<String>,
+    capacity: usize,
+}
+
+impl SemanticCache {
+    pub fn new(capacity: usize) -> SemanticCache {
+        SemanticCache {
+            scopes: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Returns the response of the most similar prompt cached in `scope`,
+    /// if it is at least `threshold` similar to `embedding`.
+    pub fn lookup(&self, scope: &str, embedding: &[f32], threshold: f32) -> Option<&str> {
+        self.scopes
+            .get(scope)?
+            .iter()
+            .map(|(cached, response)| (cosine_similarity(embedding, cached), response))
+            .filter(|(similarity, _)| *similarity >= threshold)
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, response)| response.as_str())
+    }
+
+    /// Caches `response` in `scope`, evicting the oldest response once
+    /// the cache is full.
+    pub fn insert(&mut self, scope: String, embedding: Vec<f32>, response: String) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                if let Some(entries) = self.scopes.get_mut(&oldest) {
+                    entries.pop_front();
+                    if entries.is_empty() {
+                        self.scopes.remove(&oldest);
+                    }
+                }
+            }
+        }
+        self.scopes
+            .entry(scope.clone())
+            .or_default()
+            .push_back((embedding, response));
+        self.order.push_back(scope);
+    }
+}
+
+// Hash of the request for `message` without its messages, so only requests
+// with the same provider, model, parameters, tools and response format
+// share answers. `dry_run` is not part of the body, so it is added apart.
+fn request_scope(message: &str, options: &RequestOptions) -> String {
+    let model = options.model_name(&config());
+    let body = chat_request_body(message, &model, options)
+        .and_then(|body| serde_json::from_str::<Value>(&body).ok());
+    let rest = match body {
+        Some(Value::Object(mut body)) => {
+            body.remove("messages");
+            Value::Object(body).to_string()
+        }
+        _ => model,
+    };
+    request_hash(&format!(
+        "{}\n{}\n{}",
+        options.provider.as_str(),
+        options.dry_run,
+        rest
+    ))
+}
+
+// Only completions are worth reusing, not errors or dry run estimates
+fn is_completion(response: &str) -> bool {
+    match serde_json::from_str::<Value>(response) {
+        Ok(Value::Object(response)) => {
+            response.get("choices").is_some_and(Value::is_array) && !response.contains_key("error")
+        }
+        _ => false,
+    }
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+/// Like `fetch_data`, but reuses the response of any previous or in-batch
+/// prompt whose embedding is within `threshold` cosine similarity.
+pub async fn fetch_data_semantic(
+    messages: &[(usize, String)],
+    threshold: f32,
+    options: &RequestOptions,
+    call: &Call,
+) -> Vec<Option<String>> {
+    // Nothing is sent on a dry run, so there is nothing to embed or reuse
+    if options.dry_run {
+        return fetch_data(messages, options, call).await;
+    }
+    let texts: Vec<String> = messages.iter().map(|(_, m)| m.clone()).collect();
+    let embeddings = fetch_embeddings(&texts, options, &EmbeddingParams::default()).await;
+    let embeddings: Vec<Vec<f32>> = match embeddings.into_iter().collect() {
+        Some(embeddings) => embeddings,
+        // Without every embedding the cache cannot help, send everything
+        None => return fetch_data(messages, options, call).await,
+    };
+
+    let mut results: Vec<Option<String>> = vec![None; messages.len()];
+    // Rows that reuse the response of an earlier uncached row in this batch
+    let mut aliases: Vec<(usize, usize)> = Vec::new();
+    let mut pending: Vec<usize> = Vec::new();
+
+    let scopes: Vec<String> = messages
+        .iter()
+        .map(|(_, message)| request_scope(message, options))
+        .collect();
+    {
+        let cache = SEMANTIC_CACHE.read().unwrap();
+        for (i, embedding) in embeddings.iter().enumerate() {
+            if let Some(hit) = cache.lookup(&scopes[i], embedding, threshold) {
+                results[i] = Some(hit.to_string());
+                continue;
+            }
+            let similar = pending.iter().find(|&&j| {
+                scopes[j] == scopes[i] && cosine_similarity(embedding, &embeddings[j]) >= threshold
+            });
+            match similar {
+                Some(&j) => aliases.push((i, j)),
+                None => pending.push(i),
+            }
+        }
+    }
+
+    let pending_messages: Vec<(usize, String)> =
+        pending.iter().map(|&i| messages[i].clone()).collect();
+    let fetched = fetch_data(&pending_messages, options, call).await;
+
+    let mut cache = SEMANTIC_CACHE.write().unwrap();
+    for (&i, response) in pending.iter().zip(fetched) {
+        if let Some(response) = response.as_deref().filter(|r| is_completion(r)) {
+            cache.insert(
+                scopes[i].clone(),
+                embeddings[i].clone(),
+                response.to_string(),
+            );
+        }
+        results[i] = response;
+    }
+    for (i, j) in aliases {
+        results[i] = results[j].clone();
+    }
+
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookups_stay_within_their_scope() {
+        let mut cache = SemanticCache::new(4);
+        cache.insert("gpt-4o".into(), vec![1.0, 0.0], "cached".into());
+
+        assert_eq!(cache.lookup("gpt-4o", &[1.0, 0.1], 0.9), Some("cached"));
+        assert_eq!(cache.lookup("gpt-4o-mini", &[1.0, 0.1], 0.9), None);
+        assert_eq!(cache.lookup("gpt-4o", &[0.0, 1.0], 0.9), None);
+    }
+
+    #[test]
+    fn the_oldest_response_is_evicted_first() {
+        let mut cache = SemanticCache::new(2);
+        cache.insert("a".into(), vec![1.0, 0.0], "first".into());
+        cache.insert("b".into(), vec![1.0, 0.0], "second".into());
+        cache.insert("a".into(), vec![0.0, 1.0], "third".into());
+
+        assert_eq!(cache.lookup("a", &[1.0, 0.0], 0.9), None);
+        assert_eq!(cache.lookup("b", &[1.0, 0.0], 0.9), Some("second"));
+        assert_eq!(cache.lookup("a", &[0.0, 1.0], 0.9), Some("third"));
+        assert_eq!(cache.order.len(), 2);
+    }
+
+    #[test]
+    fn only_completions_are_cached() {
+        assert!(is_completion(
+            r#"{"choices": [{"message": {"content": "hi"}}]}"#
+        ));
+        assert!(!is_completion(
+            r#"{"error": {"type": "budget_exceeded", "message": "spent"}}"#
+        ));
+        assert!(!is_completion(
+            r#"{"choices": [], "error": {"type": "output_filtered"}}"#
+        ));
+        assert!(!is_completion(r#"{"object": "dry_run", "usage": {}}"#));
+    }
+}
@@ -0,0 +1,335 @@
+use crate::config::Config;
+use crate::metrics::CallUsage;
+use crate::utils::{post_chat_request, Attempts, RequestOptions};
+use once_cell::sync::Lazy;
+use pyo3::prelude::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::ops::ControlFlow;
+use std::sync::RwLock;
+
+// Called with (row, delta) for every token chunk of a streamed request
+static STREAM_CALLBACK: Lazy<RwLock<Option<PyObject>>> = Lazy::new(|| RwLock::new(None));
+
+/// Sets the function called with `(row, delta)` as tokens of a streamed
+/// request arrive, pass `None` to remove it.
+#[pyfunction]
+#[pyo3(signature = (callback=None))]
+pub fn set_stream_callback(callback: Option<PyObject>) {
+    *STREAM_CALLBACK.write().unwrap() = callback;
+}
+
+pub(crate) fn emit(row: usize, delta: &str) {
+    if STREAM_CALLBACK.read().unwrap().is_none() {
+        return;
+    }
+    Python::with_gil(|py| {
+        // Release the lock before calling so the callback may replace itself
+        let callback = STREAM_CALLBACK
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|cb| cb.clone_ref(py));
+        if let Some(callback) = callback {
+            // A failing callback must not fail the request
+            let _ = callback.call1(py, (row, delta));
+        }
+    });
+}
+
+/// Provider-independent event decoded from a streamed response.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamEvent {
+    Start {
+        id: Option<String>,
+        model: Option<String>,
+        created: Option<i64>,
+    },
+    Delta(String),
+    ToolCallDelta {
+        index: usize,
+        id: Option<String>,
+        name: Option<String>,
+        arguments: String,
+    },
+    Usage(Value),
+    Done {
+        finish_reason: Option<String>,
+    },
+}
+
+/// Turns the `data:` payloads of one provider's stream into `StreamEvent`s.
+pub trait StreamDecoder {
+    fn decode(&mut self, payload: &str) -> Vec<StreamEvent>;
+}
+
+/// Splits a server-sent events byte stream into the payloads of its `data:` lines.
+#[derive(Default)]
+pub struct SseDecoder {
+    // Bytes rather than text, a chunk may end in the middle of a UTF-8 character
+    buffer: Vec<u8>,
+}
+
+impl SseDecoder {
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut payloads = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\r', '\n']);
+            if let Some(data) = line.strip_prefix("data:") {
+                payloads.push(data.trim_start().to_string());
+            }
+        }
+        payloads
+    }
+}
+
+#[derive(Deserialize)]
+struct OpenAIChunk {
+    id: Option<String>,
+    created: Option<i64>,
+    model: Option<String>,
+    #[serde(default)]
+    choices: Vec<OpenAIChunkChoice>,
+    usage: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct OpenAIChunkChoice {
+    #[serde(default)]
+    delta: OpenAIChunkDelta,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct OpenAIChunkDelta {
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<OpenAIToolCallDelta>,
+}
+
+#[derive(Deserialize)]
+struct OpenAIToolCallDelta {
+    index: usize,
+    id: Option<String>,
+    function: Option<OpenAIFunctionDelta>,
+}
+
+#[derive(Deserialize)]
+struct OpenAIFunctionDelta {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+/// Decoder for OpenAI and OpenAI-compatible chat completion streams.
+#[derive(Default)]
+pub struct OpenAIStreamDecoder {
+    started: bool,
+}
+
+impl StreamDecoder for OpenAIStreamDecoder {
+    fn decode(&mut self, payload: &str) -> Vec<StreamEvent> {
+        if payload == "[DONE]" {
+            return Vec::new();
+        }
+        let Ok(chunk) = serde_json::from_str::<OpenAIChunk>(payload) else {
+            return Vec::new();
+        };
+
+        let mut events = Vec::new();
+        if !self.started {
+            self.started = true;
+            events.push(StreamEvent::Start {
+                id: chunk.id,
+                model: chunk.model,
+                created: chunk.created,
+            });
+        }
+        for choice in chunk.choices {
+            if let Some(content) = choice.delta.content.filter(|c| !c.is_empty()) {
+                events.push(StreamEvent::Delta(content));
+            }
+            for call in choice.delta.tool_calls {
+                let (name, arguments) = match call.function {
+                    Some(function) => (function.name, function.arguments.unwrap_or_default()),
+                    None => (None, String::new()),
+                };
+                events.push(StreamEvent::ToolCallDelta {
+                    index: call.index,
+                    id: call.id,
+                    name,
+                    arguments,
+                });
+            }
+            if choice.finish_reason.is_some() {
+                events.push(StreamEvent::Done {
+                    finish_reason: choice.finish_reason,
+                });
+            }
+        }
+        // Sent in a final chunk without choices when include_usage is set
+        if let Some(usage) = chunk.usage.filter(|u| !u.is_null()) {
+            events.push(StreamEvent::Usage(usage));
+        }
+        events
+    }
+}
+
+/// Reads a streamed response to the end, or until `on_event` breaks.
+pub async fn read_events<D: StreamDecoder>(
+    response: &mut reqwest::Response,
+    decoder: &mut D,
+    mut on_event: impl FnMut(StreamEvent) -> ControlFlow<()>,
+) -> reqwest::Result<()> {
+    let mut sse = SseDecoder::default();
+    while let Some(bytes) = response.chunk().await? {
+        for payload in sse.push(&bytes) {
+            for event in decoder.decode(&payload) {
+                if on_event(event).is_break() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Folds stream events back into a complete chat completion.
+#[derive(Default)]
+pub struct StreamAccumulator {
+    id: Option<String>,
+    model: Option<String>,
+    created: Option<i64>,
+    content: String,
+    // (id, name, arguments) by tool call index
+    tool_calls: Vec<(Option<String>, Option<String>, String)>,
+    usage: Option<Value>,
+    finish_reason: Option<String>,
+}
+
+impl StreamAccumulator {
+    pub fn push(&mut self, event: StreamEvent) {
+        match event {
+            StreamEvent::Start { id, model, created } => {
+                self.id = id;
+                self.model = model;
+                self.created = created;
+            }
+            StreamEvent::Delta(delta) => self.content.push_str(&delta),
+            StreamEvent::ToolCallDelta {
+                index,
+                id,
+                name,
+                arguments,
+            } => {
+                if self.tool_calls.len() <= index {
+                    self.tool_calls
+                        .resize(index + 1, (None, None, String::new()));
+                }
+                let call = &mut self.tool_calls[index];
+                call.0 = call.0.take().or(id);
+                call.1 = call.1.take().or(name);
+                call.2.push_str(&arguments);
+            }
+            StreamEvent::Usage(usage) => self.usage = Some(usage),
+            StreamEvent::Done { finish_reason } => self.finish_reason = finish_reason,
+        }
+    }
+
+    /// Returns the response in the same shape as a non-streamed chat completion.
+    pub fn into_completion(self) -> Value {
+        let mut message = json!({"role": "assistant", "content": self.content});
+        if !self.tool_calls.is_empty() {
+            message["tool_calls"] = self
+                .tool_calls
+                .into_iter()
+                .map(|(id, name, arguments)| {
+                    json!({
+                        "id": id,
+                        "type": "function",
+                        "function": {"name": name, "arguments": arguments}
+                    })
+                })
+                .collect();
+        }
+        json!({
+            "id": self.id,
+            "object": "chat.completion",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{
+                "index": 0,
+                "message": message,
+                "finish_reason": self.finish_reason
+            }],
+            "usage": self.usage
+        })
+    }
+}
+
+/// Streams one chat completion, forwarding each token chunk to the stream
+/// callback, and returns the assembled response with how many attempts it
+/// took.
+pub async fn send_chat_request_streaming(
+    client: &reqwest::Client,
+    config: &Config,
+    options: &RequestOptions,
+    body: &str,
+    row: usize,
+    usage: &CallUsage,
+) -> Option<(String, Attempts)> {
+    let (mut response, attempts) = post_chat_request(client, config, options, body, usage).await?;
+
+    let mut accumulator = StreamAccumulator::default();
+    let mut decoder = OpenAIStreamDecoder::default();
+    read_events(&mut response, &mut decoder, |event| {
+        if let StreamEvent::Delta(delta) = &event {
+            emit(row, delta);
+        }
+        accumulator.push(event);
+        ControlFlow::Continue(())
+    })
+    .await
+    .ok()?;
+
+    Some((accumulator.into_completion().to_string(), attempts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payloads_split_across_chunks_are_joined() {
+        let mut decoder = SseDecoder::default();
+
+        assert!(decoder.push(b"data: {\"a\"").is_empty());
+        assert_eq!(
+            decoder.push(b": 1}\r\n\r\ndata: [DONE]\n"),
+            ["{\"a\": 1}", "[DONE]"]
+        );
+    }
+
+    #[test]
+    fn characters_split_across_chunks_are_kept() {
+        let mut decoder = SseDecoder::default();
+        let line = "data: café\n".as_bytes();
+        let (first, second) = line.split_at(10);
+
+        assert!(decoder.push(first).is_empty());
+        assert_eq!(decoder.push(second), ["café"]);
+    }
+
+    #[test]
+    fn lines_other_than_data_are_skipped() {
+        let mut decoder = SseDecoder::default();
+
+        assert_eq!(
+            decoder.push(b": keep-alive\nevent: message\ndata:x\n"),
+            ["x"]
+        );
+    }
+}
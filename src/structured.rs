@@ -0,0 +1,392 @@
+use crate::messages::{follow_up, reply_message};
+use crate::provider::Provider;
+use crate::schema::normalize_schema;
+use jsonschema::Validator;
+use once_cell::sync::Lazy;
+use polars::prelude::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+/// Reply of one row parsed as JSON and checked against the response schema.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Structured {
+    // Compact JSON of the reply, null when it could not be parsed
+    pub json: Option<String>,
+    // Whether the reply only parsed after `repair_json`
+    pub repaired: bool,
+    // request_failed, invalid_json or validation_failed, with the details
+    pub error: Option<String>,
+}
+
+/// `Struct{json, repaired, error}`
+pub fn structured_dtype() -> DataType {
+    DataType::Struct(vec![
+        Field::new("json", DataType::String),
+        Field::new("repaired", DataType::Boolean),
+        Field::new("error", DataType::String),
+    ])
+}
+
+/// Builds a `Struct{json, repaired, error}` column, null rows have every field null.
+pub fn structured_column(name: &str, rows: &[Option<Structured>]) -> PolarsResult<Series> {
+    let json: Vec<Option<&str>> = rows
+        .iter()
+        .map(|row| row.as_ref().and_then(|r| r.json.as_deref()))
+        .collect();
+    let repaired: Vec<Option<bool>> = rows
+        .iter()
+        .map(|row| row.as_ref().map(|r| r.repaired))
+        .collect();
+    let error: Vec<Option<&str>> = rows
+        .iter()
+        .map(|row| row.as_ref().and_then(|r| r.error.as_deref()))
+        .collect();
+    let fields = [
+        Series::new("json", json),
+        Series::new("repaired", repaired),
+        Series::new("error", error),
+    ];
+    Ok(StructChunked::new(name, &fields)?.into_series())
+}
+
+/// Chat completions `response_format` asking for JSON matching `schema`.
+pub fn response_format(schema: &Value, strict: bool) -> Value {
+    json!({
+        "type": "json_schema",
+        "json_schema": {"name": "response", "schema": schema, "strict": strict}
+    })
+}
+
+// URLs and models, as "url model", that rejected json_schema response formats
+static PROMPTED_SCHEMAS: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Key of the server and model a chat completion `body` is posted to at
+/// `url`, when the body asks for a json_schema response format.
+pub fn schema_format_key(url: &str, body: &str) -> Option<String> {
+    if !body.contains("\"json_schema\"") {
+        return None;
+    }
+    let body: Value = serde_json::from_str(body).ok()?;
+    body.pointer("/response_format/json_schema/schema")?;
+    Some(format!(
+        "{} {}",
+        url,
+        body["model"].as_str().unwrap_or_default()
+    ))
+}
+
+/// Whether the server and model of `key` get their schemas in the prompt.
+pub fn prompts_for_schema(key: &str) -> bool {
+    PROMPTED_SCHEMAS.lock().unwrap().contains(key)
+}
+
+/// Asks for the schemas of later requests to the server and model of `key`
+/// in the prompt.
+pub fn prompt_for_schema(key: String) {
+    PROMPTED_SCHEMAS.lock().unwrap().insert(key);
+}
+
+/// Whether an error response with `status` and body `text` turns down the
+/// response format of its request.
+pub fn rejects_response_format(status: u16, text: &str) -> bool {
+    matches!(status, 400 | 422)
+        && (text.contains("response_format") || text.contains("json_schema"))
+}
+
+/// How replies are held to a response schema.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StructuredMode {
+    // The server's structured outputs, or the prompt for models known to lack
+    // them and for servers that turn them down
+    #[default]
+    Auto,
+    // Only the server's structured outputs
+    Native,
+    // Instructions in the system message, whatever the server supports
+    Prompt,
+}
+
+/// Replaces the json_schema response format of a chat completion `body` by
+/// instructions in its system message, for servers without structured
+/// outputs. Replies are still parsed and checked against the schema. False
+/// when the body has no such format.
+pub fn prompt_schema(body: &mut Value) -> bool {
+    let Some(schema) = body.pointer("/response_format/json_schema/schema").cloned() else {
+        return false;
+    };
+    let Some(messages) = body["messages"].as_array_mut() else {
+        return false;
+    };
+    let instructions = format!(
+        "Reply with only a JSON object matching this JSON schema, without any other text:\n{}",
+        schema
+    );
+    match messages.first_mut() {
+        Some(first) if first["role"] == "system" && first["content"].is_string() => {
+            let content = first["content"].as_str().unwrap_or_default();
+            first["content"] = Value::String(format!("{}\n\n{}", content, instructions));
+        }
+        _ => messages.insert(0, json!({"role": "system", "content": instructions})),
+    }
+    if let Some(body) = body.as_object_mut() {
+        body.remove("response_format");
+    }
+    true
+}
+
+/// `prompt_schema` for the JSON text of a request body.
+pub fn schema_in_prompt(body: &str) -> Option<String> {
+    let mut body: Value = serde_json::from_str(body).ok()?;
+    prompt_schema(&mut body).then(|| body.to_string())
+}
+
+/// Compiles a response schema, given as a JSON object or its text. Strict
+/// schemas are first normalized to what `provider` accepts.
+pub fn compile_schema(
+    schema: &Value,
+    provider: Provider,
+    strict: bool,
+) -> Result<(Value, Validator), String> {
+    let schema = match schema {
+        Value::String(text) => serde_json::from_str(text).map_err(|e| e.to_string())?,
+        schema => schema.clone(),
+    };
+    let schema = if strict {
+        normalize_schema(&schema, provider)?
+    } else {
+        schema
+    };
+    let validator = jsonschema::validator_for(&schema).map_err(|e| e.to_string())?;
+    Ok((schema, validator))
+}
+
+/// Checks `value` against the schema, returning every violation.
+pub fn validate_json_schema(validator: &Validator, value: &Value) -> Result<(), Vec<String>> {
+    let errors: Vec<String> = validator
+        .iter_errors(value)
+        .map(|e| match e.instance_path.to_string() {
+            path if path.is_empty() => e.to_string(),
+            path => format!("{}: {}", path, e),
+        })
+        .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+// Body of a markdown code block, or the text itself without one
+pub fn strip_code_fences(text: &str) -> &str {
+    let text = text.trim();
+    let Some(start) = text.find("```") else {
+        return text;
+    };
+    let body = &text[start + 3..];
+    // Skip the language tag of the opening fence
+    let body = match body.find('\n') {
+        Some(i) => &body[i + 1..],
+        None => body,
+    };
+    match body.rfind("```") {
+        Some(end) => body[..end].trim(),
+        None => body.trim(),
+    }
+}
+
+fn trim_trailing_comma(out: &mut String) {
+    let trimmed = out.trim_end();
+    if trimmed.ends_with(',') {
+        out.truncate(trimmed.len() - 1);
+    }
+}
+
+/// Rewrites close-but-invalid JSON as models tend to write it: code fences
+/// and prose around the value, single-quoted strings, unquoted keys, Python
+/// literals, trailing commas and brackets left open by a cut-off reply.
+pub fn repair_json(text: &str) -> String {
+    let text = strip_code_fences(text);
+    let Some(start) = text.find(['{', '[']) else {
+        return text.to_string();
+    };
+    let mut out = String::with_capacity(text.len());
+    let mut closers: Vec<char> = Vec::new();
+    let mut quote: Option<char> = None;
+    let mut chars = text[start..].chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if let Some(q) = quote {
+            match c {
+                '\\' => match chars.next() {
+                    Some('\'') => out.push('\''),
+                    Some(escaped) => {
+                        out.push('\\');
+                        out.push(escaped);
+                    }
+                    None => {}
+                },
+                c if c == q => {
+                    out.push('"');
+                    quote = None;
+                }
+                '"' => out.push_str("\\\""),
+                '\n' => out.push_str("\\n"),
+                '\t' => out.push_str("\\t"),
+                c => out.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' | '\'' => {
+                quote = Some(c);
+                out.push('"');
+            }
+            '{' => {
+                closers.push('}');
+                out.push(c);
+            }
+            '[' => {
+                closers.push(']');
+                out.push(c);
+            }
+            '}' | ']' => {
+                trim_trailing_comma(&mut out);
+                closers.pop();
+                out.push(c);
+                // Anything after the value is prose
+                if closers.is_empty() {
+                    return out;
+                }
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut word = c.to_string();
+                while let Some(&next) = chars.peek() {
+                    if !(next.is_alphanumeric() || next == '_') {
+                        break;
+                    }
+                    word.push(next);
+                    chars.next();
+                }
+                while chars.peek().is_some_and(|c| c.is_whitespace()) {
+                    chars.next();
+                }
+                match (word.as_str(), chars.peek()) {
+                    (_, Some(':')) => out.push_str(&json!(word).to_string()),
+                    ("True", _) => out.push_str("true"),
+                    ("False", _) => out.push_str("false"),
+                    ("None", _) => out.push_str("null"),
+                    _ => out.push_str(&word),
+                }
+            }
+            c => out.push(c),
+        }
+    }
+
+    // The reply was cut off, close whatever is still open
+    if quote.is_some() {
+        out.push('"');
+    }
+    trim_trailing_comma(&mut out);
+    if out.trim_end().ends_with(':') {
+        out.push_str("null");
+    }
+    while let Some(closer) = closers.pop() {
+        out.push(closer);
+    }
+    out
+}
+
+/// Parses a reply as JSON, retrying with `repair_json` when `repair` is set.
+/// The flag tells whether the repair was needed.
+pub fn parse_json(text: &str, repair: bool) -> Result<(Value, bool), String> {
+    match serde_json::from_str(text.trim()) {
+        Ok(value) => Ok((value, false)),
+        Err(e) if !repair => Err(e.to_string()),
+        Err(e) => serde_json::from_str(&repair_json(text))
+            .map(|value| (value, true))
+            .map_err(|_| e.to_string()),
+    }
+}
+
+/// Parses the reply of a chat completion response and validates it.
+pub fn structure_reply(
+    response: Option<&str>,
+    validator: Option<&Validator>,
+    repair: bool,
+) -> Structured {
+    let Some(reply) = response.and_then(reply_message) else {
+        return Structured {
+            error: Some("request_failed".to_string()),
+            ..Default::default()
+        };
+    };
+    let (value, repaired) = match parse_json(&reply.text(), repair) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            return Structured {
+                error: Some(format!("invalid_json: {}", e)),
+                ..Default::default()
+            }
+        }
+    };
+    let error = validator
+        .and_then(|validator| validate_json_schema(validator, &value).err())
+        .map(|errors| format!("validation_failed: {}", errors.join("; ")));
+    Structured {
+        json: Some(value.to_string()),
+        repaired,
+        error,
+    }
+}
+
+/// The conversation extended by the reply and a user message listing what
+/// was wrong with it, when the reply was not valid JSON or failed the schema.
+pub fn correction_messages(messages: &str, response: &str, result: &Structured) -> Option<String> {
+    let error = result.error.as_deref()?;
+    if error == "request_failed" {
+        return None;
+    }
+    let reply = reply_message(response)?;
+    let correction = format!(
+        "Your reply could not be used ({}). Reply again with only the corrected JSON.",
+        error
+    );
+    follow_up(messages, &reply.text(), &correction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repaired(text: &str) -> Value {
+        serde_json::from_str(&repair_json(text)).unwrap()
+    }
+
+    #[test]
+    fn fences_and_prose_are_dropped() {
+        let text = "Here you go:\n```json\n{\"a\": 1}\n```\nAnything else?";
+
+        assert_eq!(repaired(text), json!({"a": 1}));
+    }
+
+    #[test]
+    fn python_style_values_are_rewritten() {
+        let text = "{'name': 'Ada', done: True, 'tags': ['x', None,],}";
+
+        assert_eq!(
+            repaired(text),
+            json!({"name": "Ada", "done": true, "tags": ["x", null]})
+        );
+    }
+
+    #[test]
+    fn cut_off_replies_are_closed() {
+        assert_eq!(
+            repaired(r#"{"items": [{"id": 1}, {"id": 2"#),
+            json!({"items": [{"id": 1}, {"id": 2}]})
+        );
+    }
+}
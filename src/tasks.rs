@@ -0,0 +1,537 @@
+use polars::chunked_array::builder::AnonymousOwnedListBuilder;
+use polars::prelude::*;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+
+/// JSON messages sending `text` to the model with task `instructions`.
+pub fn task_messages(instructions: &str, text: &str) -> String {
+    json!([
+        {"role": "system", "content": instructions},
+        {"role": "user", "content": text}
+    ])
+    .to_string()
+}
+
+// Confidence between 0 and 1, as the model estimates it
+fn confidence_schema() -> Value {
+    json!({"type": "number", "minimum": 0, "maximum": 1})
+}
+
+/// Instructions to pick exactly one of `labels`.
+pub fn classification_instructions(labels: &[String]) -> String {
+    format!(
+        "Classify the text into exactly one of these labels: {}. \
+         Spell the label exactly as given.",
+        labels.join(", ")
+    )
+}
+
+/// Schema of a reply `{"label": ..}` restricted to `labels`, with a
+/// `confidence` when asked for.
+pub fn classification_schema(labels: &[String], confidence: bool) -> Value {
+    let mut schema = json!({
+        "type": "object",
+        "properties": {"label": {"type": "string", "enum": labels}},
+        "required": ["label"]
+    });
+    if confidence {
+        schema["properties"]["confidence"] = confidence_schema();
+        schema["required"] = json!(["label", "confidence"]);
+    }
+    schema
+}
+
+/// Instructions to classify each of several numbered texts.
+pub fn packed_classification_instructions(labels: &[String]) -> String {
+    format!(
+        "Classify each numbered text into exactly one of these labels: {}. \
+         Spell the label exactly as given. Return one result for every text, \
+         with the number of the text it belongs to.",
+        labels.join(", ")
+    )
+}
+
+/// JSON messages with `texts` numbered from 1 in a single user message.
+pub fn packed_messages(instructions: &str, texts: &[&str]) -> String {
+    let texts: Vec<String> = texts
+        .iter()
+        .enumerate()
+        .map(|(i, text)| format!("[{}] {}", i + 1, text))
+        .collect();
+    task_messages(instructions, &texts.join("\n\n"))
+}
+
+/// Schema of a reply `{"results": [..]}` holding one `item_schema` object
+/// per packed text, each with the `item` number of its text.
+pub fn packed_schema(item_schema: &Value) -> Value {
+    let mut item = item_schema.clone();
+    item["properties"]["item"] = json!({"type": "integer", "minimum": 1});
+    if let Some(required) = item["required"].as_array_mut() {
+        required.insert(0, json!("item"));
+    }
+    json!({
+        "type": "object",
+        "properties": {"results": {"type": "array", "items": item}},
+        "required": ["results"]
+    })
+}
+
+/// The results of a packed reply in the order of its `count` texts, without
+/// their `item` numbers. None unless every text got exactly one result.
+pub fn unpack_results(reply: &Value, count: usize) -> Option<Vec<Value>> {
+    let results = reply["results"].as_array()?;
+    if results.len() != count {
+        return None;
+    }
+    let mut unpacked = vec![None; count];
+    for result in results {
+        let item = result["item"].as_u64()? as usize;
+        let slot = unpacked.get_mut(item.checked_sub(1)?)?;
+        if slot.is_some() {
+            return None;
+        }
+        let mut result = result.clone();
+        result.as_object_mut()?.remove("item");
+        *slot = Some(result);
+    }
+    unpacked.into_iter().collect()
+}
+
+/// Instructions to answer from numbered documents, citing the ones used.
+pub fn citation_instructions() -> &'static str {
+    "Answer the question using only the numbered documents. Cite the number of every \
+     document the answer relies on. If the documents do not answer the question, say so \
+     and cite nothing."
+}
+
+/// JSON messages asking `question` about `documents`, numbered from 1.
+pub fn citation_messages(question: &str, documents: &[&str]) -> String {
+    let documents: String = documents
+        .iter()
+        .enumerate()
+        .map(|(i, document)| format!("[{}] {}\n\n", i + 1, document))
+        .collect();
+    task_messages(
+        citation_instructions(),
+        &format!("Documents:\n\n{}Question: {}", documents, question),
+    )
+}
+
+/// Schema of a reply `{"answer": .., "citations": [..]}`.
+pub fn citation_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "answer": {"type": "string"},
+            "citations": {"type": "array", "items": {"type": "integer", "minimum": 1}}
+        },
+        "required": ["answer", "citations"]
+    })
+}
+
+/// `Struct{answer, citations: List[UInt32]}`
+pub fn cited_dtype() -> DataType {
+    DataType::Struct(vec![
+        Field::new("answer", DataType::String),
+        Field::new("citations", DataType::List(Box::new(DataType::UInt32))),
+    ])
+}
+
+/// Builds the `cited_dtype` column of each reply, with citations turned
+/// into positions in the row's list of `document_counts` documents.
+/// Citations of documents that do not exist are dropped.
+pub fn cited_column(
+    name: &str,
+    replies: &[Option<Value>],
+    document_counts: &[usize],
+) -> PolarsResult<Series> {
+    let answers = StringChunked::from_iter_options(
+        "answer",
+        replies
+            .iter()
+            .map(|r| r.as_ref().and_then(|r| r["answer"].as_str())),
+    );
+    let mut citations = ListPrimitiveChunkedBuilder::<UInt32Type>::new(
+        "citations",
+        replies.len(),
+        replies.len(),
+        DataType::UInt32,
+    );
+    for (reply, &count) in replies.iter().zip(document_counts) {
+        let Some(cited) = reply.as_ref().and_then(|r| r["citations"].as_array()) else {
+            citations.append_null();
+            continue;
+        };
+        let mut positions: Vec<u32> = cited
+            .iter()
+            .filter_map(Value::as_u64)
+            .filter(|&n| n >= 1 && n as usize <= count)
+            .map(|n| (n - 1) as u32)
+            .collect();
+        positions.sort_unstable();
+        positions.dedup();
+        citations.append_slice(&positions);
+    }
+    let fields = [answers.into_series(), citations.finish().into_series()];
+    Ok(StructChunked::new(name, &fields)?.into_series())
+}
+
+/// Allowed values of every tagging dimension, dimensions are kept in name order.
+pub type Taxonomy = BTreeMap<String, Vec<String>>;
+
+/// Instructions to tag a text along every dimension of `taxonomy`.
+pub fn taxonomy_instructions(taxonomy: &Taxonomy, multi_label: bool) -> String {
+    let dimensions: Vec<String> = taxonomy
+        .iter()
+        .map(|(name, values)| format!("- {}: {}", name, values.join(", ")))
+        .collect();
+    let pick = if multi_label {
+        "every value that applies, possibly none"
+    } else {
+        "the single value that fits best"
+    };
+    format!(
+        "Tag the text along each of these dimensions, choosing {} from its values:\n{}",
+        pick,
+        dimensions.join("\n")
+    )
+}
+
+/// Schema of a reply with the tags of every dimension, each tag a value or,
+/// with `confidence`, `{"value": .., "confidence": ..}`.
+pub fn taxonomy_schema(taxonomy: &Taxonomy, multi_label: bool, confidence: bool) -> Value {
+    let properties: Map<String, Value> = taxonomy
+        .iter()
+        .map(|(name, values)| {
+            let mut tag = json!({"type": "string", "enum": values});
+            if confidence {
+                tag = json!({
+                    "type": "object",
+                    "properties": {"value": tag, "confidence": confidence_schema()},
+                    "required": ["value", "confidence"]
+                });
+            }
+            if multi_label {
+                tag = json!({"type": "array", "items": tag});
+            }
+            (name.clone(), tag)
+        })
+        .collect();
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": taxonomy.keys().collect::<Vec<_>>()
+    })
+}
+
+fn tag_field_dtype(multi_label: bool, confidence: bool) -> DataType {
+    let tag = if confidence {
+        DataType::Struct(vec![
+            Field::new("value", DataType::String),
+            Field::new("confidence", DataType::Float64),
+        ])
+    } else {
+        DataType::String
+    };
+    if multi_label {
+        DataType::List(Box::new(tag))
+    } else {
+        tag
+    }
+}
+
+/// `Struct` with a field per dimension holding its tag, or list of tags with
+/// `multi_label`, each tag a `Struct{value, confidence}` with `confidence`.
+pub fn tags_dtype(taxonomy: &Taxonomy, multi_label: bool, confidence: bool) -> DataType {
+    DataType::Struct(
+        taxonomy
+            .keys()
+            .map(|name| Field::new(name, tag_field_dtype(multi_label, confidence)))
+            .collect(),
+    )
+}
+
+// A tag value with its confidence
+type Tag = (String, Option<f64>);
+
+// Tags of one dimension in a reply
+fn reply_tags(reply: &Value, multi_label: bool, confidence: bool) -> Vec<Tag> {
+    let tags = if multi_label {
+        reply.as_array().cloned().unwrap_or_default()
+    } else {
+        vec![reply.clone()]
+    };
+    tags.iter()
+        .filter_map(|tag| {
+            let (value, score) = if confidence {
+                (&tag["value"], tag["confidence"].as_f64())
+            } else {
+                (tag, None)
+            };
+            Some((value.as_str()?.to_string(), score))
+        })
+        .collect()
+}
+
+fn tag_structs(name: &str, tags: &[Option<&Tag>]) -> PolarsResult<Series> {
+    let values = StringChunked::from_iter_options(
+        "value",
+        tags.iter().map(|t| t.map(|(value, _)| value.as_str())),
+    );
+    let scores =
+        Float64Chunked::from_iter_options("confidence", tags.iter().map(|t| t.and_then(|t| t.1)));
+    Ok(StructChunked::new(name, &[values.into_series(), scores.into_series()])?.into_series())
+}
+
+/// Builds the `tags_dtype` column from the parsed replies, null rows have
+/// every field null.
+pub fn tags_column(
+    name: &str,
+    taxonomy: &Taxonomy,
+    replies: &[Option<Value>],
+    multi_label: bool,
+    confidence: bool,
+) -> PolarsResult<Series> {
+    let mut fields = Vec::with_capacity(taxonomy.len());
+    for dimension in taxonomy.keys() {
+        let rows: Vec<Option<Vec<Tag>>> = replies
+            .iter()
+            .map(|reply| {
+                let reply = reply.as_ref()?;
+                Some(reply_tags(&reply[dimension], multi_label, confidence))
+            })
+            .collect();
+        let field = match (multi_label, confidence) {
+            (false, false) => StringChunked::from_iter_options(
+                dimension,
+                rows.iter()
+                    .map(|row| row.as_ref()?.first().map(|(v, _)| v.as_str())),
+            )
+            .into_series(),
+            (false, true) => {
+                let tags: Vec<_> = rows.iter().map(|row| row.as_ref()?.first()).collect();
+                tag_structs(dimension, &tags)?
+            }
+            (true, false) => {
+                let mut builder = ListStringChunkedBuilder::new(dimension, rows.len(), rows.len());
+                for row in &rows {
+                    match row {
+                        Some(tags) => {
+                            builder.append_values_iter(tags.iter().map(|(v, _)| v.as_str()))
+                        }
+                        None => builder.append_null(),
+                    }
+                }
+                builder.finish().into_series()
+            }
+            (true, true) => {
+                let tags: Vec<_> = rows.iter().flatten().flatten().map(Some).collect();
+                let all = tag_structs("", &tags)?;
+                let dtype = tag_field_dtype(false, true);
+                let mut builder =
+                    AnonymousOwnedListBuilder::new(dimension, rows.len(), Some(dtype));
+                let mut offset = 0;
+                for row in &rows {
+                    match row {
+                        Some(tags) => {
+                            builder.append_series(&all.slice(offset as i64, tags.len()))?;
+                            offset += tags.len();
+                        }
+                        None => builder.append_null(),
+                    }
+                }
+                builder.finish().into_series()
+            }
+        };
+        fields.push(field);
+    }
+    Ok(StructChunked::new(name, &fields)?.into_series())
+}
+
+/// Instructions to list the entities of `entity_types` mentioned in a text.
+pub fn entity_instructions(entity_types: &[String]) -> String {
+    format!(
+        "List every entity of these types mentioned in the text: {}. \
+         Give each entity's text exactly as it appears, in order of appearance.",
+        entity_types.join(", ")
+    )
+}
+
+/// Schema of a reply `{"entities": [{"type": .., "text": ..}]}`.
+pub fn entity_schema(entity_types: &[String]) -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "entities": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "type": {"type": "string", "enum": entity_types},
+                        "text": {"type": "string"}
+                    },
+                    "required": ["type", "text"]
+                }
+            }
+        },
+        "required": ["entities"]
+    })
+}
+
+/// `Struct{type, text, span_start, span_end}`
+fn entity_dtype() -> DataType {
+    DataType::Struct(vec![
+        Field::new("type", DataType::String),
+        Field::new("text", DataType::String),
+        Field::new("span_start", DataType::UInt32),
+        Field::new("span_end", DataType::UInt32),
+    ])
+}
+
+/// `List[Struct{type, text, span_start, span_end}]`
+pub fn entities_dtype() -> DataType {
+    DataType::List(Box::new(entity_dtype()))
+}
+
+/// An entity located in a source text.
+pub struct Entity {
+    pub kind: String,
+    pub text: String,
+    // Character offsets, None when the text is not found verbatim
+    pub span: Option<(u32, u32)>,
+}
+
+// Finds every entity in the text, searching past the previous occurrence
+// of the same entity text so repeated mentions get their own spans
+fn locate_entities(text: &str, reply: &Value) -> Vec<Entity> {
+    let mut next_search: HashMap<String, usize> = HashMap::new();
+    let entities = reply["entities"].as_array().cloned().unwrap_or_default();
+    entities
+        .iter()
+        .filter_map(|entity| {
+            let kind = entity["type"].as_str()?.to_string();
+            let mention = entity["text"].as_str()?.to_string();
+            let from = next_search.get(&mention).copied().unwrap_or(0);
+            let span = text[from..]
+                .find(&mention)
+                .filter(|_| !mention.is_empty())
+                .map(|i| {
+                    let start = from + i;
+                    next_search.insert(mention.clone(), start + mention.len());
+                    let start_char = text[..start].chars().count();
+                    let len = mention.chars().count();
+                    (start_char as u32, (start_char + len) as u32)
+                });
+            Some(Entity {
+                kind,
+                text: mention,
+                span,
+            })
+        })
+        .collect()
+}
+
+/// Builds the `entities_dtype` column of the entities in each reply, with
+/// spans located in the source `texts`.
+pub fn entities_column(
+    name: &str,
+    texts: &StringChunked,
+    replies: &[Option<Value>],
+) -> PolarsResult<Series> {
+    let rows: Vec<Option<Vec<Entity>>> = texts
+        .into_iter()
+        .zip(replies)
+        .map(|(text, reply)| Some(locate_entities(text?, reply.as_ref()?)))
+        .collect();
+    entity_list_column(name, &rows)
+}
+
+/// Builds an `entities_dtype` column from the entities of every row.
+pub fn entity_list_column(name: &str, rows: &[Option<Vec<Entity>>]) -> PolarsResult<Series> {
+    let entities: Vec<&Entity> = rows.iter().flatten().flatten().collect();
+    let fields = [
+        Series::new(
+            "type",
+            entities.iter().map(|e| e.kind.as_str()).collect::<Vec<_>>(),
+        ),
+        Series::new(
+            "text",
+            entities.iter().map(|e| e.text.as_str()).collect::<Vec<_>>(),
+        ),
+        Series::new(
+            "span_start",
+            entities
+                .iter()
+                .map(|e| e.span.map(|s| s.0))
+                .collect::<Vec<_>>(),
+        ),
+        Series::new(
+            "span_end",
+            entities
+                .iter()
+                .map(|e| e.span.map(|s| s.1))
+                .collect::<Vec<_>>(),
+        ),
+    ];
+    let all = StructChunked::new("", &fields)?.into_series();
+
+    let mut builder = AnonymousOwnedListBuilder::new(name, rows.len(), Some(entity_dtype()));
+    let mut offset = 0;
+    for row in rows {
+        match row {
+            Some(row) => {
+                builder.append_series(&all.slice(offset as i64, row.len()))?;
+                offset += row.len();
+            }
+            None => builder.append_null(),
+        }
+    }
+    Ok(builder.finish().into_series())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packed_results_are_unpacked_in_item_order() {
+        let reply = json!({"results": [
+            {"item": 2, "label": "negative"},
+            {"item": 1, "label": "positive"},
+        ]});
+
+        assert_eq!(
+            unpack_results(&reply, 2),
+            Some(vec![
+                json!({"label": "positive"}),
+                json!({"label": "negative"})
+            ])
+        );
+    }
+
+    #[test]
+    fn packed_results_need_one_result_per_text() {
+        let missing = json!({"results": [{"item": 1, "label": "positive"}]});
+        let repeated = json!({"results": [{"item": 1}, {"item": 1}]});
+        let out_of_range = json!({"results": [{"item": 1}, {"item": 3}]});
+
+        assert_eq!(unpack_results(&missing, 2), None);
+        assert_eq!(unpack_results(&repeated, 2), None);
+        assert_eq!(unpack_results(&out_of_range, 2), None);
+    }
+
+    #[test]
+    fn repeated_mentions_get_their_own_spans() {
+        let reply = json!({"entities": [
+            {"type": "city", "text": "Paris"},
+            {"type": "person", "text": "Zoë"},
+            {"type": "city", "text": "Paris"},
+            {"type": "city", "text": "Lyon"},
+        ]});
+
+        let entities = locate_entities("Zoë left Paris for Paris, Texas.", &reply);
+
+        let spans: Vec<_> = entities.iter().map(|e| e.span).collect();
+        assert_eq!(spans, [Some((9, 14)), Some((0, 3)), Some((19, 24)), None]);
+    }
+}
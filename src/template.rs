@@ -0,0 +1,79 @@
+use minijinja::{AutoEscape, Environment, UndefinedBehavior, Value};
+use polars::prelude::*;
+use serde::Deserialize;
+
+/// Escaping applied to every value substituted in a template.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Escape {
+    #[default]
+    None,
+    Html,
+    // Values are printed as JSON literals, for templates that build JSON
+    Json,
+}
+
+/// What to do with rows where a bound column is null.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingValues {
+    // The rendered row is null
+    #[default]
+    Null,
+    // Null values render as empty strings
+    Empty,
+    // Rendering fails
+    Error,
+}
+
+/// Renders `template` once per row, with every column bound to a variable
+/// of the same name.
+pub fn render(
+    template: &str,
+    columns: &[&StringChunked],
+    escape: Escape,
+    missing: MissingValues,
+) -> PolarsResult<StringChunked> {
+    let mut env = Environment::new();
+    env.set_undefined_behavior(UndefinedBehavior::Strict);
+    env.set_auto_escape_callback(move |_| match escape {
+        Escape::None => AutoEscape::None,
+        Escape::Html => AutoEscape::Html,
+        Escape::Json => AutoEscape::Json,
+    });
+    let template = env
+        .template_from_str(template)
+        .map_err(|e| polars_err!(ComputeError: "invalid prompt template: {}", e))?;
+
+    let len = columns.first().map_or(0, |c| c.len());
+    let mut out = Vec::with_capacity(len);
+    for row in 0..len {
+        let mut context = Vec::with_capacity(columns.len());
+        let mut has_null = false;
+        for column in columns {
+            let value = match column.get(row) {
+                Some(value) => Value::from(value),
+                None => {
+                    has_null = true;
+                    match missing {
+                        MissingValues::Null => break,
+                        MissingValues::Empty => Value::from(""),
+                        MissingValues::Error => polars_bail!(
+                            ComputeError: "column {} is null in row {}", column.name(), row
+                        ),
+                    }
+                }
+            };
+            context.push((column.name().to_string(), value));
+        }
+        if has_null && matches!(missing, MissingValues::Null) {
+            out.push(None);
+            continue;
+        }
+        let rendered = template
+            .render(Value::from_iter(context))
+            .map_err(|e| polars_err!(ComputeError: "failed to render row {}: {}", row, e))?;
+        out.push(Some(rendered));
+    }
+    Ok(StringChunked::from_iter_options("prompt", out.into_iter()))
+}
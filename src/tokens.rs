@@ -0,0 +1,178 @@
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer};
+use tiktoken_rs::{get_bpe_from_tokenizer, CoreBPE};
+
+// Building an encoder parses its whole vocabulary, so each is built once
+static ENCODERS: Lazy<Mutex<HashMap<Tokenizer, Arc<CoreBPE>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Returns the tokenizer of an OpenAI `model`.
+///
+/// Other models fall back to `o200k_base`, which gives a close estimate for
+/// most modern tokenizers.
+pub fn encoder(model: &str) -> Arc<CoreBPE> {
+    let tokenizer = get_tokenizer(model).unwrap_or(Tokenizer::O200kBase);
+    ENCODERS
+        .lock()
+        .unwrap()
+        .entry(tokenizer)
+        .or_insert_with(|| {
+            Arc::new(get_bpe_from_tokenizer(tokenizer).expect("bundled tokenizer should load"))
+        })
+        .clone()
+}
+
+pub fn count_tokens(encoder: &CoreBPE, text: &str) -> usize {
+    encoder.encode_with_special_tokens(text).len()
+}
+
+// Logit bias that keeps a token out of the reply entirely
+const BANNED: i64 = -100;
+
+/// Logit bias banning every token of `words`, with and without the leading
+/// space they take mid-sentence. Words spanning several tokens ban each of
+/// them, which also keeps them out of other words.
+pub fn banned_tokens(encoder: &CoreBPE, words: &[String]) -> Vec<(String, i64)> {
+    let mut tokens: Vec<_> = words
+        .iter()
+        .flat_map(|word| [word.clone(), format!(" {}", word)])
+        .flat_map(|variant| encoder.encode_with_special_tokens(&variant))
+        .collect();
+    tokens.sort_unstable();
+    tokens.dedup();
+    tokens
+        .into_iter()
+        .map(|token| (token.to_string(), BANNED))
+        .collect()
+}
+
+/// Prompt tokens of a chat request's messages, counting the few tokens
+/// OpenAI adds around each message and to prime the reply.
+pub fn count_message_tokens(encoder: &CoreBPE, messages: &[Value]) -> usize {
+    let text_tokens = |content: &Value| match content {
+        Value::String(text) => count_tokens(encoder, text),
+        Value::Array(parts) => parts
+            .iter()
+            .filter_map(|part| part["text"].as_str())
+            .map(|text| count_tokens(encoder, text))
+            .sum(),
+        _ => 0,
+    };
+    let per_message: usize = messages
+        .iter()
+        .map(|message| 3 + text_tokens(&message["content"]))
+        .sum();
+    per_message + 3
+}
+
+// Byte offset of every token boundary in `text`, from 0 to `text.len()`.
+// Tokens can end inside a multibyte character, such boundaries are moved
+// forward to the end of the character so slices stay valid.
+fn token_offsets(encoder: &CoreBPE, text: &str) -> Vec<usize> {
+    let tokens = encoder.encode_with_special_tokens(text);
+    let mut offsets = Vec::with_capacity(tokens.len() + 1);
+    offsets.push(0);
+    let mut end = 0;
+    for bytes in encoder._decode_native_and_split(tokens) {
+        end += bytes.len();
+        let mut boundary = end.min(text.len());
+        while !text.is_char_boundary(boundary) {
+            boundary += 1;
+        }
+        offsets.push(boundary);
+    }
+    offsets
+}
+
+// Whether a sentence ends at byte `offset`: after terminal punctuation or a
+// newline that is followed by whitespace, so "3.14" or "e.g" are not split.
+fn is_sentence_end(text: &str, offset: usize) -> bool {
+    let (head, tail) = text.split_at(offset);
+    let spaced = head.ends_with(char::is_whitespace) || tail.starts_with(char::is_whitespace);
+    spaced
+        && head
+            .trim_end_matches([' ', '\t'])
+            .ends_with(['.', '!', '?', '\n'])
+}
+
+/// Splits `text` into chunks of at most `max_tokens` tokens, consecutive
+/// chunks sharing `overlap` tokens.
+///
+/// With `sentences` chunks end at the last sentence boundary that fits,
+/// sentences longer than a chunk are still split mid-sentence.
+pub fn chunk_text(
+    encoder: &CoreBPE,
+    text: &str,
+    max_tokens: usize,
+    overlap: usize,
+    sentences: bool,
+) -> Vec<String> {
+    let offsets = token_offsets(encoder, text);
+    let count = offsets.len() - 1;
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < count {
+        let mut end = (start + max_tokens).min(count);
+        if sentences && end < count {
+            if let Some(boundary) = (start + 1..=end)
+                .rev()
+                .find(|&t| is_sentence_end(text, offsets[t]))
+            {
+                end = boundary;
+            }
+        }
+        let chunk = text[offsets[start]..offsets[end]].trim();
+        if !chunk.is_empty() {
+            chunks.push(chunk.to_string());
+        }
+        if end == count {
+            break;
+        }
+        start = end.saturating_sub(overlap).max(start + 1);
+    }
+    chunks
+}
+
+/// Part of a text kept by `truncate_tokens`.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Truncation {
+    // Keep the start of the text
+    #[default]
+    Head,
+    // Keep the end of the text
+    Tail,
+    // Keep the start and the end, dropping the middle
+    Middle,
+}
+
+/// Shortens `text` to at most `max_tokens` tokens, texts that already fit are
+/// returned unchanged.
+pub fn truncate_tokens<'a>(
+    encoder: &CoreBPE,
+    text: &'a str,
+    max_tokens: usize,
+    strategy: Truncation,
+) -> Cow<'a, str> {
+    let offsets = token_offsets(encoder, text);
+    let count = offsets.len() - 1;
+    if count <= max_tokens {
+        return Cow::Borrowed(text);
+    }
+    match strategy {
+        Truncation::Head => Cow::Borrowed(&text[..offsets[max_tokens]]),
+        Truncation::Tail => Cow::Borrowed(&text[offsets[count - max_tokens]..]),
+        Truncation::Middle => {
+            let head = max_tokens.div_ceil(2);
+            let tail = max_tokens - head;
+            let mut kept = text[..offsets[head]].to_string();
+            kept.push_str(&text[offsets[count - tail]..]);
+            Cow::Owned(kept)
+        }
+    }
+}
@@ -1,26 +1,72 @@
+use crate::audit;
+use crate::capabilities::model_capabilities;
+use crate::checkpoint::{request_hash, ResponseStores};
+use crate::concurrency;
+use crate::config::{config, Config};
+use crate::credentials::{acquire_key, api_key, OPENAI};
+use crate::guardrails::{FilterAction, OutputFilter};
+use crate::http::http_client;
+use crate::ledger;
+use crate::messages::{follow_up, map_roles, reply_message, request_parts};
+use crate::metrics::{self, CallUsage};
+use crate::mock;
+use crate::postprocess::PostProcess;
+use crate::pricing;
+use crate::progress;
+use crate::provider::Provider;
+use crate::responses::{self, OpenAIApi};
+use crate::routing::{self, Route, Routed};
+use crate::secrets::scrub;
+use crate::stream;
+use crate::structured::{
+    prompt_for_schema, prompt_schema, prompts_for_schema, rejects_response_format,
+    schema_format_key, schema_in_prompt, StructuredMode,
+};
+use crate::tokens;
+use futures::future::join_all;
+use futures::StreamExt;
 use polars::prelude::*;
-use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
-use futures::future::join_all;
-use serde_json::json;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tracing::{Instrument, Span};
 
-#[derive(Debug)]
 pub enum FetchError {
     Http(u16, String), // Status code and error message
     // Serialization(serde_json::Error), // May be needed in future
-    // Reqwest(reqwest::Error), // May be needed in future
+    Reqwest(reqwest::Error),
+    Unsupported(String),
     ReadBody(std::io::Error), // Changed from ureq::Error to std::io::Error
+    // No usable API key, e.g. every pooled key was rejected
+    NoKey(String),
+    // Every attempt failed, or the retry budget ran out
+    RetriesExhausted,
 }
 
+// Error bodies and request URLs can echo API keys, they are scrubbed from the message
 impl fmt::Display for FetchError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            FetchError::Http(code, ref message) => write!(f, "HTTP Error {}: {}", code, message),
-            // FetchError::Serialization(ref err) => write!(f, "Serialization Error: {}", err),
-            FetchError::ReadBody(ref err) => write!(f, "Error reading body: {}", err),
-            // FetchError::Reqwest(ref err) => write!(f, "Request Error: {}", err),
-        }
+        let message = match *self {
+            FetchError::Http(code, ref message) => format!("HTTP Error {}: {}", code, message),
+            // FetchError::Serialization(ref err) => format!("Serialization Error: {}", err),
+            FetchError::ReadBody(ref err) => format!("Error reading body: {}", err),
+            FetchError::Reqwest(ref err) => format!("Request Error: {}", err),
+            FetchError::Unsupported(ref what) => format!("Unsupported: {}", what),
+            FetchError::NoKey(ref reason) => format!("No API key: {}", reason),
+            FetchError::RetriesExhausted => "Request failed after its retries".to_string(),
+        };
+        f.write_str(&scrub(&message))
+    }
+}
+
+// Also used by panics and `{:?}`, so it must not print the raw error either
+impl fmt::Debug for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
     }
 }
 
@@ -52,55 +98,979 @@ where
 
 // Initialize a global runtime for all async operations
 
-pub async fn fetch_data(messages: &[String]) -> Vec<Option<String>> {
-    let client = Client::new();
-    let fetch_tasks: Vec<_> = messages.iter().map(|message| {
-        let client = &client;
-        let api_key = std::env::var("OPENAI_API_KEY").unwrap_or_else(|_| "".to_string());
-        async move {
-            let body = format!(
-                            r#"{{"messages": [{}], "model": "gpt-4-turbo"}}"#,
-                            message
-                        );
-            let response = client.post("https://api.openai.com/v1/chat/completions")
-                .bearer_auth(api_key)
-                .header("Content-Type", "application/json")
-                .body(body)
-                .send()
-                .await;
-
-            match response {
-                Ok(res) => {
-                    if res.status().is_success() {
-                        res.text().await.ok()
-                    } else {
-                        None
+/// Per-call request options, serialized fields are forwarded as-is in the
+/// chat completions request body.
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct RequestOptions {
+    // Extra HTTP headers, e.g. routing or audit headers required by a gateway
+    #[serde(default, skip_serializing)]
+    pub headers: HashMap<String, String>,
+    // Deliver tokens to the stream callback as they are generated
+    #[serde(default, skip_serializing)]
+    pub stream: bool,
+    #[serde(default, skip_serializing)]
+    pub provider: Provider,
+    // Flag a trailing assistant prefill with `prefix: true`, as the
+    // OpenAI-compatible APIs of Mistral and DeepSeek require
+    #[serde(default, skip_serializing)]
+    pub prefill_prefix: bool,
+    // Roles renamed before sending, e.g. {"narrator": "system"}
+    #[serde(default, skip_serializing)]
+    pub role_map: HashMap<String, String>,
+    // Routes requests sharing a prefix to the same OpenAI prompt cache
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_cache_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub safety_identifier: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    // low, medium or high, only for reasoning models
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning_effort: Option<String>,
+    // Processing tier, e.g. "flex" or "auto" on OpenAI and Groq
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service_tier: Option<String>,
+    // Bias of token ids, from -100 (banned) to 100 (forced)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logit_bias: Option<HashMap<String, i64>>,
+    // Words kept out of replies, banned through `logit_bias` with the model's tokenizer
+    #[serde(default, skip_serializing)]
+    pub banned_strings: Vec<String>,
+    // Keep the completion in the OpenAI dashboard, with `metadata` to filter it by
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub store: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<HashMap<String, String>>,
+    // How Groq returns the reasoning of reasoning models: parsed, raw or hidden
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning_format: Option<String>,
+    // Sent as max_completion_tokens to reasoning models, which reject max_tokens
+    #[serde(default, skip_serializing)]
+    pub max_tokens: Option<u32>,
+    // Neither is sent to reasoning models, which only support the defaults
+    #[serde(default, skip_serializing)]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing)]
+    pub top_p: Option<f32>,
+    // Sampling cut-offs of OpenAI compatible servers for open models, such as
+    // vLLM; OpenAI itself rejects them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_p: Option<f32>,
+    #[serde(default, skip_serializing)]
+    pub api: OpenAIApi,
+    // e.g. {"type": "json_schema", "json_schema": {...}} for structured outputs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<Value>,
+    #[serde(default, skip_serializing)]
+    pub structured_mode: StructuredMode,
+    // Requests stop once the call has spent this much, see `Config::max_cost_usd`
+    #[serde(default, skip_serializing)]
+    pub max_cost_usd: Option<f64>,
+    #[serde(default, skip_serializing)]
+    pub max_total_tokens: Option<u64>,
+    // Retries the whole call may make, see `Config::retry_budget`
+    #[serde(default, skip_serializing)]
+    pub retry_budget: Option<u64>,
+    // Labels this call's requests in the usage report, e.g. a pipeline run
+    #[serde(default, skip_serializing)]
+    pub usage_tag: Option<String>,
+    // Answer with the prompt's token count instead of sending anything
+    #[serde(default, skip_serializing)]
+    pub dry_run: bool,
+    // Checks every reply has to pass, see `guardrails::OutputFilter`
+    #[serde(default, skip_serializing)]
+    pub output_filter: Option<OutputFilter>,
+    // Clean-up of every reply, see `postprocess::PostProcess`
+    #[serde(default, skip_serializing)]
+    pub postprocess: Option<PostProcess>,
+    // Keep the body the provider sent, as it came, in `raw_response`
+    #[serde(default, skip_serializing)]
+    pub return_raw: bool,
+    // Add the queue and network time of each request sent, in `timing`
+    #[serde(default, skip_serializing)]
+    pub return_timing: bool,
+    // Responses API tools, e.g. [{"type": "web_search"}]
+    #[serde(default, skip_serializing)]
+    pub tools: Option<Value>,
+    // Check the provider lists the model before sending anything
+    #[serde(default, skip_serializing)]
+    pub preflight: bool,
+    // Spread the rows across several models instead of the configured one
+    #[serde(default, skip_serializing)]
+    pub route: Option<Route>,
+    // Model of this call's requests, the configured one by default
+    #[serde(default, skip_serializing)]
+    pub model: Option<String>,
+}
+
+impl RequestOptions {
+    /// Model the call's requests go to unless they are routed.
+    pub fn model_name(&self, config: &Config) -> String {
+        self.model.clone().unwrap_or_else(|| config.model.clone())
+    }
+}
+
+// OpenAI o-series models, which take max_completion_tokens and no sampling parameters
+fn is_reasoning_model(model: &str) -> bool {
+    let model = model.rsplit('/').next().unwrap_or(model);
+    let mut chars = model.chars();
+    chars.next() == Some('o') && chars.next().is_some_and(|c| c.is_ascii_digit())
+}
+
+// Returns None if the message is not valid JSON, since the API would reject it anyway
+pub fn chat_request_body(message: &str, model: &str, options: &RequestOptions) -> Option<String> {
+    let (mut messages, row_fields) = request_parts(message)?;
+    map_roles(&mut messages, &options.role_map);
+    if options.prefill_prefix {
+        if let Some(last) = messages.last_mut().filter(|m| m["role"] == "assistant") {
+            last["prefix"] = json!(true);
+        }
+    }
+    let mut body = json!({
+        "messages": messages,
+        "model": model
+    });
+    if let (Some(body), Ok(Value::Object(extra))) =
+        (body.as_object_mut(), serde_json::to_value(options))
+    {
+        body.extend(extra);
+    }
+    if let Some(body) = body.as_object_mut() {
+        body.extend(row_fields);
+    }
+    if !options.banned_strings.is_empty() {
+        let banned = tokens::banned_tokens(&tokens::encoder(model), &options.banned_strings);
+        for (token, bias) in banned {
+            body["logit_bias"][token] = json!(bias);
+        }
+    }
+    let reasoning = is_reasoning_model(model) || options.reasoning_effort.is_some();
+    if let Some(max_tokens) = options.max_tokens {
+        let field = if reasoning {
+            "max_completion_tokens"
+        } else {
+            "max_tokens"
+        };
+        body[field] = json!(max_tokens);
+    }
+    if let Some(temperature) = options.temperature.filter(|_| !reasoning) {
+        body["temperature"] = json!(temperature);
+    }
+    if let Some(top_p) = options.top_p.filter(|_| !reasoning) {
+        body["top_p"] = json!(top_p);
+    }
+    let prompted = match options.structured_mode {
+        // The mock provider answers any request alike
+        StructuredMode::Auto => {
+            options.provider != Provider::Mock
+                && model_capabilities(model).is_some_and(|c| !c.structured_output)
+        }
+        StructuredMode::Native => false,
+        StructuredMode::Prompt => true,
+    };
+    if prompted {
+        prompt_schema(&mut body);
+    }
+    if options.stream {
+        body["stream"] = json!(true);
+        // Usage only arrives in a final chunk when asked for
+        body["stream_options"] = json!({"include_usage": true});
+    }
+    Some(body.to_string())
+}
+
+// Content of a trailing assistant message the reply has to continue
+fn prefill(body: &str) -> Option<String> {
+    let body: Value = serde_json::from_str(body).ok()?;
+    let last = body["messages"].as_array()?.last()?;
+    if last["role"] != "assistant" {
+        return None;
+    }
+    last["content"].as_str().map(|s| s.to_string())
+}
+
+// Starts the reply with the prefill it continues, unless the provider
+// already included it
+fn complete_prefill(response: String, prefill: Option<&str>) -> String {
+    let Some(prefill) = prefill else {
+        return response;
+    };
+    let Ok(mut parsed) = serde_json::from_str::<Value>(&response) else {
+        return response;
+    };
+    let content = &mut parsed["choices"][0]["message"]["content"];
+    match content.as_str() {
+        Some(text) if !text.starts_with(prefill) => {
+            *content = json!(format!("{}{}", prefill, text));
+            parsed.to_string()
+        }
+        _ => response,
+    }
+}
+
+/// What the requests of one expression call share: the response stores
+/// consulted before sending, the usage its budgets are checked against and
+/// the slots of its requests in flight.
+pub struct Call {
+    pub stores: ResponseStores,
+    pub usage: CallUsage,
+    semaphore: Semaphore,
+    // Progress of the whole call when it is sent in several batches
+    tracker: Option<progress::Tracker>,
+}
+
+impl Call {
+    pub fn new(stores: ResponseStores) -> Call {
+        Call {
+            stores,
+            usage: CallUsage::default(),
+            semaphore: Semaphore::new(config().max_concurrency.max(1)),
+            tracker: None,
+        }
+    }
+
+    /// The call with its progress reported over `total` requests, rather
+    /// than per `fetch_data` batch.
+    pub fn tracking(mut self, total: usize) -> Call {
+        self.tracker = progress::Tracker::start(total);
+        self
+    }
+}
+
+/// Sends every message, each given with the input row it belongs to, which
+/// stream callbacks, logs and the audit log report it under. Responses are
+/// in the order of `messages`.
+pub async fn fetch_data(
+    messages: &[(usize, String)],
+    options: &RequestOptions,
+    call: &Call,
+) -> Vec<Option<String>> {
+    let batch = match call.tracker {
+        Some(_) => None,
+        None => progress::Tracker::start(messages.len()),
+    };
+    let tracker = call.tracker.as_ref().or(batch.as_ref());
+    let results = fetch_timed(messages, options, call, tracker).await;
+    metrics::record_rows(messages.len());
+    audit::flush();
+    results.into_iter().map(|(response, _)| response).collect()
+}
+
+/// Sends every message with each of `variants`, e.g. one per model, at
+/// once and sharing the call's slots. Returns the responses of each variant
+/// with how long its requests took, zero for responses that were not sent.
+pub async fn fetch_variants(
+    messages: &[(usize, String)],
+    variants: &[RequestOptions],
+    call: &Call,
+) -> Vec<Vec<(Option<String>, Duration)>> {
+    let batch = match call.tracker {
+        Some(_) => None,
+        None => progress::Tracker::start(messages.len() * variants.len()),
+    };
+    let tracker = call.tracker.as_ref().or(batch.as_ref());
+    let results = join_all(
+        variants
+            .iter()
+            .map(|options| fetch_timed(messages, options, call, tracker)),
+    )
+    .await;
+    metrics::record_rows(messages.len());
+    audit::flush();
+    results
+}
+
+// `fetch_data` with how long the requests of each message took
+async fn fetch_timed(
+    messages: &[(usize, String)],
+    options: &RequestOptions,
+    call: &Call,
+    tracker: Option<&progress::Tracker>,
+) -> Vec<(Option<String>, Duration)> {
+    // Send each distinct message once and fan the responses back out to every row,
+    // keeping the first row of each message to report streamed tokens against
+    let mut unique: Vec<(usize, &String)> = Vec::new();
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    let row_to_unique: Vec<usize> = messages
+        .iter()
+        .map(|(row, message)| {
+            *seen.entry(message.as_str()).or_insert_with(|| {
+                unique.push((*row, message));
+                unique.len() - 1
+            })
+        })
+        .collect();
+
+    // Rows sharing each distinct message, which complete together
+    let mut copies = vec![0; unique.len()];
+    for &i in &row_to_unique {
+        copies[i] += 1;
+    }
+
+    let config = config();
+    let client = http_client();
+    // Requests are prepared a chunk ahead of those being sent, rather than
+    // all at once, so memory stays bounded on very large frames
+    let fetch_tasks =
+        futures::stream::iter(unique.into_iter().zip(copies)).map(|((row, message), copies)| {
+            let client = &client;
+            let config = &config;
+            let fetch = async move {
+                let Some(filter) = &options.output_filter else {
+                    return fetch_attempt(client, config, options, call, row, message, 0).await;
+                };
+                let mut latency = Duration::ZERO;
+                let mut message = message.clone();
+                for attempt in 0..=filter.max_retries {
+                    let (response, took) =
+                        fetch_attempt(client, config, options, call, row, &message, attempt).await;
+                    latency += took;
+                    let Some(response) = response else {
+                        return (None, latency);
+                    };
+                    let Some(reply) = reply_message(&response) else {
+                        return (Some(response), latency);
+                    };
+                    let Some(violation) = filter.violation(&reply.text()) else {
+                        return (Some(response), latency);
+                    };
+                    tracing::warn!(row, attempt, %violation, "reply rejected by the output filter");
+                    metrics::record_error("output_filtered");
+                    match filter.on_violation {
+                        FilterAction::Null => return (None, latency),
+                        FilterAction::Error => {
+                            let response = error_response("output_filtered", &violation);
+                            return (Some(response), latency);
+                        }
+                        FilterAction::Retry if attempt < filter.max_retries => {
+                            let rejection = format!(
+                                "Your reply was rejected because {}. Answer again.",
+                                violation
+                            );
+                            match follow_up(&message, &reply.text(), &rejection) {
+                                Some(next) => message = next,
+                                None => return (None, latency),
+                            }
+                        }
+                        FilterAction::Retry => return (None, latency),
                     }
-                },
-                Err(_) => None,
+                }
+                (None, latency)
+            };
+            async move {
+                let (response, latency) = fetch.await;
+                if let Some(tracker) = tracker {
+                    tracker.rows_done(response.as_deref(), copies);
+                }
+                (response, latency)
+            }
+        });
+
+    let results: Vec<(Option<String>, Duration)> = fetch_tasks
+        .buffered(config.chunk_size.max(1))
+        .collect()
+        .await;
+    row_to_unique
+        .into_iter()
+        .map(|i| results[i].clone())
+        .collect()
+}
+
+// One request for a message, with its reply cleaned up, and how long it took
+async fn fetch_attempt(
+    client: &reqwest::Client,
+    config: &Config,
+    options: &RequestOptions,
+    call: &Call,
+    row: usize,
+    message: &str,
+    attempt: usize,
+) -> (Option<String>, Duration) {
+    let mut latency = Duration::ZERO;
+    let response = fetch_message_timed(client, config, options, call, row, message, &mut latency)
+        .instrument(request_span(config, options, row, attempt))
+        .await
+        .map(|response| postprocess(response, options));
+    (response, latency)
+}
+
+// The response with its reply cleaned up as `options.postprocess` says
+fn postprocess(response: String, options: &RequestOptions) -> String {
+    match &options.postprocess {
+        Some(postprocess) => postprocess.apply_to_response(response),
+        None => response,
+    }
+}
+
+/// Error response standing in for a reply that was not sent or not allowed,
+/// e.g. `budget_exceeded` or `output_filtered`.
+pub fn error_response(kind: &str, message: &str) -> String {
+    json!({"error": {"type": kind, "message": scrub(message)}}).to_string()
+}
+
+// Why no more requests can be sent in this call, if its budget is spent
+fn budget_exceeded(config: &Config, options: &RequestOptions, usage: &CallUsage) -> Option<String> {
+    let (tokens, cost) = usage.spent();
+    if let Some(max_tokens) = options.max_total_tokens.or(config.max_total_tokens) {
+        if tokens >= max_tokens {
+            return Some(format!(
+                "{} tokens used, the budget is {}",
+                tokens, max_tokens
+            ));
+        }
+    }
+    match options.max_cost_usd.or(config.max_cost_usd) {
+        Some(max_cost) if cost >= max_cost => Some(format!(
+            "${:.4} spent, the budget is ${:.4}",
+            cost, max_cost
+        )),
+        _ => None,
+    }
+}
+
+// Response of a request that was not sent, with the prompt tokens it would use
+fn dry_run_response(body: &str) -> Option<String> {
+    let body: Value = serde_json::from_str(body).ok()?;
+    let model = body["model"].as_str().unwrap_or_default();
+    let messages = body["messages"].as_array()?;
+    let prompt_tokens = tokens::count_message_tokens(&tokens::encoder(model), messages);
+    let mut response = json!({
+        "object": "dry_run",
+        "model": model,
+        "usage": {
+            "prompt_tokens": prompt_tokens,
+            "completion_tokens": 0,
+            "total_tokens": prompt_tokens
+        }
+    });
+    let response_text = response.to_string();
+    response["estimated_cost_usd"] = json!(pricing::response_cost(&response_text, model));
+    Some(response.to_string())
+}
+
+// Span of the requests for one row; `attempt` counts the times the row is asked again
+fn request_span(config: &Config, options: &RequestOptions, row: usize, attempt: usize) -> Span {
+    tracing::info_span!(
+        "request",
+        provider = options.provider.as_str(),
+        model = %options.model_name(config),
+        row,
+        attempt
+    )
+}
+
+// Where a message is sent, the configured model unless the call is routed
+fn route_message(
+    config: &Config,
+    options: &RequestOptions,
+    message: &str,
+) -> Option<Result<Routed, String>> {
+    let Some(route) = &options.route else {
+        return Some(Ok(Routed {
+            model: options.model_name(config),
+            arm: None,
+            shadow: None,
+        }));
+    };
+    let (messages, _) = request_parts(message)?;
+    Some(route.choose(&messages, options))
+}
+
+// Sends a request body, the response is a chat completion whichever API was used
+async fn send_body(
+    client: &reqwest::Client,
+    config: &Config,
+    options: &RequestOptions,
+    body: &str,
+    row: usize,
+    usage: &CallUsage,
+) -> Option<String> {
+    let responses_api = options.api == OpenAIApi::Responses && options.provider != Provider::Mock;
+    let (text, attempts) = if options.provider == Provider::Mock {
+        let text = mock::respond(body, options.stream.then_some(row)).await?;
+        (text, Attempts::default())
+    } else if options.stream {
+        stream::send_chat_request_streaming(client, config, options, body, row, usage).await?
+    } else {
+        send_chat_request(client, config, options, body, usage).await?
+    };
+    let raw = options.return_raw.then(|| text.clone());
+    let response = if responses_api {
+        responses::to_chat_completion(&text)?
+    } else {
+        text
+    };
+    let response = attempts.tag(response);
+    Some(match raw {
+        Some(raw) => with_raw_response(response, raw),
+        None => response,
+    })
+}
+
+// Adds the untouched body of a response to it, as `{"raw_response": ..}`
+fn with_raw_response(response: String, raw: String) -> String {
+    let Ok(mut parsed) = serde_json::from_str::<Value>(&response) else {
+        return response;
+    };
+    parsed["raw_response"] = Value::String(raw);
+    parsed.to_string()
+}
+
+// Turns a chat completion request body into one for the call's API
+fn api_body(body: String, options: &RequestOptions) -> Option<String> {
+    if options.api == OpenAIApi::Responses && options.provider != Provider::Mock {
+        return responses::request_body(&body, options.tools.as_ref());
+    }
+    Some(body)
+}
+
+/// Key a request for `message` is checkpointed and cached under: the hash
+/// of the request body, which holds the model, messages and parameters.
+pub fn request_fingerprint(message: &str, model: &str, options: &RequestOptions) -> Option<String> {
+    let body = api_body(chat_request_body(message, model, options)?, options)?;
+    Some(request_hash(&body))
+}
+
+// Mirrors a routed message to the shadow model, whose reply is recorded but not used
+async fn send_shadow(
+    client: &reqwest::Client,
+    config: &Config,
+    options: &RequestOptions,
+    row: usize,
+    message: &str,
+    model: Option<&str>,
+    usage: &CallUsage,
+) -> Option<String> {
+    let model = model?;
+    let options = RequestOptions {
+        stream: false,
+        ..options.clone()
+    };
+    let body = api_body(chat_request_body(message, model, &options)?, &options)?;
+    let started = Instant::now();
+    let result = send_body(client, config, &options, &body, row, usage).await;
+    let latency = started.elapsed();
+    ledger::record(
+        options.provider,
+        model,
+        result.as_deref(),
+        latency,
+        options.usage_tag.as_deref(),
+    );
+    audit::record(
+        options.provider,
+        model,
+        row,
+        &options.headers,
+        &body,
+        result.as_deref(),
+        latency,
+    );
+    if let Some(text) = &result {
+        metrics::record_response(text);
+        usage.record_response(text);
+    }
+    result
+}
+
+// Sends one message, or answers it from the response stores, setting how
+// long the request took, left alone when nothing was sent
+#[allow(clippy::too_many_arguments)]
+async fn fetch_message_timed(
+    client: &reqwest::Client,
+    config: &Config,
+    options: &RequestOptions,
+    call: &Call,
+    row: usize,
+    message: &str,
+    request_latency: &mut Duration,
+) -> Option<String> {
+    let routed = match route_message(config, options, message)? {
+        Ok(routed) => routed,
+        Err(reason) => {
+            metrics::record_error("no_route");
+            return Some(error_response("no_route", &reason));
+        }
+    };
+    let model = routed.model.as_str();
+    let tag = |response: String, shadow_response: Option<&str>| match &options.route {
+        Some(route) => route.tag(response, &routed, shadow_response),
+        None => response,
+    };
+    let body = chat_request_body(message, model, options)?;
+    if options.dry_run {
+        let response = dry_run_response(&body)?;
+        metrics::record_response(&response);
+        return Some(tag(response, None));
+    }
+    let prefill = prefill(&body);
+    let body = api_body(body, options)?;
+    let key = request_hash(&body);
+    if let Some(done) = call.stores.lookup(&key) {
+        tracing::debug!("answered from the response stores");
+        return Some(complete_prefill(done.to_string(), prefill.as_deref()));
+    }
+    if !call.stores.allows_network() {
+        tracing::warn!("no stored response and the network is not allowed");
+        return None;
+    }
+
+    let queued = Instant::now();
+    let _permit = call.semaphore.acquire().await.ok()?;
+    let queue_time = queued.elapsed();
+    // Checked once a slot is free, so the usage of earlier requests has come in
+    if let Some(reason) = budget_exceeded(config, options, &call.usage) {
+        tracing::warn!(%reason, "budget exceeded, request not sent");
+        metrics::record_error("budget_exceeded");
+        return Some(error_response("budget_exceeded", &reason));
+    }
+    if options.provider != Provider::Mock {
+        if let Err(reason) = api_key(OPENAI) {
+            tracing::warn!(%reason, "request not sent");
+            metrics::record_error("keys_disabled");
+            return Some(error_response("keys_disabled", &reason));
+        }
+    }
+    tracing::debug!(model, "sending request");
+    let started = Instant::now();
+    let (result, shadow_result) = futures::join!(
+        async {
+            let result = send_body(client, config, options, &body, row, &call.usage).await;
+            (result, started.elapsed())
+        },
+        send_shadow(
+            client,
+            config,
+            options,
+            row,
+            message,
+            routed.shadow.as_deref(),
+            &call.usage
+        )
+    );
+    let (result, latency) = result;
+    *request_latency = latency;
+    let result = result.map(|text| tag(text, shadow_result.as_deref()));
+    metrics::record_latency(latency);
+    match &result {
+        Some(_) => tracing::debug!(latency_ms = latency.as_millis() as u64, "request done"),
+        None => tracing::error!(latency_ms = latency.as_millis() as u64, "request failed"),
+    }
+    if result.is_some() {
+        routing::observe_latency(model, latency);
+    }
+    if result.is_none() && options.provider == Provider::Mock {
+        metrics::record_error("mock_failure");
+    }
+    ledger::record(
+        options.provider,
+        model,
+        result.as_deref(),
+        latency,
+        options.usage_tag.as_deref(),
+    );
+    audit::record(
+        options.provider,
+        model,
+        row,
+        &options.headers,
+        &body,
+        result.as_deref(),
+        latency,
+    );
+
+    if let Some(text) = &result {
+        metrics::record_response(text);
+        call.usage.record_response(text);
+        call.stores.record(&key, &body, text);
+    }
+    result.map(|text| {
+        let text = complete_prefill(text, prefill.as_deref());
+        if options.return_timing {
+            with_timing(text, queue_time, latency)
+        } else {
+            text
+        }
+    })
+}
+
+// Adds how long a request waited for a free slot and then took to be
+// answered, retries included, as `{"timing": {"queue_ms": .., "network_ms":
+// .., "total_ms": ..}}`
+fn with_timing(response: String, queue_time: Duration, latency: Duration) -> String {
+    let Ok(mut parsed) = serde_json::from_str::<Value>(&response) else {
+        return response;
+    };
+    let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    parsed["timing"] = json!({
+        "queue_ms": ms(queue_time),
+        "network_ms": ms(latency),
+        "total_ms": ms(queue_time + latency)
+    });
+    parsed.to_string()
+}
+
+// Adds the configured OpenAI headers, for OpenAI requests, followed by the caller's own headers
+pub(crate) fn with_headers(
+    mut request: reqwest::RequestBuilder,
+    config: &Config,
+    options: &RequestOptions,
+) -> reqwest::RequestBuilder {
+    if options.provider == Provider::OpenAI {
+        for (name, value) in config.openai_headers() {
+            request = request.header(name, value);
+        }
+    }
+    for (name, value) in &options.headers {
+        request = request.header(name, value);
+    }
+    request
+}
+
+/// How the requests for a row went before it was answered.
+#[derive(Default)]
+pub(crate) struct Attempts {
+    pub count: u32,
+    // Time spent waiting between attempts
+    pub retry_delay: Duration,
+    // Why the previous attempt failed, e.g. http_429 or timeout
+    pub last_error: Option<String>,
+}
+
+impl Attempts {
+    /// Adds `{"retries": {"attempts": .., "total_retry_delay_ms": ..,
+    /// "last_error": ..}}` to a response that took more than one attempt.
+    pub fn tag(&self, response: String) -> String {
+        if self.count <= 1 {
+            return response;
+        }
+        let Ok(mut parsed) = serde_json::from_str::<Value>(&response) else {
+            return response;
+        };
+        parsed["retries"] = json!({
+            "attempts": self.count,
+            "total_retry_delay_ms": self.retry_delay.as_millis() as u64,
+            "last_error": self.last_error
+        });
+        parsed.to_string()
+    }
+}
+
+// Posts a chat completion. In the auto structured mode, servers that reject
+// json_schema response formats, as some OpenAI compatible ones do, are asked
+// for the schema in the prompt instead, and so is every later request to the
+// same URL and model.
+// Returns the first successful response, with its body still unread, and
+// how many attempts it took.
+pub(crate) async fn post_chat_request(
+    client: &reqwest::Client,
+    config: &Config,
+    options: &RequestOptions,
+    body: &str,
+    usage: &CallUsage,
+) -> Option<(reqwest::Response, Attempts)> {
+    let url = config.url(options.api.path());
+    let schema_key = schema_format_key(&url, body);
+    if options.structured_mode == StructuredMode::Auto
+        && schema_key.as_deref().is_some_and(prompts_for_schema)
+    {
+        let prompted = schema_in_prompt(body)?;
+        return accepted(post_with_retries(client, config, options, &url, &prompted, usage).await);
+    }
+    let result = post_with_retries(client, config, options, &url, body, usage).await;
+    if let (Some(key), Err(Some((status, text)))) = (schema_key, &result) {
+        if options.structured_mode == StructuredMode::Auto && rejects_response_format(*status, text)
+        {
+            tracing::warn!(
+                status,
+                "no native structured outputs, asking for the schema in the prompt"
+            );
+            prompt_for_schema(key);
+            let prompted = schema_in_prompt(body)?;
+            return accepted(
+                post_with_retries(client, config, options, &url, &prompted, usage).await,
+            );
+        }
+    }
+    accepted(result)
+}
+
+// The response of `post_with_retries`, after logging why it was rejected
+fn accepted(
+    result: Result<(reqwest::Response, Attempts), Option<(u16, String)>>,
+) -> Option<(reqwest::Response, Attempts)> {
+    match result {
+        Ok(response) => Some(response),
+        Err(Some((status, text))) => {
+            tracing::error!(status, body = %text, "request rejected");
+            metrics::record_error(&format!("http_{}", status));
+            None
+        }
+        Err(None) => None,
+    }
+}
+
+// Posts a chat completion, or another request to the provider of `options`
+// such as a batch of embeddings, retrying rate limits, server and connection
+// errors until the retries of the request or of the whole call run out, under
+// the adaptive concurrency limit. Fails with
+// the status and body of a request the server rejected, or None when the
+// retries ran out.
+pub(crate) async fn post_with_retries(
+    client: &reqwest::Client,
+    config: &Config,
+    options: &RequestOptions,
+    url: &str,
+    body: &str,
+    usage: &CallUsage,
+) -> Result<(reqwest::Response, Attempts), Option<(u16, String)>> {
+    let budget = options.retry_budget.or(config.retry_budget);
+    // Why the last attempt failed, reported when the retries run out
+    let mut failure = String::new();
+    let mut retry_delay = Duration::ZERO;
+    for attempt in 0..=config.max_retries {
+        if attempt > 0 {
+            if !usage.take_retry(budget) {
+                tracing::error!(budget, "retry budget of the call spent");
+                metrics::record_error("retry_budget_exhausted");
+                return Err(None);
             }
+            metrics::record_retry();
+            let delay = Duration::from_millis(500 * 2u64.pow(attempt - 1));
+            retry_delay += delay;
+            tokio::time::sleep(delay).await;
         }
-    }).collect();
 
-    join_all(fetch_tasks).await
+        let slot = concurrency::acquire(config).await;
+        let lease = match acquire_key(options.provider.as_str()).await {
+            Ok(lease) => lease,
+            Err(reason) => {
+                tracing::error!(%reason, "no API key left to send with");
+                metrics::record_error("keys_disabled");
+                return Err(None);
+            }
+        };
+        let request = client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .timeout(config.timeout());
+        // Gemini takes its key in a header of its own
+        let request = match options.provider {
+            Provider::Gemini => request.header("x-goog-api-key", lease.key()),
+            _ => request.bearer_auth(lease.key()),
+        };
+        let response = with_headers(request, config, options)
+            .body(body.to_string())
+            .send()
+            .await;
+
+        let res = match response {
+            Ok(res) => res,
+            Err(e) => {
+                tracing::warn!(retry = attempt, error = %e, "connection error");
+                failure = if e.is_timeout() {
+                    "timeout"
+                } else {
+                    "connection_error"
+                }
+                .to_string();
+                continue;
+            }
+        };
+        record_rate_limits(res.headers());
+        let status = res.status();
+        if status.is_success() {
+            slot.succeeded();
+            let attempts = Attempts {
+                count: attempt + 1,
+                retry_delay,
+                last_error: (attempt > 0).then_some(failure),
+            };
+            return Ok((res, attempts));
+        }
+        // 529 is the overloaded status of some providers
+        if matches!(status.as_u16(), 429 | 529) {
+            slot.throttled();
+        }
+        let text = res.text().await.unwrap_or_default();
+        lease.report(status.as_u16(), &text);
+        if !(status.as_u16() == 429 || status.is_server_error()) {
+            return Err(Some((status.as_u16(), text)));
+        }
+        tracing::warn!(retry = attempt, status = status.as_u16(), "retryable error");
+        failure = format!("http_{}", status.as_u16());
+    }
+    tracing::error!(retries = config.max_retries, "giving up after retries");
+    metrics::record_error(&failure);
+    Err(None)
+}
+
+// Quota left as reported by OpenAI compatible APIs such as Groq's
+fn record_rate_limits(headers: &reqwest::header::HeaderMap) {
+    let remaining = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok())
+    };
+    metrics::record_rate_limits(
+        remaining("x-ratelimit-remaining-requests"),
+        remaining("x-ratelimit-remaining-tokens"),
+    );
+}
+
+async fn send_chat_request(
+    client: &reqwest::Client,
+    config: &Config,
+    options: &RequestOptions,
+    body: &str,
+    usage: &CallUsage,
+) -> Option<(String, Attempts)> {
+    let (response, attempts) = post_chat_request(client, config, options, body, usage).await?;
+    Some((response.text().await.ok()?, attempts))
 }
 
-pub fn fetch_api_response_sync(msg: &str, model: &str) -> Result<String, FetchError> {
+pub fn fetch_api_response_sync(msg: &str, options: &RequestOptions) -> Result<String, FetchError> {
+    let config = config();
     let agent = ureq::agent();
-    let body = json!({
-        "messages": [{"role": "user", "content": msg}],
-        "model": model
-    }).to_string();
-    let api_key = std::env::var("OPENAI_API_KEY").unwrap_or_else(|_| "".to_string());
+    let message = json!({"role": "user", "content": msg}).to_string();
+    let mut body =
+        chat_request_body(&message, &options.model_name(&config), options).unwrap_or_default();
+    if options.provider == Provider::Mock {
+        return mock::respond_sync(&body)
+            .ok_or_else(|| FetchError::Http(500, "Mock failure".to_string()));
+    }
+    if options.api == OpenAIApi::Responses {
+        body = responses::request_body(&body, options.tools.as_ref()).unwrap_or_default();
+    }
+    let api_key = api_key(OPENAI).map_err(FetchError::NoKey)?;
     let auth = format!("Bearer {}", api_key);
-    let response = agent.post("https://api.openai.com/v1/chat/completions")
+    let mut request = agent.post(&config.url(options.api.path()));
+    request
         .set("Authorization", auth.as_str())
-        .set("Content-Type", "application/json")
-        .send_string(&body);
+        .set("Content-Type", "application/json");
+    for (name, value) in config.openai_headers() {
+        request.set(name, value);
+    }
+    for (name, value) in &options.headers {
+        request.set(name, value);
+    }
+    let response = request.send_string(&body);
 
     if response.ok() {
-        response.into_string().map_err(FetchError::ReadBody)
+        let text = response.into_string().map_err(FetchError::ReadBody)?;
+        match options.api {
+            OpenAIApi::Responses => Ok(responses::to_chat_completion(&text).unwrap_or(text)),
+            OpenAIApi::ChatCompletions => Ok(text),
+        }
     } else {
-        Err(FetchError::Http(response.status(), response.into_string().unwrap_or_else(|_| "Unknown error".to_string())))
+        Err(FetchError::Http(
+            response.status(),
+            response
+                .into_string()
+                .unwrap_or_else(|_| "Unknown error".to_string()),
+        ))
     }
 }
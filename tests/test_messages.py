@@ -0,0 +1,307 @@
+import json
+
+import polars as pl
+import pytest
+from polar_llama import (
+    append_message,
+    combine_messages,
+    configure_mock,
+    document_message,
+    few_shot,
+    image_message,
+    inference_messages,
+    inference_sessions,
+    inference_turn,
+    pairwise_prompt,
+    string_to_message,
+)
+
+
+def test_few_shot_prepends_examples_after_system_message():
+    examples = pl.DataFrame({"input": ["2 + 2", "3 + 5"], "output": ["4", "8"]})
+    df = pl.DataFrame({"question": ["1 + 1"]})
+
+    result = df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(prompt=few_shot("prompt", examples=examples.to_dicts()))
+
+    messages = json.loads(result["prompt"][0])
+    assert [(m["role"], m["content"]) for m in messages] == [
+        ("user", "2 + 2"),
+        ("assistant", "4"),
+        ("user", "3 + 5"),
+        ("assistant", "8"),
+        ("user", "1 + 1"),
+    ]
+
+
+def test_combine_messages_builds_conversations():
+    configure_mock(template="echo: {content}")
+    df = pl.DataFrame({"system": ["Be brief."] * 2, "question": ["Hi", None]})
+
+    result = df.with_columns(
+        conversation=combine_messages(
+            string_to_message("system", message_type="system"),
+            string_to_message("question", message_type="user"),
+        )
+    ).with_columns(answer=inference_messages("conversation", provider="mock"))
+
+    assert result["conversation"][0].to_list() == [
+        {"role": "system", "content": "Be brief.", "name": None, "cache_control": None, "images": [], "documents": [], "parts": [], "extra": None},
+        {"role": "user", "content": "Hi", "name": None, "cache_control": None, "images": [], "documents": [], "parts": [], "extra": None},
+    ]
+    assert result["conversation"][1] is None
+    assert json.loads(result["answer"][0])["choices"][0]["message"]["content"] == "echo: Hi"
+
+
+def test_combine_messages_flattens_nested_arrays_and_checks_roles():
+    history = json.dumps([[{"role": "user", "content": "Hi ]["}], {"role": "assistant", "content": "Hello"}])
+    df = pl.DataFrame({"history": [history], "question": ["And now?"]})
+
+    result = df.with_columns(
+        conversation=combine_messages("history", string_to_message("question", message_type="user"))
+    )
+
+    assert [m["content"] for m in result["conversation"][0]] == ["Hi ][", "Hello", "And now?"]
+
+    bad = pl.DataFrame({"history": [json.dumps([{"role": "narrator", "content": "Once"}])]})
+    with pytest.raises(pl.exceptions.ComputeError, match="unknown role"):
+        bad.with_columns(conversation=combine_messages("history"))
+
+
+def test_combine_messages_shares_literal_messages_with_every_row():
+    df = pl.DataFrame({"question": ["Hi", "Bye", "Why?"]})
+    system = pl.lit(json.dumps({"role": "system", "content": "Be brief."}))
+
+    result = df.with_columns(
+        conversation=combine_messages(
+            system, string_to_message("question", message_type="user")
+        )
+    )
+
+    assert [[m["content"] for m in row] for row in result["conversation"]] == [
+        ["Be brief.", "Hi"],
+        ["Be brief.", "Bye"],
+        ["Be brief.", "Why?"],
+    ]
+
+
+def test_tool_parts_round_trip_through_conversations(tmp_path):
+    configure_mock(template="done")
+    path = tmp_path / "fixture.jsonl"
+    history = [
+        {"role": "user", "content": "Weather in Paris?"},
+        {
+            "role": "assistant",
+            "content": [
+                {"type": "text", "text": "Checking."},
+                {"type": "tool_use", "id": "call_1", "name": "weather", "input": {"city": "Paris"}},
+            ],
+        },
+        {
+            "role": "user",
+            "content": [
+                {"type": "tool_result", "tool_use_id": "call_1", "content": "18C", "is_error": False},
+                {"type": "image", "source": {"type": "url", "url": "https://example.com/map.png"}},
+            ],
+        },
+    ]
+    df = pl.DataFrame({"history": [json.dumps(history)]})
+
+    result = df.with_columns(conversation=combine_messages("history")).with_columns(
+        answer=inference_messages(
+            "conversation", provider="mock", fixture_path=str(path), fixture_mode="record"
+        )
+    )
+    configure_mock()
+
+    assert [json.loads(p)["type"] for p in result["conversation"][0][2]["parts"]] == [
+        "tool_result",
+        "image",
+    ]
+    request = json.loads(path.read_text().splitlines()[0])["request"]
+    assert request["messages"] == history
+
+
+def test_mixed_parts_keep_their_order(tmp_path):
+    configure_mock(template="done")
+    path = tmp_path / "fixture.jsonl"
+    message = {
+        "role": "user",
+        "content": [
+            {"type": "image_url", "image_url": {"url": "https://example.com/a.png"}},
+            {"type": "text", "text": "Compare this"},
+            {"type": "image_url", "image_url": {"url": "https://example.com/b.png", "detail": "low"}},
+            {"type": "text", "text": "with this."},
+        ],
+    }
+    df = pl.DataFrame({"history": [json.dumps([message])]})
+
+    result = df.with_columns(conversation=combine_messages("history")).with_columns(
+        answer=inference_messages(
+            "conversation", provider="mock", fixture_path=str(path), fixture_mode="record"
+        )
+    )
+    configure_mock()
+
+    converted = result["conversation"][0][0]
+    assert converted["content"] == "Compare this\nwith this."
+    assert converted["images"] == ["https://example.com/a.png", "https://example.com/b.png"]
+    assert [json.loads(p) for p in converted["parts"]] == message["content"]
+    request = json.loads(path.read_text().splitlines()[0])["request"]
+    assert request["messages"] == [message]
+
+
+def test_role_map_renames_roles_outside_the_chat_roles(tmp_path):
+    configure_mock(template="{content}")
+    path = tmp_path / "fixture.jsonl"
+    history = [{"role": "narrator", "content": "Once"}, {"role": "user", "content": "Go on"}]
+    df = pl.DataFrame({"history": [json.dumps(history)]})
+
+    df.with_columns(
+        answer=inference_messages(
+            "history",
+            provider="mock",
+            role_map={"narrator": "system"},
+            fixture_path=str(path),
+            fixture_mode="record",
+        )
+    )
+    configure_mock()
+
+    request = json.loads(path.read_text().splitlines()[0])["request"]
+    assert [m["role"] for m in request["messages"]] == ["system", "user"]
+
+    with pytest.raises(pl.exceptions.ComputeError, match='role "narrator"'):
+        df.with_columns(answer=inference_messages("history", provider="mock"))
+
+
+def test_string_to_message_encodes_any_content():
+    configure_mock(template="{content}")
+    content = 'She said "hi"\n\tthen left \\ 🎉'
+    df = pl.DataFrame({"question": [content]})
+
+    result = df.with_columns(
+        prompt=string_to_message("question", message_type="user", name="alice")
+    ).with_columns(
+        answer=inference_messages(combine_messages("prompt"), provider="mock")
+    )
+
+    assert result["prompt"][0] == {
+        "role": "user",
+        "content": content,
+        "name": "alice",
+        "cache_control": None,
+        "images": [],
+        "documents": [],
+        "parts": [],
+        "extra": None,
+    }
+    assert json.loads(result["answer"][0])["choices"][0]["message"]["content"] == content
+
+
+def test_multi_turn_loop_accumulates_history():
+    configure_mock(template="re: {content}")
+    df = pl.DataFrame({"first": ["Hi"], "second": ["More"]})
+
+    df = df.with_columns(history=append_message(pl.lit(None), "first", role="user"))
+    df = df.with_columns(turn=inference_turn("history", provider="mock"))
+    df = df.with_columns(
+        history=append_message(pl.col("turn").struct.field("history"), "second", role="user")
+    ).with_columns(turn=inference_turn("history", provider="mock"))
+
+    assert df["turn"].struct.field("reply").to_list() == ["re: More"]
+    history = df["turn"].struct.field("history")[0].to_list()
+    assert [(m["role"], m["content"]) for m in history] == [
+        ("user", "Hi"),
+        ("assistant", "re: Hi"),
+        ("user", "More"),
+        ("assistant", "re: More"),
+    ]
+
+
+def test_sessions_see_earlier_turns_of_their_conversation():
+    configure_mock(template="{content} (turn with {model})")
+    df = pl.DataFrame(
+        {"conversation": [1, 2, 1], "question": ["a", "b", "c"]}
+    )
+
+    result = df.with_columns(
+        reply=inference_sessions(
+            string_to_message("question", message_type="user"),
+            "conversation",
+            provider="mock",
+        )
+    )
+
+    assert [reply.split(" ")[0] for reply in result["reply"]] == ["a", "b", "c"]
+
+
+def test_sessions_apply_the_empty_prompt_policy():
+    configure_mock(template="{content}")
+    df = pl.DataFrame({"conversation": [1, 1], "question": ["a", None]})
+    prompt = string_to_message("question", message_type="user")
+
+    result = df.with_columns(
+        reply=inference_sessions(prompt, "conversation", provider="mock", on_empty="default:b")
+    )
+    configure_mock()
+
+    assert result["reply"].to_list() == ["a", "b"]
+    with pytest.raises(pl.exceptions.ComputeError, match="row 1 has an empty prompt"):
+        df.with_columns(
+            reply=inference_sessions(prompt, "conversation", provider="mock", on_empty="error")
+        )
+
+
+def test_assistant_prefill_starts_the_reply():
+    configure_mock(template='"answer": 4}')
+    df = pl.DataFrame({"question": ["2 + 2?"], "prefill": ["{"]})
+
+    result = df.with_columns(
+        conversation=combine_messages(
+            string_to_message("question", message_type="user"),
+            string_to_message("prefill", message_type="assistant"),
+        )
+    ).with_columns(answer=inference_messages("conversation", provider="mock"))
+
+    reply = json.loads(result["answer"][0])["choices"][0]["message"]["content"]
+    assert json.loads(reply) == {"answer": 4}
+
+
+def test_image_message_encodes_binary_images():
+    png = b"\x89PNG\r\n\x1a\n"
+    df = pl.DataFrame({"question": ["What is this?"], "image": [png]})
+
+    result = df.with_columns(prompt=image_message("question", "image"))
+
+    message = result["prompt"][0]
+    assert message["content"] == "What is this?"
+    assert message["images"] == ["data:image/png;base64,iVBORw0KGgo="]
+
+
+def test_document_message_reads_pdf_files(tmp_path):
+    pdf = tmp_path / "report.pdf"
+    pdf.write_bytes(b"%PDF-1.4")
+    df = pl.DataFrame({"question": ["Summarize"], "path": [str(pdf)]})
+
+    result = df.with_columns(prompt=document_message("question", "path"))
+
+    assert result["prompt"][0]["documents"] == ["data:application/pdf;base64,JVBERi0xLjQ="]
+
+
+def test_pairwise_prompt_builds_a_message_from_both_columns():
+    df = pl.DataFrame(
+        {"resume": ['Wrote a "fast" parser', None], "job": ["Parser engineer"] * 2}
+    )
+
+    result = df.select(
+        prompt=pairwise_prompt("Does {{ a }} match {{ b }}?", a="resume", b="job")
+    )
+
+    prompt = result["prompt"].struct
+    assert prompt.field("role")[0] == "user"
+    assert prompt.field("content").to_list() == [
+        'Does Wrote a "fast" parser match Parser engineer?',
+        None,
+    ]
@@ -0,0 +1,873 @@
+import json
+
+import polars as pl
+import pytest
+from polar_llama import (
+    Config,
+    Provider,
+    cache_metrics,
+    clear_usage_report,
+    compare_models,
+    configure_audit_log,
+    configure_logging,
+    configure_mock,
+    extract_json,
+    get_usage_report,
+    inference,
+    inference_async,
+    inference_predicted,
+    inference_prioritized,
+    last_run_stats,
+    list_models,
+    model_capabilities,
+    request_fingerprint,
+    parse_xml_tags,
+    reset_config,
+    response_cost,
+    response_usage,
+    set_config,
+    set_model_price,
+    set_progress_callback,
+    set_stream_callback,
+    string_to_message,
+    validate_setup,
+)
+
+
+def answers(df: pl.DataFrame) -> list:
+    return [
+        json.loads(response)["choices"][0]["message"]["content"]
+        for response in df["answer"]
+    ]
+
+
+def test_mock_provider_templates_responses():
+    configure_mock(template="echo: {content}")
+    df = pl.DataFrame({"question": ["What is 2 + 2?", "Name a colour"]})
+
+    result = df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(answer=inference_async("prompt", provider="mock"))
+
+    assert answers(result) == ["echo: What is 2 + 2?", "echo: Name a colour"]
+
+
+def test_mock_provider_failures_are_deterministic():
+    configure_mock(failure_rate=0.5)
+    df = pl.DataFrame({"question": [f"question {i}" for i in range(50)]})
+    prompts = df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    )
+
+    first = prompts.with_columns(answer=inference_async("prompt", provider="mock"))
+    second = prompts.with_columns(answer=inference_async("prompt", provider="mock"))
+
+    assert first["answer"].null_count() > 0
+    assert first["answer"].is_null().to_list() == second["answer"].is_null().to_list()
+    configure_mock()
+
+
+def test_sync_inference_leaves_failed_rows_null():
+    configure_mock(failure_rate=1.0)
+    df = pl.DataFrame({"question": ["first", "second", None]})
+
+    result = df.with_columns(answer=inference("question", provider="mock"))
+    configure_mock()
+
+    assert result["answer"].null_count() == 3
+
+
+def test_output_filter_replaces_rejected_replies():
+    configure_mock(template="Call me at {content}")
+    df = pl.DataFrame({"question": ["555-0100", "later"]})
+
+    result = df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(
+        answer=inference_async(
+            "prompt",
+            provider="mock",
+            output_filter={"deny_patterns": [r"\d{3}-\d{4}"], "on_violation": "error"},
+        )
+    )
+    configure_mock()
+
+    assert json.loads(result["answer"][0])["error"]["type"] == "output_filtered"
+    assert answers(result.slice(1)) == ["Call me at later"]
+
+
+def test_response_cost_prices_usage():
+    set_model_price("gpt-4-turbo", input=10.0, output=30.0)
+    df = pl.DataFrame({"question": ["What is 2 + 2?"]})
+
+    result = df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(
+        answer=inference_async("prompt", provider="mock")
+    ).with_columns(cost=response_cost("answer"))
+
+    usage = json.loads(result["answer"][0])["usage"]
+    expected = (usage["prompt_tokens"] * 10.0 + usage["completion_tokens"] * 30.0) / 1e6
+    assert result["cost"][0] == pytest.approx(expected)
+    assert cache_metrics().cost_usd == pytest.approx(expected)
+
+
+def test_dry_run_counts_prompt_tokens_without_requests():
+    df = pl.DataFrame({"question": ["What is 2 + 2?"]})
+
+    result = df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(answer=inference_async("prompt", dry_run=True))
+
+    response = json.loads(result["answer"][0])
+    assert response["object"] == "dry_run"
+    assert response["usage"]["prompt_tokens"] > 0
+    assert response["estimated_cost_usd"] > 0
+
+
+def test_budget_stops_requests_once_spent():
+    df = pl.DataFrame({"question": ["first", "second", "third"]})
+
+    result = df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(answer=inference_async("prompt", provider="mock", max_total_tokens=1))
+
+    errors = [json.loads(r).get("error", {}).get("type") for r in result["answer"]]
+    assert errors == [None, "budget_exceeded", "budget_exceeded"]
+
+
+def test_stream_callback_reports_the_dataframe_row():
+    configure_mock(template="{content}")
+    rows = []
+    set_stream_callback(lambda row, delta: rows.append(row))
+    df = pl.DataFrame({"question": [None, "second", None, "fourth"]})
+
+    df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(answer=inference_async("prompt", provider="mock", stream=True))
+    set_stream_callback(None)
+    configure_mock()
+
+    assert sorted(set(rows)) == [1, 3]
+
+
+def test_columns_computed_together_keep_their_own_budget():
+    df = pl.DataFrame({"question": ["first", "second", "third"]}).with_columns(
+        prompt=string_to_message("question", message_type="user")
+    )
+
+    result = df.with_columns(
+        first=inference_async("prompt", provider="mock", max_total_tokens=1),
+        second=inference_async("prompt", provider="mock", max_total_tokens=1),
+    )
+
+    for column in ["first", "second"]:
+        errors = [json.loads(r).get("error", {}).get("type") for r in result[column]]
+        assert errors == [None, "budget_exceeded", "budget_exceeded"]
+
+
+def test_budget_caps_the_whole_column_on_the_streaming_engine(monkeypatch):
+    monkeypatch.setenv("POLARS_STREAMING_CHUNK_SIZE", "10")
+    set_config(Config(max_concurrency=1))
+    lf = pl.LazyFrame({"question": [f"question {i}" for i in range(30)]})
+
+    result = (
+        lf.with_columns(prompt=string_to_message("question", message_type="user"))
+        .with_columns(
+            answer=inference_async("prompt", provider="mock", max_total_tokens=1)
+        )
+        .collect(streaming=True)
+    )
+    reset_config()
+
+    errors = [json.loads(r).get("error", {}).get("type") for r in result["answer"]]
+    assert errors.count(None) == 1
+    assert errors.count("budget_exceeded") == 29
+
+
+def test_retry_budget_caps_the_retries_of_a_call():
+    # Nothing listens on port 9, so every attempt fails to connect
+    set_config(Config(base_url="http://127.0.0.1:9/v1", max_retries=3, retry_budget=1))
+    df = pl.DataFrame({"question": ["first", "second"]})
+
+    result = df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(answer=inference_async("prompt"))
+    stats = last_run_stats()
+    reset_config()
+
+    assert result["answer"].to_list() == [None, None]
+    assert stats.retries == 1
+    assert stats.errors == {"retry_budget_exhausted": 2}
+
+
+def test_usage_report_records_every_request():
+    clear_usage_report()
+    df = pl.DataFrame({"question": ["first", "second"]})
+
+    df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(answer=inference_async("prompt", provider="mock", usage_tag="nightly"))
+
+    report = get_usage_report()
+    assert report.height == 2
+    assert report["tag"].to_list() == ["nightly", "nightly"]
+    assert report["provider"].unique().to_list() == ["mock"]
+    assert report["call"].n_unique() == 1
+    assert (report["prompt_tokens"] > 0).all()
+
+
+def test_logging_forwards_request_events_to_python(caplog):
+    configure_logging("debug", forward_to_python=True)
+    df = pl.DataFrame({"question": ["first"]})
+
+    with caplog.at_level("DEBUG", logger="polar_llama"):
+        df.with_columns(
+            prompt=string_to_message("question", message_type="user")
+        ).with_columns(answer=inference_async("prompt", provider="mock"))
+    configure_logging("off")
+
+    messages = [record.getMessage() for record in caplog.records]
+    assert any("request done" in m and "provider=mock" in m and "row=0" in m for m in messages)
+
+
+def test_logging_rejects_unknown_levels():
+    with pytest.raises(ValueError):
+        configure_logging("loud")
+
+
+def test_progress_callback_reports_completed_rows():
+    configure_mock(template="echo: {content}")
+    reports = []
+    set_progress_callback(reports.append, interval=0)
+    df = pl.DataFrame({"question": ["first", "second", "first"]})
+
+    df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(answer=inference_async("prompt", provider="mock"))
+    set_progress_callback(None)
+
+    assert reports[-1].completed == reports[-1].total == 3
+    assert reports[-1].errors == 0
+    assert reports[-1].remaining == 0
+
+
+def test_audit_log_writes_scrubbed_jsonl(tmp_path):
+    path = tmp_path / "audit.jsonl"
+    configure_audit_log(str(path))
+    df = pl.DataFrame({"question": ["my key is sk-abcdefghijklmnopqrstuvwx"]})
+
+    df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(answer=inference_async("prompt", provider="mock"))
+    configure_audit_log(None)
+
+    entries = [json.loads(line) for line in path.read_text().splitlines()]
+    assert len(entries) == 1
+    assert entries[0]["provider"] == "mock"
+    assert "sk-abcdefghijklmnopqrstuvwx" not in entries[0]["request"]
+    assert "[REDACTED]" in entries[0]["request"]
+
+
+def test_audit_log_hashes_content_to_parquet(tmp_path):
+    configure_audit_log(str(tmp_path / "audit.parquet"), hash_content=True)
+    df = pl.DataFrame({"question": ["first", "second"]})
+
+    df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(answer=inference_async("prompt", provider="mock"))
+    configure_audit_log(None)
+
+    audit = pl.read_parquet(tmp_path / "audit.parquet" / "*.parquet")
+    assert audit.height == 2
+    assert audit["request"].str.starts_with("sha256:").all()
+
+
+def test_last_run_stats_reports_latency_and_errors():
+    configure_mock(failure_rate=1.0)
+    df = pl.DataFrame({"question": ["first", "second"]})
+
+    df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(answer=inference_async("prompt", provider="mock"))
+    configure_mock()
+
+    stats = last_run_stats()
+    assert stats.rows == 2
+    assert stats.requests == 2
+    assert stats.errors == {"mock_failure": 2}
+    assert stats.latency_p50_ms <= stats.latency_p95_ms <= stats.latency_p99_ms
+
+
+def test_model_capabilities_cover_dated_snapshots():
+    capabilities = model_capabilities("gpt-4o-2024-08-06")
+    assert capabilities.structured_output
+    assert capabilities.context_window == 128_000
+    assert not model_capabilities("gpt-4-turbo").structured_output
+    assert model_capabilities("my-finetune") is None
+
+
+def test_list_models_of_the_mock_provider():
+    assert "gpt-4o-mini" in list_models("mock")
+
+
+def test_route_sends_rows_to_the_cheapest_candidate():
+    configure_mock(template="{model}")
+    df = pl.DataFrame({"question": ["first", "second"]})
+
+    result = df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(
+        answer=inference_async(
+            "prompt",
+            provider="mock",
+            route={"candidates": ["gpt-4o", "gpt-4o-mini"], "strategy": "cost"},
+        )
+    )
+    configure_mock()
+
+    assert answers(result) == ["gpt-4o-mini", "gpt-4o-mini"]
+    routes = [json.loads(r)["route"] for r in result["answer"]]
+    assert routes == [{"strategy": "cost", "model": "gpt-4o-mini"}] * 2
+
+
+def test_split_route_tags_each_row_with_its_arm():
+    configure_mock(template="{model}")
+    df = pl.DataFrame({"question": [f"question {i}" for i in range(40)]})
+
+    result = df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(
+        answer=inference_async(
+            "prompt",
+            provider="mock",
+            route={"candidates": ["gpt-4o", "gpt-4.1"], "strategy": "split", "split": 0.5},
+        )
+    )
+    configure_mock()
+
+    routes = [json.loads(r)["route"] for r in result["answer"]]
+    arms = {route["arm"] for route in routes}
+    assert arms == {"a", "b"}
+    assert all(
+        answer == ("gpt-4o" if route["arm"] == "a" else "gpt-4.1")
+        for answer, route in zip(answers(result), routes)
+    )
+
+
+def test_shadow_route_records_the_shadow_reply():
+    configure_mock(template="{model}")
+    df = pl.DataFrame({"question": ["first"]})
+
+    result = df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(
+        answer=inference_async(
+            "prompt",
+            provider="mock",
+            route={"candidates": ["gpt-4o", "gpt-4.1"], "strategy": "shadow"},
+        )
+    )
+    configure_mock()
+
+    assert answers(result) == ["gpt-4o"]
+    shadow = json.loads(result["answer"][0])["route"]["shadow"]
+    assert shadow["model"] == "gpt-4.1"
+    assert shadow["response"]["choices"][0]["message"]["content"] == "gpt-4.1"
+
+
+def test_compare_models_answers_with_each_model():
+    configure_mock(template="{model}")
+    df = pl.DataFrame({"question": ["first", "second"]})
+
+    result = df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(
+        answer=compare_models("prompt", models=["mock/gpt-4o", "mock/gpt-4.1"])
+    )
+    configure_mock()
+
+    answers = result["answer"].struct.unnest()
+    assert answers.columns == ["mock/gpt-4o", "mock/gpt-4.1"]
+    assert answers["mock/gpt-4o"].struct.field("answer").to_list() == ["gpt-4o"] * 2
+    assert answers["mock/gpt-4.1"].struct.field("answer").to_list() == ["gpt-4.1"] * 2
+
+
+def test_compare_models_matches_inference_for_each_model():
+    configure_mock(template="  {content}  ")
+    df = pl.DataFrame({"question": [None, "second"]}).with_columns(
+        prompt=string_to_message("question", message_type="user")
+    )
+    kwargs = {"postprocess": {"trim": True}}
+
+    result = df.with_columns(
+        compared=compare_models("prompt", models=["mock/gpt-4o"], **kwargs),
+        answer=inference_async("prompt", provider="mock", model="gpt-4o", **kwargs),
+    )
+    configure_mock()
+
+    compared = result["compared"].struct.field("mock/gpt-4o").struct.field("answer")
+    assert compared.to_list() == [None, "second"]
+    assert compared.to_list() == [
+        None if r is None else json.loads(r)["choices"][0]["message"]["content"]
+        for r in result["answer"]
+    ]
+    assert all(ms >= 0 for ms in answers["mock/gpt-4o"].struct.field("latency_ms"))
+
+
+@pytest.mark.parametrize("timeout", [-1.0, 0.0, float("nan"), float("inf")])
+def test_config_rejects_invalid_timeouts(timeout):
+    with pytest.raises(ValueError, match="timeout_secs"):
+        Config(timeout_secs=timeout)
+
+    config = Config()
+    config.timeout_secs = timeout
+    with pytest.raises(ValueError, match="timeout_secs"):
+        set_config(config)
+
+
+def test_runtime_follows_the_configured_threads():
+    set_config(Config(worker_threads=1, max_blocking_threads=1))
+    df = pl.DataFrame({"question": ["first", "second"]})
+
+    result = df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(answer=inference_async("prompt", provider="mock"))
+    reset_config()
+
+    assert len(answers(result)) == 2
+
+
+def test_adaptive_concurrency_leaves_mock_calls_unchanged():
+    configure_mock(template="{content}")
+    set_config(Config(max_concurrency=8, adaptive_concurrency=2))
+    df = pl.DataFrame({"question": [f"question {i}" for i in range(5)]})
+
+    result = df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(answer=inference_async("prompt", provider="mock"))
+    reset_config()
+    configure_mock()
+
+    assert "adaptive_concurrency=2" in repr(Config(adaptive_concurrency=2))
+    assert answers(result) == [f"question {i}" for i in range(5)]
+
+
+def test_small_chunks_keep_row_order():
+    configure_mock(template="{content}")
+    set_config(Config(chunk_size=2))
+    questions = [f"question {i}" for i in range(7)]
+    df = pl.DataFrame({"question": questions})
+
+    result = df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(answer=inference_async("prompt", provider="mock"))
+    reset_config()
+    configure_mock()
+
+    assert answers(result) == questions
+
+
+def test_inference_runs_on_the_streaming_engine():
+    configure_mock(template="{content}")
+    questions = [f"question {i}" for i in range(50)]
+    lf = pl.LazyFrame({"question": questions})
+
+    result = (
+        lf.with_columns(prompt=string_to_message("question", message_type="user"))
+        .with_columns(answer=inference_async("prompt", provider="mock"))
+        .collect(streaming=True)
+    )
+    configure_mock()
+
+    assert answers(result) == questions
+
+
+def test_streaming_runs_each_call_on_the_whole_column(monkeypatch):
+    monkeypatch.setenv("POLARS_STREAMING_CHUNK_SIZE", "10")
+    configure_mock(template="{content}")
+    questions = [f"question {i}" for i in range(50)]
+    lf = pl.LazyFrame({"question": questions})
+
+    result = (
+        lf.with_columns(prompt=string_to_message("question", message_type="user"))
+        .with_columns(answer=inference_async("prompt", provider="mock"))
+        .collect(streaming=True)
+    )
+    configure_mock()
+
+    assert answers(result) == questions
+    assert last_run_stats().rows == len(questions)
+
+
+def test_streaming_orders_every_row_by_priority(monkeypatch, tmp_path):
+    monkeypatch.setenv("POLARS_STREAMING_CHUNK_SIZE", "10")
+    configure_mock(template="{content}")
+    set_config(Config(max_concurrency=1))
+    path = tmp_path / "fixture.jsonl"
+    questions = [f"question {i}" for i in range(30)]
+    lf = pl.LazyFrame({"question": questions, "priority": list(range(30))})
+
+    (
+        lf.with_columns(prompt=string_to_message("question", message_type="user"))
+        .with_columns(
+            answer=inference_prioritized(
+                "prompt",
+                "priority",
+                provider="mock",
+                fixture_path=str(path),
+                fixture_mode="record",
+            )
+        )
+        .collect(streaming=True)
+    )
+    reset_config()
+    configure_mock()
+
+    sent = [
+        entry["request"]["messages"][0]["content"]
+        for entry in map(json.loads, path.read_text().splitlines())
+    ]
+    assert sent == questions[::-1]
+
+
+def empty_prompts() -> pl.DataFrame:
+    df = pl.DataFrame({"question": ["first", "", "  ", None]})
+    return df.with_columns(prompt=string_to_message("question", message_type="user"))
+
+
+def test_empty_prompts_are_skipped():
+    result = empty_prompts().with_columns(
+        answer=inference_async("prompt", provider="mock")
+    )
+
+    assert result["answer"].null_count() == 3
+
+
+def test_empty_prompts_can_get_a_default():
+    configure_mock(template="{content}")
+    result = empty_prompts().with_columns(
+        answer=inference_async("prompt", provider="mock", on_empty="default:nothing")
+    )
+    configure_mock()
+
+    assert answers(result) == ["first", "nothing", "nothing", "nothing"]
+
+
+def test_empty_prompts_can_fail_the_call():
+    with pytest.raises(pl.exceptions.ComputeError, match="row 1 has an empty prompt"):
+        empty_prompts().with_columns(
+            answer=inference_async("prompt", provider="mock", on_empty="error")
+        )
+
+
+def test_empty_prompt_in_a_later_chunk_fails_before_sending():
+    set_config(Config(chunk_size=2))
+    clear_usage_report()
+    df = pl.DataFrame({"question": ["first", "second", "third", "fourth", ""]})
+
+    with pytest.raises(pl.exceptions.ComputeError, match="row 4 has an empty prompt"):
+        df.with_columns(
+            prompt=string_to_message("question", message_type="user")
+        ).with_columns(answer=inference_async("prompt", provider="mock", on_empty="error"))
+    reset_config()
+
+    assert get_usage_report().height == 0
+
+
+def test_extract_json_follows_paths_into_responses():
+    configure_mock(template="{content}")
+    df = pl.DataFrame({"question": ["first", None]})
+
+    result = df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(
+        answer=inference_async("prompt", provider="mock")
+    ).with_columns(
+        reply=extract_json("answer", "$.choices[0].message.content"),
+        role=extract_json("answer", "choices.0.message.role"),
+        missing=extract_json("answer", "$.choices[3]"),
+    )
+    configure_mock()
+
+    assert result["reply"].to_list() == ["first", None]
+    assert result["role"].to_list() == ["assistant", None]
+    assert result["missing"].null_count() == 2
+
+
+def test_postprocess_cleans_up_replies():
+    configure_mock(template="{content}")
+    df = pl.DataFrame(
+        {
+            "question": [
+                "Thinking... <answer> 42 </answer>",
+                "```json\n{\"a\": 1}\n```",
+                "no tags here",
+            ]
+        }
+    )
+
+    result = df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(
+        answer=inference_async(
+            "prompt",
+            provider="mock",
+            postprocess={"strip_fences": True, "extract_tag": "answer", "trim": True},
+        )
+    )
+    configure_mock()
+
+    assert answers(result) == ["42", '{"a": 1}', "no tags here"]
+
+
+def test_parse_xml_tags_reads_each_tag():
+    configure_mock(template="{content}")
+    df = pl.DataFrame(
+        {
+            "question": [
+                "<classification>spam</classification>\n<rationale> Sells pills </rationale>",
+                "<classification>ham</classification>",
+            ]
+        }
+    )
+
+    result = df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(
+        answer=inference_async("prompt", provider="mock")
+    ).with_columns(parsed=parse_xml_tags("answer", tags=["classification", "rationale"]))
+    configure_mock()
+
+    parsed = result["parsed"].struct
+    assert parsed.field("classification").to_list() == ["spam", "ham"]
+    assert parsed.field("rationale").to_list() == ["Sells pills", None]
+
+
+def test_request_fingerprint_matches_the_checkpoint_key(tmp_path):
+    path = tmp_path / "answers.jsonl"
+    df = pl.DataFrame({"question": ["first", "first", "second"]}).with_columns(
+        prompt=string_to_message("question", message_type="user")
+    )
+
+    df.with_columns(
+        answer=inference_async("prompt", provider="mock", checkpoint_path=str(path))
+    )
+    result = df.with_columns(
+        key=request_fingerprint("prompt", provider="mock"),
+        other=request_fingerprint("prompt", provider="mock", model="gpt-4o"),
+    )
+
+    keys = result["key"].to_list()
+    assert keys[0] == keys[1] != keys[2]
+    assert set(keys) == {json.loads(line)["key"] for line in path.read_text().splitlines()}
+    assert result["other"][0] != keys[0]
+
+
+def test_response_cost_halves_flex_responses():
+    set_model_price("gpt-4o", input=2.0, output=8.0)
+    usage = {"prompt_tokens": 1000, "completion_tokens": 500}
+    responses = [
+        json.dumps({"model": "gpt-4o", "usage": usage}),
+        json.dumps({"model": "gpt-4o", "service_tier": "flex", "usage": usage}),
+    ]
+
+    result = pl.DataFrame({"answer": responses}).with_columns(cost=response_cost("answer"))
+
+    standard = (1000 * 2.0 + 500 * 8.0) / 1e6
+    assert result["cost"].to_list() == pytest.approx([standard, standard / 2])
+
+
+def test_inference_predicted_sends_each_rows_prediction(tmp_path):
+    configure_mock(template="{content}")
+    path = tmp_path / "fixture.jsonl"
+    df = pl.DataFrame(
+        {"question": ["Fix: teh cat", "Fix: a dgo"], "draft": ["the cat", None]}
+    )
+
+    result = df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(
+        answer=inference_predicted(
+            "prompt", "draft", provider="mock", fixture_path=str(path), fixture_mode="record"
+        )
+    )
+    configure_mock()
+
+    assert answers(result) == ["Fix: teh cat", "Fix: a dgo"]
+    requests = {
+        entry["request"]["messages"][0]["content"]: entry["request"].get("prediction")
+        for entry in map(json.loads, path.read_text().splitlines())
+    }
+    assert requests == {
+        "Fix: teh cat": {"type": "content", "content": "the cat"},
+        "Fix: a dgo": None,
+    }
+
+
+def test_banned_strings_become_a_logit_bias(tmp_path):
+    path = tmp_path / "fixture.jsonl"
+    df = pl.DataFrame({"question": ["Describe the product"]})
+
+    df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(
+        answer=inference_async(
+            "prompt",
+            provider="mock",
+            logit_bias={"1234": 5},
+            banned_strings=["cheap"],
+            fixture_path=str(path),
+            fixture_mode="record",
+        )
+    )
+
+    request = json.loads(path.read_text().splitlines()[0])["request"]
+    bias = request["logit_bias"]
+    assert bias["1234"] == 5
+    assert len(bias) > 1
+    assert all(value == -100 for token, value in bias.items() if token != "1234")
+
+
+def test_sampling_parameters_are_sent(tmp_path):
+    path = tmp_path / "fixture.jsonl"
+    df = pl.DataFrame({"question": ["Write a slogan"]})
+
+    df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(
+        answer=inference_async(
+            "prompt",
+            provider="mock",
+            top_p=0.9,
+            top_k=40,
+            min_p=0.05,
+            fixture_path=str(path),
+            fixture_mode="record",
+        )
+    )
+
+    request = json.loads(path.read_text().splitlines()[0])["request"]
+    assert request["top_p"] == pytest.approx(0.9)
+    assert request["top_k"] == 40
+    assert request["min_p"] == pytest.approx(0.05)
+
+
+def test_inference_prioritized_sends_high_priority_rows_first(tmp_path):
+    configure_mock(template="{content}")
+    set_config(Config(max_concurrency=1))
+    path = tmp_path / "fixture.jsonl"
+    df = pl.DataFrame(
+        {"question": ["backfill", "urgent", "soon", "whenever"], "priority": [1, 9, 5, None]}
+    )
+
+    result = df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(
+        answer=inference_prioritized(
+            "prompt", "priority", provider="mock", fixture_path=str(path), fixture_mode="record"
+        )
+    )
+    reset_config()
+    configure_mock()
+
+    assert answers(result) == ["backfill", "urgent", "soon", "whenever"]
+    sent = [
+        entry["request"]["messages"][0]["content"]
+        for entry in map(json.loads, path.read_text().splitlines())
+    ]
+    assert sent == ["urgent", "soon", "backfill", "whenever"]
+
+
+def test_inference_prioritized_reports_errors_by_input_row():
+    history = [
+        json.dumps([{"role": "user", "content": "fine"}]),
+        json.dumps([{"role": "narrator", "content": "Once"}]),
+    ]
+    df = pl.DataFrame({"prompt": history, "priority": [1, 9]})
+
+    with pytest.raises(pl.exceptions.ComputeError, match="row 1 has"):
+        df.with_columns(answer=inference_prioritized("prompt", "priority", provider="mock"))
+
+    df = pl.DataFrame({"prompt": [history[0], None], "priority": [1, 9]})
+    with pytest.raises(pl.exceptions.ComputeError, match="row 1 has an empty prompt"):
+        df.with_columns(
+            answer=inference_prioritized("prompt", "priority", provider="mock", on_empty="error")
+        )
+
+
+def test_validate_setup_diagnoses_the_setup(monkeypatch):
+    assert validate_setup("mock", "gpt-4o").ok
+    missing_model = validate_setup("mock", "no-such-model")
+    assert (missing_model.ok, missing_model.problem) == (False, "model_not_found")
+
+    monkeypatch.delenv("OPENAI_API_KEY", raising=False)
+    assert validate_setup("openai").problem == "missing_key"
+
+    monkeypatch.setenv("OPENAI_API_KEY", "sk-test")
+    set_config(Config(base_url="http://127.0.0.1:9/v1"))
+    unreachable = validate_setup("openai", "gpt-4o")
+    reset_config()
+    assert unreachable.problem == "unreachable"
+
+
+def test_provider_class_lists_every_provider():
+    providers = Provider.list()
+
+    assert Provider.OPENAI in providers and Provider.MOCK in providers
+    assert [str(p) for p in providers] == [p.name for p in providers]
+    assert Provider.MOCK == "mock" and Provider.MOCK != Provider.OPENAI
+    assert {Provider.MOCK: 1}["mock"] == 1
+    assert repr(Provider("Cohere")) == "Provider.COHERE"
+    assert not Provider.COHERE.supports_chat
+
+
+def test_return_raw_keeps_the_untouched_response():
+    configure_mock(template="  padded  ")
+    df = pl.DataFrame({"question": ["first"]})
+
+    result = df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(
+        answer=inference_async(
+            "prompt", provider="mock", return_raw=True, postprocess={"trim": True}
+        )
+    )
+    configure_mock()
+
+    response = json.loads(result["answer"][0])
+    assert response["choices"][0]["message"]["content"] == "padded"
+    raw = json.loads(response["raw_response"])
+    assert raw["choices"][0]["message"]["content"] == "  padded  "
+
+
+def test_response_usage_reports_the_tokens_of_each_row():
+    df = pl.DataFrame({"question": ["first", None]})
+
+    result = df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(answer=inference_async("prompt", provider="mock")).with_columns(
+        usage=response_usage("answer")
+    )
+
+    usage = result["usage"].to_list()
+    assert usage[0]["prompt_tokens"] > 0
+    assert usage[1]["prompt_tokens"] is None
+    assert usage[0]["cached_tokens"] == 0
+    assert usage[0]["reasoning_tokens"] == 0
+    assert cache_metrics().prompt_tokens == usage[0]["prompt_tokens"]
+
+
+def test_return_timing_adds_queue_and_network_time():
+    configure_mock(latency_ms=20)
+    df = pl.DataFrame({"question": ["first", "second"]})
+
+    result = df.with_columns(
+        prompt=string_to_message("question", message_type="user")
+    ).with_columns(answer=inference_async("prompt", provider="mock", return_timing=True))
+    configure_mock()
+
+    for response in result["answer"]:
+        timing = json.loads(response)["timing"]
+        assert timing["network_ms"] >= 20
+        assert timing["total_ms"] == pytest.approx(timing["queue_ms"] + timing["network_ms"])
@@ -0,0 +1,288 @@
+import json
+import threading
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+import polars as pl
+import pytest
+from polar_llama import (
+    Config,
+    answer_with_citations,
+    cache_metrics,
+    classify,
+    configure_mock,
+    extract_entities,
+    inference_json,
+    reset_config,
+    set_config,
+    string_to_message,
+    tag_taxonomy,
+)
+
+SCHEMA = {
+    "type": "object",
+    "properties": {"sentiment": {"type": "string", "enum": ["positive", "negative"]}},
+    "required": ["sentiment"],
+}
+
+
+def structured(template: str, **kwargs) -> dict:
+    configure_mock(template=template)
+    df = pl.DataFrame({"review": ["Loved it"]})
+    result = df.with_columns(
+        prompt=string_to_message("review", message_type="user")
+    ).with_columns(answer=inference_json("prompt", provider="mock", **kwargs))
+    configure_mock()
+    return result["answer"][0]
+
+
+def test_inference_json_parses_valid_replies():
+    answer = structured('{"sentiment": "positive"}', schema=SCHEMA)
+
+    assert json.loads(answer["json"]) == {"sentiment": "positive"}
+    assert answer["repaired"] is False
+    assert answer["error"] is None
+
+
+def test_inference_json_falls_back_to_the_prompt_without_native_support(monkeypatch):
+    requests = []
+
+    # An OpenAI compatible server that turns down json_schema response formats
+    class Handler(BaseHTTPRequestHandler):
+        def do_POST(self):
+            body = json.loads(self.rfile.read(int(self.headers["Content-Length"])))
+            requests.append(body)
+            if "response_format" in body:
+                status = 400
+                reply = {"error": {"message": "response_format json_schema is not supported"}}
+            else:
+                status = 200
+                message = {"role": "assistant", "content": '{"sentiment": "positive"}'}
+                reply = {"choices": [{"index": 0, "message": message, "finish_reason": "stop"}]}
+            data = json.dumps(reply).encode()
+            self.send_response(status)
+            self.send_header("Content-Type", "application/json")
+            self.send_header("Content-Length", str(len(data)))
+            self.end_headers()
+            self.wfile.write(data)
+
+        def log_message(self, *args):
+            pass
+
+    server = HTTPServer(("127.0.0.1", 0), Handler)
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    monkeypatch.setenv("OPENAI_API_KEY", "sk-test")
+    set_config(Config(base_url=f"http://127.0.0.1:{server.server_port}/v1"))
+    df = pl.DataFrame({"review": ["Loved it", "Great value"]})
+    try:
+        result = df.with_columns(
+            prompt=string_to_message("review", message_type="user")
+        ).with_columns(answer=inference_json("prompt", schema=SCHEMA, model="local-model"))
+    finally:
+        reset_config()
+        server.shutdown()
+
+    assert [json.loads(a["json"]) for a in result["answer"]] == [{"sentiment": "positive"}] * 2
+    # Once turned down, the server and model get the schema in the prompt
+    assert sum("response_format" in r for r in requests) <= 2
+    prompted = [r for r in requests if "response_format" not in r]
+    assert len(prompted) == 2
+    assert "JSON schema" in prompted[0]["messages"][0]["content"]
+
+
+def test_prompt_mode_puts_the_schema_in_the_system_message(tmp_path):
+    path = tmp_path / "fixture.jsonl"
+    answer = structured(
+        '{"sentiment": "negative"}',
+        schema=SCHEMA,
+        structured_mode="prompt",
+        fixture_path=str(path),
+        fixture_mode="record",
+    )
+
+    assert json.loads(answer["json"]) == {"sentiment": "negative"}
+    request = json.loads(path.read_text().splitlines()[0])["request"]
+    assert "response_format" not in request
+    assert request["messages"][0]["role"] == "system"
+    assert '"sentiment"' in request["messages"][0]["content"]
+
+
+def test_inference_json_checks_roles_before_sending():
+    question = json.dumps([{"role": "user", "content": "Weather?"}])
+    history = json.dumps([{"role": "narrator", "content": "Once"}])
+    df = pl.DataFrame({"prompt": [question, history], "schema": ['{"type": "object"}', None]})
+
+    with pytest.raises(pl.exceptions.ComputeError, match='row 1 has a message with role "narrator"'):
+        df.with_columns(answer=inference_json("prompt", "schema", provider="mock"))
+
+
+def test_inference_json_repairs_fenced_replies():
+    answer = structured("```json\n{'sentiment': 'positive',}\n```", schema=SCHEMA)
+
+    assert json.loads(answer["json"]) == {"sentiment": "positive"}
+    assert answer["repaired"] is True
+
+
+def test_inference_json_reports_invalid_replies():
+    unrepaired = structured("{'sentiment': 'positive'}", repair=False)
+    invalid = structured('{"sentiment": "meh"}', schema=SCHEMA)
+
+    assert unrepaired["json"] is None
+    assert unrepaired["error"].startswith("invalid_json")
+    assert invalid["error"].startswith("validation_failed")
+
+
+def test_inference_json_retries_failed_validation():
+    answer = structured('{"sentiment": "meh"}', schema=SCHEMA, max_validation_retries=2)
+
+    assert answer["error"].startswith("validation_failed")
+    assert cache_metrics().requests == 3
+
+
+def test_inference_json_makes_optional_fields_nullable():
+    schema = {"type": "object", "properties": {"summary": {"type": "string"}}}
+
+    answer = structured('{"summary": null}', schema=schema)
+
+    assert answer["error"] is None
+
+
+def test_inference_json_rejects_unsupported_schemas():
+    schema = {"type": "object", "patternProperties": {"^x": {"type": "string"}}}
+
+    with pytest.raises(pl.exceptions.ComputeError, match="patternProperties"):
+        structured("{}", schema=schema)
+
+
+def test_inference_json_takes_a_schema_per_row():
+    configure_mock(template='{"sentiment": "positive"}')
+    topic = {"type": "object", "properties": {"topic": {"type": "string"}}, "required": ["topic"]}
+    df = pl.DataFrame(
+        {
+            "review": ["Loved it", "Shipping was slow"],
+            "schema": [json.dumps(SCHEMA), json.dumps(topic)],
+        }
+    )
+
+    result = df.with_columns(
+        answer=inference_json(
+            string_to_message("review", message_type="user"), "schema", provider="mock"
+        )
+    )
+    configure_mock()
+
+    assert result["answer"][0]["error"] is None
+    assert result["answer"][1]["error"].startswith("validation_failed")
+
+
+def test_classify_returns_an_enum_of_the_labels():
+    configure_mock(template='{"label": "negative", "confidence": 0.8}')
+    df = pl.DataFrame({"review": ["Broke after a day", None]})
+    labels = ["positive", "negative"]
+
+    result = df.with_columns(
+        label=classify("review", labels=labels, provider="mock"),
+        scored=classify("review", labels=labels, confidence=True, provider="mock"),
+    )
+    configure_mock()
+
+    assert result["label"].dtype == pl.Enum(labels)
+    assert result["label"].to_list() == ["negative", None]
+    assert result["scored"][0] == {"label": "negative", "confidence": 0.8}
+
+
+def test_classify_unpacks_packed_replies_and_resends_bad_packs():
+    configure_mock(
+        template='{"results": [{"item": 2, "label": "positive"}, {"item": 1, "label": "negative"}]}'
+    )
+    df = pl.DataFrame({"review": ["Broke after a day", "Works great", "Fine"]})
+    labels = ["positive", "negative"]
+
+    result = df.with_columns(label=classify("review", labels=labels, pack=2, provider="mock"))
+    configure_mock()
+
+    # The last pack holds one text but got two results, so it is sent alone
+    # and its reply, which is not a single label, is null
+    assert result["label"].to_list() == ["negative", "positive", None]
+
+
+def test_classify_packs_keep_their_labels_with_an_empty_prompt_policy():
+    configure_mock(
+        template='{"results": [{"item": 1, "label": "negative"}, {"item": 2, "label": "positive"}]}'
+    )
+    df = pl.DataFrame({"review": ["Broke after a day", "Works great"]})
+    labels = ["positive", "negative"]
+
+    result = df.with_columns(
+        label=classify("review", labels=labels, pack=2, provider="mock", on_empty="error"),
+        defaulted=classify(
+            "review", labels=labels, pack=2, provider="mock", on_empty="default:No review"
+        ),
+    )
+    configure_mock()
+
+    assert result["label"].to_list() == ["negative", "positive"]
+    assert result["defaulted"].to_list() == ["negative", "positive"]
+
+
+def test_tag_taxonomy_returns_scored_tags_per_dimension():
+    configure_mock(
+        template='{"department": [{"value": "billing", "confidence": 0.9}, '
+        '{"value": "shipping", "confidence": 0.6}], "urgency": [{"value": "high", "confidence": 0.7}]}'
+    )
+    taxonomy = {"department": ["billing", "shipping", "support"], "urgency": ["low", "high"]}
+    df = pl.DataFrame({"ticket": ["Charged twice and the parcel never came"]})
+
+    result = df.with_columns(
+        tags=tag_taxonomy(
+            "ticket", taxonomy=taxonomy, multi_label=True, confidence=True, provider="mock"
+        )
+    )
+    configure_mock()
+
+    assert result["tags"][0] == {
+        "department": [
+            {"value": "billing", "confidence": 0.9},
+            {"value": "shipping", "confidence": 0.6},
+        ],
+        "urgency": [{"value": "high", "confidence": 0.7}],
+    }
+
+
+def test_extract_entities_locates_spans():
+    configure_mock(
+        template='{"entities": [{"type": "person", "text": "Ada"}, '
+        '{"type": "city", "text": "London"}, {"type": "person", "text": "Ada"}]}'
+    )
+    df = pl.DataFrame({"text": ["Ada moved to London, where Ada wrote notes."]})
+
+    result = df.with_columns(
+        entities=extract_entities("text", entity_types=["person", "city"], provider="mock")
+    )
+    configure_mock()
+
+    assert result["entities"][0].to_list() == [
+        {"type": "person", "text": "Ada", "span_start": 0, "span_end": 3},
+        {"type": "city", "text": "London", "span_start": 13, "span_end": 19},
+        {"type": "person", "text": "Ada", "span_start": 27, "span_end": 30},
+    ]
+
+
+def test_answer_with_citations_returns_document_positions():
+    configure_mock(template='{"answer": "Paris", "citations": [2, 2, 7]}')
+    df = pl.DataFrame(
+        {
+            "question": ["What is the capital of France?", None],
+            "passages": [
+                [None, "Paris is the capital of France.", "Berlin is in Germany."],
+                ["Unused"],
+            ],
+        }
+    )
+
+    result = df.with_columns(
+        answer=answer_with_citations("question", "passages", provider="mock")
+    )
+    configure_mock()
+
+    assert result["answer"][0] == {"answer": "Paris", "citations": [1]}
+    assert result["answer"].struct.field("answer")[1] is None